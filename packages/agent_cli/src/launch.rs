@@ -1,8 +1,6 @@
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::process::Stdio;
-use std::sync::Arc;
-use std::sync::atomic::Ordering;
 use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
@@ -10,11 +8,17 @@ use tokio::io::{Stdout, stdout};
 use uuid::Uuid;
 
 use playit_agent_core::api::client::ApiClient;
+use playit_agent_core::agent::AgentBuilder;
+use playit_agent_core::audit_log::{AuditLog, AuditLogSettings};
 use playit_agent_core::api::messages::TunnelType;
-use playit_agent_core::tunnel_runner::TunnelRunner;
+use playit_agent_core::network::backend_pool::{BackendPool, BalanceStrategy};
+use playit_agent_core::network::local_target::LocalTarget;
+use playit_agent_core::network::mapping_overrides::{LookupWithOverrides, MappingOverride};
+use playit_agent_core::network::rate_limit::RateLimits;
+use playit_agent_core::network::tunnel_settings::TunnelSettings;
 use playit_agent_proto::PortProto;
 
-use crate::{API_BASE, claim_exchange, claim_generate, claim_url, CliError, LookupWithOverrides, MappingOverride, tunnels_prepare};
+use crate::{API_BASE, claim_exchange, claim_generate, claim_url, CliError, parse_local_target, tunnels_prepare};
 
 #[derive(Serialize, Deserialize)]
 pub struct LaunchConfig {
@@ -33,6 +37,20 @@ pub struct LaunchConfig {
 
     #[serde(default = "default_as_true")]
     pub special_lan: bool,
+
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    pub max_tcp_clients: Option<usize>,
+
+    /* MaxMind format databases used by tunnel geo rules, needs the geoip feature */
+    pub geoip_country_db: Option<String>,
+    pub geoip_asn_db: Option<String>,
+
+    /* append only record of every TCP connection and UDP flow */
+    pub audit_log: Option<AuditLogSettings>,
+
+    /* Lua script deciding on new clients and flows, needs the lua feature */
+    pub packet_filter_script: Option<String>,
 }
 
 fn default_as_true() -> bool {
@@ -47,6 +65,25 @@ pub struct Tunnel {
     pub proto: PortProto,
     pub port_count: u16,
     pub local: Option<u16>,
+    /* unix:<path> or <ip>:<port>, takes precedence over local */
+    pub local_target: Option<String>,
+    /* several local servers sharing the tunnel, takes precedence over local_target */
+    #[serde(default)]
+    pub backends: Vec<SocketAddr>,
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+    /* TLS server name to local target, others go to the tunnel's target */
+    #[serde(default)]
+    pub sni_routes: Vec<SniRoute>,
+
+    #[serde(default, flatten)]
+    pub settings: TunnelSettings,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SniRoute {
+    pub server_name: String,
+    pub local_target: String,
 }
 
 pub async fn launch(config: LaunchConfig) -> Result<(), anyhow::Error> {
@@ -76,12 +113,28 @@ pub async fn launch(config: LaunchConfig) -> Result<(), anyhow::Error> {
 
         tunnels.push(tunnel.clone());
 
-        if let Some(local_port) = tunnel_config.local {
-            mapping_overrides.push(MappingOverride::new(
-                tunnel,
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), local_port)
-            ));
+        let local_target = match &tunnel_config.local_target {
+            _ if !tunnel_config.backends.is_empty() => {
+                /* the runner health checks pools along with the other local targets */
+                LocalTarget::from(BackendPool::new(tunnel_config.backends.clone(), tunnel_config.balance))
+            }
+            Some(target) => parse_local_target(target)?,
+            /* without a local port the tunnel's own port is used, same as the default lookup */
+            None => LocalTarget::Addr(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                tunnel_config.local.unwrap_or(tunnel.from_port),
+            )),
+        };
+
+        let mut mapping = MappingOverride::with_target(tunnel, local_target)
+            .with_settings(tunnel_config.settings.clone());
+
+        for route in &tunnel_config.sni_routes {
+            let target = parse_local_target(&route.local_target)?;
+            mapping = mapping.with_sni_route(&route.server_name, target);
         }
+
+        mapping_overrides.push(mapping);
     }
 
     let mut command = tokio::process::Command::new(config.command);
@@ -97,17 +150,41 @@ pub async fn launch(config: LaunchConfig) -> Result<(), anyhow::Error> {
 
     tracing::info!("setting up connection to tunnel server");
 
-    let mut tunnel = TunnelRunner::new(
-        secret,
-        Arc::new(LookupWithOverrides(mapping_overrides)),
-    ).await?;
+    let mut builder = AgentBuilder::new(secret)
+        .lookup(LookupWithOverrides::new(mapping_overrides))
+        .use_special_lan(config.special_lan)
+        .rate_limits(config.rate_limits)
+        .max_tcp_clients(config.max_tcp_clients);
+
+    if config.geoip_country_db.is_some() || config.geoip_asn_db.is_some() {
+        #[cfg(feature = "geoip")]
+        {
+            use playit_agent_core::network::geo_filter::MmdbGeoLookup;
+            builder = builder.geo_lookup(MmdbGeoLookup::open(config.geoip_country_db.as_ref(), config.geoip_asn_db.as_ref())?);
+        }
+
+        #[cfg(not(feature = "geoip"))]
+        return Err(anyhow::anyhow!("geoip databases configured but playit-cli was built without the geoip feature"));
+    }
+
+    if let Some(settings) = &config.audit_log {
+        builder = builder.audit_log(AuditLog::open(settings)?);
+    }
 
-    tunnel.set_use_special_lan(config.special_lan);
+    if let Some(script) = &config.packet_filter_script {
+        #[cfg(feature = "lua")]
+        {
+            use playit_agent_core::network::packet_filter::LuaPacketFilter;
+            builder = builder.packet_filter(LuaPacketFilter::load(script)?);
+        }
+
+        #[cfg(not(feature = "lua"))]
+        return Err(anyhow::anyhow!("packet filter script {} configured but playit-cli was built without the lua feature", script));
+    }
 
-    let keep_running = tunnel.keep_running();
+    let agent = builder.start().await?;
 
     tracing::info!("processing connection to tunnel server");
-    let tunnel_task = tokio::spawn(tunnel.run());
 
     tracing::info!(?command, "starting command");
     let mut child = command.spawn()?;
@@ -115,8 +192,7 @@ pub async fn launch(config: LaunchConfig) -> Result<(), anyhow::Error> {
     let exit_status = child.wait().await?;
 
     tracing::info!(?exit_status, "program closed");
-    keep_running.store(false, Ordering::SeqCst);
-    tunnel_task.await?;
+    agent.shutdown().await;
 
     Ok(())
 }
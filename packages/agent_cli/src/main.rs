@@ -1,10 +1,6 @@
-use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::process::Termination;
-use std::str::FromStr;
-use std::sync::Arc;
 use std::time::Duration;
 
 use clap::{arg, ArgMatches, Command};
@@ -14,8 +10,9 @@ use uuid::Uuid;
 
 use playit_agent_core::api::client::{ApiClient, ApiError};
 use playit_agent_core::api::messages::{AccountTunnel, CreateGuestSession, CreateTunnel, GetSession, ListAccountTunnels, TunnelType};
-use playit_agent_core::network::address_lookup::{AddressLookup, MatchAddress};
-use playit_agent_core::tunnel_runner::TunnelRunner;
+use playit_agent_core::agent::AgentBuilder;
+use playit_agent_core::network::local_target::{InvalidLocalTarget, LocalTarget};
+use playit_agent_core::network::mapping_overrides::{LookupWithOverrides, OverrideError};
 use playit_agent_core::utils::now_milli;
 use playit_agent_proto::PortProto;
 use crate::launch::{launch, LaunchConfig};
@@ -140,52 +137,32 @@ async fn main() -> Result<std::process::ExitCode, anyhow::Error> {
 
             let secret_key = secret.get()?;
             let api = ApiClient::new(API_BASE.to_string(), Some(secret_key.clone()));
-            let tunnels = api.req(ListAccountTunnels).await?;
-            let mut tunnel_lookup = HashMap::new();
-            let mut tunnel_found = HashSet::new();
-
-            for tunnel in tunnels.tunnels {
-                tunnel_found.insert(tunnel.id);
-                tunnel_lookup.insert(tunnel.id, tunnel);
-            }
 
             let mapping_override_strings: Vec<String> = match m.get_many::<String>("MAPPING_OVERRIDE") {
                 Some(v) => v.into_iter().map(|v| v.to_string()).collect(),
                 None => vec![],
             };
 
-            let mut mapping_overrides = Vec::new();
+            let mut local_targets = Vec::new();
             for override_str in mapping_override_strings {
                 let mut parts = override_str.split("=");
 
                 let tunnel_id: Uuid = parts.next().ok_or(CliError::InvalidMappingOverride)?
                     .parse().map_err(|_| CliError::InvalidMappingOverride)?;
 
-                let local_addr_str = parts.next().ok_or(CliError::InvalidMappingOverride)?;
-                let local_addr = match SocketAddr::from_str(local_addr_str) {
-                    Ok(addr) => addr,
-                    _ => match u16::from_str(local_addr_str) {
-                        Ok(port) => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
-                        _ => return Err(CliError::InvalidMappingOverride.into()),
-                    }
-                };
+                let local_target = parse_local_target(parts.next().ok_or(CliError::InvalidMappingOverride)?)?;
 
-                match tunnel_lookup.remove(&tunnel_id) {
-                    Some(tunnel) => {
-                        mapping_overrides.push(MappingOverride::new(tunnel, local_addr));
-                    }
-                    None => {
-                        return if tunnel_found.contains(&tunnel_id) {
-                            Err(CliError::TunnelOverwrittenAlready(tunnel_id).into())
-                        } else {
-                            Err(CliError::TunnelNotFound(tunnel_id).into())
-                        };
-                    }
-                }
+                local_targets.push((tunnel_id, local_target));
             }
 
-            let tunnel = TunnelRunner::new(secret_key, Arc::new(LookupWithOverrides(mapping_overrides))).await?;
-            tunnel.run().await;
+            let lookup = LookupWithOverrides::load(&api, local_targets).await.map_err(CliError::from)?;
+
+            let agent = AgentBuilder::new(secret_key)
+                .lookup(lookup)
+                .start()
+                .await?;
+
+            agent.wait().await;
         }
         Some(("launch", m)) => {
             let config_file = m.get_one::<String>("CONFIG_FILE").unwrap();
@@ -326,47 +303,6 @@ pub async fn tunnels_prepare(api: &ApiClient, name: Option<String>, tunnel_type:
     Err(CliError::ResourceNotFoundAfterCreate(created.id))
 }
 
-pub struct MappingOverride {
-    tunnel: AccountTunnel,
-    match_ip: Ipv6Addr,
-    local_addr: SocketAddr,
-}
-
-impl MappingOverride {
-    pub fn new(tunnel: AccountTunnel, local_addr: SocketAddr) -> Self {
-        let match_ip = LookupWithOverrides::match_ip(tunnel.ip_address);
-
-        MappingOverride {
-            tunnel,
-            match_ip,
-            local_addr,
-        }
-    }
-}
-
-pub struct LookupWithOverrides(Vec<MappingOverride>);
-
-impl AddressLookup for LookupWithOverrides {
-    fn find_tunnel_port_range(&self, match_ip: Ipv6Addr, port: u16, proto: PortProto) -> Option<(u16, u16)> {
-        for over in &self.0 {
-            if over.match_ip == match_ip && over.tunnel.from_port <= port && port < over.tunnel.to_port && (over.tunnel.port_type == PortProto::Both || over.tunnel.port_type == proto) {
-                return Some((over.tunnel.from_port, over.tunnel.to_port));
-            }
-        }
-        Some((1, u16::MAX))
-    }
-
-    fn local_address(&self, match_addr: MatchAddress, proto: PortProto) -> Option<SocketAddr> {
-        for over in &self.0 {
-            if over.match_ip == match_addr.ip && over.tunnel.from_port == match_addr.from_port && (over.tunnel.port_type == PortProto::Both || over.tunnel.port_type == proto) {
-                return Some(over.local_addr);
-            }
-        }
-
-        Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, match_addr.from_port)))
-    }
-}
-
 pub struct Secrets {
     secret: Option<String>,
     path: Option<String>,
@@ -411,25 +347,47 @@ pub enum CliError {
     InvalidPortCount,
     InvalidMappingOverride,
     InvalidConfigFile,
+    InvalidLocalTarget(String, InvalidLocalTarget),
     TunnelNotFound(Uuid),
     TunnelOverwrittenAlready(Uuid),
     ResourceNotFoundAfterCreate(Uuid),
     ApiError(ApiError),
 }
 
+pub fn parse_local_target(target: &str) -> Result<LocalTarget, CliError> {
+    target.parse().map_err(|error| CliError::InvalidLocalTarget(target.to_string(), error))
+}
+
 impl From<ApiError> for CliError {
     fn from(e: ApiError) -> Self {
         CliError::ApiError(e)
     }
 }
 
+impl From<OverrideError> for CliError {
+    fn from(e: OverrideError) -> Self {
+        match e {
+            OverrideError::TunnelNotFound(id) => CliError::TunnelNotFound(id),
+            OverrideError::TunnelOverwrittenAlready(id) => CliError::TunnelOverwrittenAlready(id),
+            OverrideError::ApiError(e) => CliError::ApiError(e),
+        }
+    }
+}
+
 impl Display for CliError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
-impl Error for CliError {}
+impl Error for CliError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CliError::InvalidLocalTarget(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
 
 fn cli() -> Command {
     Command::new("playit-cli")
@@ -499,7 +457,7 @@ fn cli() -> Command {
         .subcommand(
             Command::new("run")
                 .about("Run the playit agent")
-                .arg(arg!([MAPPING_OVERRIDE] "(format \"<tunnel-id>=[<local-ip>:]<local-port> [, ..]\")").required(false).value_delimiter(','))
+                .arg(arg!([MAPPING_OVERRIDE] "(format \"<tunnel-id>=[<local-ip>:]<local-port> | unix:<path> [, ..]\")").required(false).value_delimiter(','))
         )
         .subcommand(
             Command::new("launch")
@@ -0,0 +1,247 @@
+//! Loopback throughput of the UDP tunnel path, comparing one syscall per packet
+//! against the batched path (recvmmsg / sendmmsg on Linux). Receive rates are
+//! capped by the sender, so the CPU time of the measured thread is reported
+//! too (Linux only).
+//!
+//! cargo bench -p playit-agent-core --bench udp_loopback
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+use playit_agent_core::tunnel::udp_batch::{send_batch, UdpBatch, UDP_BATCH_SIZE, UDP_SLOT_SIZE};
+use playit_agent_core::tunnel::udp_proto::UdpFlow;
+use playit_agent_core::tunnel::udp_tunnel::{UdpTunnel, UdpTunnelRx, UDP_RECV_BUFFER_SIZE};
+use playit_agent_proto::control_messages::UdpChannelDetails;
+
+const DURATION: Duration = Duration::from_secs(3);
+const PAYLOAD_SIZE: usize = 512;
+
+fn main() {
+    /* single thread so its CPU time is the cost of the measured path */
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        println!("{:<28} {:>14} {:>16}", "path", "packets/sec", "packets/cpu sec");
+        report("tunnel recv (per packet)", bench_recv(false).await);
+        report("tunnel recv (batched)", bench_recv(true).await);
+        report("tunnel burst (per packet)", bench_burst(false).await);
+        report("tunnel burst (batched)", bench_burst(true).await);
+        report("tunnel send (per packet)", bench_send(false).await);
+        report("tunnel send (batched)", bench_send(true).await);
+    });
+}
+
+struct Sample {
+    packets: u64,
+    elapsed: Duration,
+    cpu: Option<Duration>,
+}
+
+fn report(name: &str, sample: Sample) {
+    let per_cpu_sec = match sample.cpu {
+        Some(cpu) => format!("{:.0}", sample.packets as f64 / cpu.as_secs_f64()),
+        None => "-".to_string(),
+    };
+    println!("{:<28} {:>14.0} {:>16}", name, sample.packets as f64 / sample.elapsed.as_secs_f64(), per_cpu_sec);
+}
+
+#[cfg(target_os = "linux")]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: clock_gettime only writes to the timespec it is given.
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } != 0 {
+        return None;
+    }
+    Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+fn measure(start: Instant, start_cpu: Option<Duration>, packets: u64) -> Sample {
+    Sample {
+        packets,
+        elapsed: start.elapsed(),
+        cpu: start_cpu.zip(thread_cpu_time()).map(|(start, end)| end - start),
+    }
+}
+
+fn test_flow() -> UdpFlow {
+    UdpFlow::V4 {
+        src: "10.0.0.1:1234".parse().unwrap(),
+        dst: "147.185.221.1:5000".parse().unwrap(),
+    }
+}
+
+async fn connected_tunnel() -> (UdpTunnel, UdpSocket, SocketAddr) {
+    let server = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+    let tunnel = UdpTunnel::new().await.unwrap();
+    tunnel.set_udp_tunnel(UdpChannelDetails {
+        tunnel_addr: server.local_addr().unwrap(),
+        token: Arc::new(vec![1, 2, 3, 4]),
+    }).await.unwrap();
+
+    let mut buffer = [0u8; 16];
+    let (_, agent_addr) = server.recv_from(&mut buffer).await.unwrap();
+
+    (tunnel, server, agent_addr)
+}
+
+async fn bench_recv(batched: bool) -> Sample {
+    let (tunnel, server, agent_addr) = connected_tunnel().await;
+
+    let mut packet = vec![0u8; PAYLOAD_SIZE + UdpFlow::len_v4()];
+    test_flow().write_to(&mut packet[PAYLOAD_SIZE..]);
+
+    /* sender gets its own thread so it isn't counted as receive CPU time */
+    let server = server.into_std().unwrap();
+    server.set_nonblocking(false).unwrap();
+
+    let sending = Arc::new(AtomicBool::new(true));
+    let sender = std::thread::spawn({
+        let sending = sending.clone();
+
+        move || {
+            while sending.load(Ordering::Relaxed) {
+                let _ = server.send_to(&packet, agent_addr);
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let start_cpu = thread_cpu_time();
+    let mut received = 0u64;
+
+    if batched {
+        let mut batch = UdpBatch::new(UDP_SLOT_SIZE, UDP_BATCH_SIZE);
+
+        while start.elapsed() < DURATION {
+            let count = tunnel.receive_batch(&mut batch).await.unwrap();
+            for index in 0..count {
+                if let Ok(UdpTunnelRx::ReceivedPacket { .. }) = tunnel.read_batch_packet(&batch, index) {
+                    received += 1;
+                }
+            }
+        }
+    } else {
+        let mut buffer = vec![0u8; UDP_RECV_BUFFER_SIZE];
+
+        while start.elapsed() < DURATION {
+            if let Ok(UdpTunnelRx::ReceivedPacket { .. }) = tunnel.receive_from(&mut buffer).await {
+                received += 1;
+            }
+        }
+    }
+
+    let sample = measure(start, start_cpu, received);
+    sending.store(false, Ordering::Relaxed);
+    sender.join().unwrap();
+
+    sample
+}
+
+/* BURST_SIZE datagrams are queued before each drain, only draining is timed */
+const BURST_SIZE: u64 = 64;
+
+async fn bench_burst(batched: bool) -> Sample {
+    let (tunnel, server, agent_addr) = connected_tunnel().await;
+
+    let mut packet = vec![0u8; PAYLOAD_SIZE + UdpFlow::len_v4()];
+    test_flow().write_to(&mut packet[PAYLOAD_SIZE..]);
+    let packets = vec![&packet[..]; BURST_SIZE as usize];
+
+    let mut batch = UdpBatch::new(UDP_SLOT_SIZE, UDP_BATCH_SIZE);
+    let mut buffer = vec![0u8; UDP_RECV_BUFFER_SIZE];
+
+    let mut elapsed = Duration::ZERO;
+    let mut cpu = Some(Duration::ZERO);
+    let mut received = 0u64;
+
+    while elapsed < DURATION {
+        send_batch(&server, &packets, agent_addr).await.unwrap();
+
+        let start = Instant::now();
+        let start_cpu = thread_cpu_time();
+        let mut drained = 0;
+
+        while drained < BURST_SIZE {
+            if batched {
+                let count = tunnel.receive_batch(&mut batch).await.unwrap();
+                for index in 0..count {
+                    if let Ok(UdpTunnelRx::ReceivedPacket { .. }) = tunnel.read_batch_packet(&batch, index) {
+                        drained += 1;
+                    }
+                }
+            } else if let Ok(UdpTunnelRx::ReceivedPacket { .. }) = tunnel.receive_from(&mut buffer).await {
+                drained += 1;
+            }
+        }
+
+        let sample = measure(start, start_cpu, drained);
+        elapsed += sample.elapsed;
+        cpu = cpu.zip(sample.cpu).map(|(total, cpu)| total + cpu);
+        received += drained;
+    }
+
+    Sample { packets: received, elapsed, cpu }
+}
+
+async fn bench_send(batched: bool) -> Sample {
+    let (tunnel, _server, _) = connected_tunnel().await;
+
+    let start = Instant::now();
+    let start_cpu = thread_cpu_time();
+    let mut sent = 0u64;
+
+    if batched {
+        let mut batch = full_batch().await;
+        let packets: Vec<(usize, UdpFlow)> = (0..batch.len()).map(|index| (index, test_flow())).collect();
+
+        while start.elapsed() < DURATION {
+            tunnel.send_batch(&mut batch, &packets).await.unwrap();
+            sent += packets.len() as u64;
+        }
+    } else {
+        let mut data = vec![0u8; PAYLOAD_SIZE];
+
+        while start.elapsed() < DURATION {
+            data.truncate(PAYLOAD_SIZE);
+            tunnel.send(&mut data, test_flow()).await.unwrap();
+            sent += 1;
+        }
+    }
+
+    measure(start, start_cpu, sent)
+}
+
+/* batches grow as they are filled, keep receiving until every slot holds a packet */
+async fn full_batch() -> UdpBatch {
+    let host = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let sender = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+    let payload = vec![0u8; PAYLOAD_SIZE];
+    let packets = vec![&payload[..]; UDP_BATCH_SIZE];
+    let mut batch = UdpBatch::new(UDP_SLOT_SIZE, UDP_BATCH_SIZE);
+
+    loop {
+        send_batch(&sender, &packets, host.local_addr().unwrap()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        if batch.recv_from(&host).await.unwrap() == UDP_BATCH_SIZE {
+            return batch;
+        }
+
+        /* drain leftovers so the next round starts clean */
+        while host.try_recv_from(&mut [0u8; UDP_RECV_BUFFER_SIZE]).is_ok() {}
+    }
+}
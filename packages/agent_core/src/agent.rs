@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+use crate::api::client::ApiClient;
+use crate::audit_log::AuditLog;
+use crate::events::{AgentEvent, AgentEvents, EventSink};
+use crate::network::address_lookup::AddressLookup;
+use crate::network::geo_filter::GeoLookup;
+use crate::network::packet_filter::PacketFilter;
+use crate::network::mapping_overrides::LookupWithOverrides;
+use crate::network::rate_limit::RateLimits;
+use crate::tunnel::setup::SetupError;
+use crate::tunnel_runner::{AgentStats, RunnerStats, TunnelRunner};
+
+pub const DEFAULT_API_BASE: &str = "https://api.playit.cloud";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentEndpoints {
+    pub api_base: String,
+    pub control_host: String,
+    pub control_port: u16,
+}
+
+impl Default for AgentEndpoints {
+    fn default() -> Self {
+        AgentEndpoints {
+            api_base: DEFAULT_API_BASE.to_string(),
+            control_host: "control.playit.gg".to_string(),
+            control_port: 5525,
+        }
+    }
+}
+
+/// Configures and starts an agent inside the calling process.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), playit_agent_core::tunnel::setup::SetupError> {
+/// use playit_agent_core::agent::AgentBuilder;
+///
+/// let agent = AgentBuilder::new("secret".to_string())
+///     .use_special_lan(false)
+///     .start()
+///     .await?;
+///
+/// println!("{:?}", agent.stats().await);
+/// agent.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AgentBuilder<L: AddressLookup> {
+    secret_key: String,
+    endpoints: AgentEndpoints,
+    lookup: L,
+    use_special_lan: bool,
+    rate_limits: RateLimits,
+    max_tcp_clients: Option<usize>,
+    geo_lookup: Option<Arc<dyn GeoLookup>>,
+    packet_filter: Option<Arc<dyn PacketFilter>>,
+    setup_timeout: Duration,
+    shutdown_timeout: Duration,
+    event_sinks: Vec<Arc<dyn EventSink>>,
+}
+
+impl AgentBuilder<LookupWithOverrides> {
+    /// Uses a lookup without overrides, sending each tunnel to localhost
+    /// on the tunnel's port. See [`AgentBuilder::lookup`] to change that.
+    pub fn new(secret_key: String) -> Self {
+        AgentBuilder {
+            secret_key,
+            endpoints: AgentEndpoints::default(),
+            lookup: LookupWithOverrides::default(),
+            use_special_lan: true,
+            rate_limits: RateLimits::default(),
+            max_tcp_clients: None,
+            geo_lookup: None,
+            packet_filter: None,
+            setup_timeout: Duration::from_secs(60),
+            shutdown_timeout: Duration::from_secs(5),
+            event_sinks: Vec::new(),
+        }
+    }
+}
+
+impl<L: AddressLookup + Send + Sync> AgentBuilder<L> {
+    pub fn endpoints(mut self, endpoints: AgentEndpoints) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    pub fn lookup<N: AddressLookup>(self, lookup: N) -> AgentBuilder<N> {
+        AgentBuilder {
+            secret_key: self.secret_key,
+            endpoints: self.endpoints,
+            lookup,
+            use_special_lan: self.use_special_lan,
+            rate_limits: self.rate_limits,
+            max_tcp_clients: self.max_tcp_clients,
+            geo_lookup: self.geo_lookup,
+            packet_filter: self.packet_filter,
+            setup_timeout: self.setup_timeout,
+            shutdown_timeout: self.shutdown_timeout,
+            event_sinks: self.event_sinks,
+        }
+    }
+
+    pub fn use_special_lan(mut self, use_special_lan: bool) -> Self {
+        self.use_special_lan = use_special_lan;
+        self
+    }
+
+    /// Token bucket limits for new TCP clients and UDP flows.
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits = limits;
+        self
+    }
+
+    /// Caps concurrent TCP clients across all tunnels, see
+    /// `TcpSettings::max_connections` for a per tunnel limit.
+    pub fn max_tcp_clients(mut self, max: Option<usize>) -> Self {
+        self.max_tcp_clients = max;
+        self
+    }
+
+    /// Source of country and ASN for tunnels with `GeoRules`, without one
+    /// every address is treated as unknown.
+    pub fn geo_lookup<G: GeoLookup>(mut self, lookup: G) -> Self {
+        self.geo_lookup = Some(Arc::new(lookup));
+        self
+    }
+
+    /// Decides to accept, reject or route each new TCP client and UDP flow.
+    pub fn packet_filter<F: PacketFilter>(mut self, filter: F) -> Self {
+        self.packet_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Max time to connect and authenticate with the control server.
+    pub fn setup_timeout(mut self, timeout: Duration) -> Self {
+        self.setup_timeout = timeout;
+        self
+    }
+
+    /// Max time to wait for tasks to stop before they are aborted.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Adds a sink called for every event, can be called more than once.
+    pub fn event_sink<S: EventSink>(mut self, sink: S) -> Self {
+        self.event_sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Records every closed TCP client and UDP flow, see [`AuditLog::open`].
+    pub fn audit_log(self, log: AuditLog) -> Self {
+        self.event_sink(log)
+    }
+
+    /// Client for the configured api, authenticated with the agent's secret.
+    pub fn api_client(&self) -> ApiClient {
+        ApiClient::new(self.endpoints.api_base.clone(), Some(self.secret_key.clone()))
+    }
+
+    pub async fn start(self) -> Result<AgentHandle, SetupError> {
+        let mut events = AgentEvents::default();
+        for sink in self.event_sinks {
+            events.add_sink(sink);
+        }
+
+        let setup = TunnelRunner::setup(self.endpoints, self.secret_key, Arc::new(self.lookup), events.clone());
+        let mut runner = match tokio::time::timeout(self.setup_timeout, setup).await {
+            Ok(res) => res?,
+            Err(_) => return Err(SetupError::Timeout),
+        };
+
+        runner.set_use_special_lan(self.use_special_lan);
+        runner.set_rate_limits(self.rate_limits);
+        runner.set_max_tcp_clients(self.max_tcp_clients);
+        runner.set_geo_lookup(self.geo_lookup);
+        runner.set_packet_filter(self.packet_filter);
+
+        Ok(AgentHandle {
+            keep_running: runner.keep_running(),
+            stats: runner.stats(),
+            events,
+            shutdown_timeout: self.shutdown_timeout,
+            task: tokio::spawn(runner.run()),
+        })
+    }
+}
+
+pub struct AgentHandle {
+    keep_running: Arc<AtomicBool>,
+    stats: RunnerStats,
+    events: AgentEvents,
+    shutdown_timeout: Duration,
+    task: JoinHandle<()>,
+}
+
+impl AgentHandle {
+    pub async fn stats(&self) -> AgentStats {
+        self.stats.snapshot().await
+    }
+
+    pub fn events(&self) -> broadcast::Receiver<AgentEvent> {
+        self.events.subscribe()
+    }
+
+    /// Waits for the agent to stop, it only stops on its own if its tasks can't be restarted.
+    pub async fn wait(self) {
+        if let Err(error) = self.task.await {
+            tracing::error!(?error, "agent task failed");
+        }
+    }
+
+    pub async fn shutdown(mut self) {
+        self.keep_running.store(false, Ordering::SeqCst);
+
+        match tokio::time::timeout(self.shutdown_timeout, &mut self.task).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => tracing::error!(?error, "agent task failed"),
+            Err(_) => {
+                tracing::warn!("agent did not stop in time, aborting");
+                self.task.abort();
+            }
+        }
+    }
+}
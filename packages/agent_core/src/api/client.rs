@@ -49,6 +49,10 @@ impl ApiClient {
         }
     }
 
+    pub fn api_base(&self) -> &str {
+        &self.api_base
+    }
+
     pub async fn sign_and_register(&self, details: SignAgentRegister) -> Result<SignedAgentRegister, ApiError> {
         self.req(details).await
     }
@@ -0,0 +1,270 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use serde::{Deserialize, Serialize};
+
+use crate::events::{AgentEvent, EventSink};
+use crate::network::tcp_pipe::PipeCloseReason;
+use crate::network::udp_clients::UdpCloseReason;
+use crate::utils::now_milli;
+
+const CSV_HEADER: &str = "proto,peer_addr,tunnel_addr,local_target,lan_addr,started_at,ended_at,bytes_to_local,bytes_to_tunnel,close_reason";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditFormat {
+    #[default]
+    JsonLines,
+    Csv,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditLogSettings {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: AuditFormat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditProto {
+    Tcp,
+    Udp,
+}
+
+/// One closed TCP connection or UDP flow, times are unix milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub proto: AuditProto,
+    pub peer_addr: SocketAddr,
+    pub tunnel_addr: SocketAddr,
+    pub local_target: String,
+    pub lan_addr: Option<SocketAddr>,
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub bytes_to_local: u64,
+    pub bytes_to_tunnel: u64,
+    pub close_reason: String,
+}
+
+impl AuditRecord {
+    /// Builds a record from the close event of a connection or flow.
+    pub fn from_event(event: &AgentEvent, ended_at: u64) -> Option<Self> {
+        match event {
+            AgentEvent::TcpClientClosed { peer_addr, tunnel_addr, local_target, lan_addr, started_at, summary } => Some(AuditRecord {
+                proto: AuditProto::Tcp,
+                peer_addr: *peer_addr,
+                tunnel_addr: *tunnel_addr,
+                local_target: local_target.clone(),
+                lan_addr: *lan_addr,
+                started_at: *started_at,
+                ended_at,
+                bytes_to_local: summary.tunnel_to_local,
+                bytes_to_tunnel: summary.local_to_tunnel,
+                close_reason: match summary.close_reason {
+                    PipeCloseReason::Eof => "eof".to_string(),
+                    PipeCloseReason::IdleTimeout => "idle_timeout".to_string(),
+                    PipeCloseReason::Error(kind) => format!("error:{:?}", kind),
+                },
+            }),
+            AgentEvent::UdpFlowClosed { peer_addr, tunnel_addr, local_target, lan_addr, started_at, summary } => Some(AuditRecord {
+                proto: AuditProto::Udp,
+                peer_addr: *peer_addr,
+                tunnel_addr: *tunnel_addr,
+                local_target: local_target.clone(),
+                lan_addr: *lan_addr,
+                started_at: *started_at,
+                ended_at,
+                bytes_to_local: summary.tunnel_to_local,
+                bytes_to_tunnel: summary.local_to_tunnel,
+                close_reason: match summary.close_reason {
+                    UdpCloseReason::IdleTimeout => "idle_timeout".to_string(),
+                    UdpCloseReason::Evicted => "evicted".to_string(),
+                    UdpCloseReason::Disconnected => "disconnected".to_string(),
+                    UdpCloseReason::Error(kind) => format!("error:{:?}", kind),
+                },
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W, format: AuditFormat) -> std::io::Result<()> {
+        match format {
+            AuditFormat::JsonLines => {
+                serde_json::to_writer(&mut *out, self)?;
+                writeln!(out)
+            }
+            AuditFormat::Csv => {
+                let proto = match self.proto {
+                    AuditProto::Tcp => "tcp",
+                    AuditProto::Udp => "udp",
+                };
+
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    proto,
+                    self.peer_addr,
+                    self.tunnel_addr,
+                    csv_field(&self.local_target),
+                    self.lan_addr.map(|addr| addr.to_string()).unwrap_or_default(),
+                    self.started_at,
+                    self.ended_at,
+                    self.bytes_to_local,
+                    self.bytes_to_tunnel,
+                    csv_field(&self.close_reason),
+                )
+            }
+        }
+    }
+}
+
+/* local targets can be unix socket paths, quote anything that would break the row */
+fn csv_field(value: &str) -> String {
+    if !value.contains([',', '"', '\n', '\r']) {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Appends a record for every closed TCP client and UDP flow. Records are
+/// written from a separate thread so emitting events never waits on disk,
+/// pending records are flushed when the log is dropped.
+pub struct AuditLog {
+    sender: Option<mpsc::Sender<AuditRecord>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    pub fn open(settings: &AuditLogSettings) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&settings.path)?;
+        let is_empty = file.metadata()?.len() == 0;
+
+        let mut out = BufWriter::new(file);
+        if settings.format == AuditFormat::Csv && is_empty {
+            writeln!(out, "{}", CSV_HEADER)?;
+            out.flush()?;
+        }
+
+        let format = settings.format;
+        let (sender, receiver) = mpsc::channel::<AuditRecord>();
+
+        let writer = std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || {
+                for record in receiver {
+                    if let Err(error) = record.write(&mut out, format).and_then(|_| out.flush()) {
+                        tracing::error!(?error, "failed to write audit log record");
+                    }
+                }
+            })?;
+
+        Ok(AuditLog {
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+}
+
+impl EventSink for AuditLog {
+    fn on_event(&self, event: &AgentEvent) {
+        let (Some(sender), Some(record)) = (&self.sender, AuditRecord::from_event(event, now_milli())) else {
+            return;
+        };
+
+        let _ = sender.send(record);
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        /* closing the channel lets the writer finish what's queued */
+        self.sender.take();
+
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::network::tcp_pipe::PipeSummary;
+    use crate::network::udp_clients::UdpFlowSummary;
+
+    use super::*;
+
+    fn events() -> Vec<AgentEvent> {
+        vec![
+            AgentEvent::TcpClientClosed {
+                peer_addr: "1.2.3.4:5000".parse().unwrap(),
+                tunnel_addr: "147.185.221.1:25565".parse().unwrap(),
+                local_target: "127.0.0.1:25565".to_string(),
+                lan_addr: Some("127.3.4.5:40000".parse().unwrap()),
+                started_at: 1000,
+                summary: PipeSummary {
+                    tunnel_to_local: 10,
+                    local_to_tunnel: 20,
+                    duration: Duration::from_secs(1),
+                    close_reason: PipeCloseReason::Eof,
+                },
+            },
+            AgentEvent::UdpFlowClosed {
+                peer_addr: "1.2.3.4:5001".parse().unwrap(),
+                tunnel_addr: "147.185.221.1:19132".parse().unwrap(),
+                local_target: "a,\"b\"".to_string(),
+                lan_addr: None,
+                started_at: 1000,
+                summary: UdpFlowSummary {
+                    tunnel_to_local: 30,
+                    local_to_tunnel: 40,
+                    duration: Duration::from_secs(1),
+                    close_reason: UdpCloseReason::Evicted,
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn test_audit_log_formats() {
+        let path = std::env::temp_dir().join(format!("playit-audit-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let settings = AuditLogSettings { path: path.clone(), format: AuditFormat::Csv };
+        for _ in 0..2 {
+            let log = AuditLog::open(&settings).unwrap();
+            for event in events() {
+                log.on_event(&event);
+            }
+            log.on_event(&AgentEvent::UdpFlowOpened {
+                peer_addr: "1.2.3.4:5001".parse().unwrap(),
+                tunnel_addr: "147.185.221.1:19132".parse().unwrap(),
+                local_target: String::new(),
+            });
+        }
+
+        /* header is only written to a new file */
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].starts_with("tcp,1.2.3.4:5000,147.185.221.1:25565,127.0.0.1:25565,127.3.4.5:40000,1000,"));
+        assert!(lines[1].ends_with(",10,20,eof"));
+        assert!(lines[2].starts_with("udp,1.2.3.4:5001,147.185.221.1:19132,\"a,\"\"b\"\"\",,1000,"));
+        assert!(lines[2].ends_with(",30,40,evicted"));
+
+        let record = AuditRecord::from_event(&events()[1], 2000).unwrap();
+        let mut json = Vec::new();
+        record.write(&mut json, AuditFormat::JsonLines).unwrap();
+        assert_eq!(serde_json::from_slice::<AuditRecord>(&json).unwrap(), record);
+        assert!(json.ends_with(b"}\n"));
+    }
+}
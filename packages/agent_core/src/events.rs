@@ -0,0 +1,102 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+
+use crate::network::address_lookup::MatchAddress;
+use crate::network::tcp_pipe::PipeSummary;
+use crate::network::udp_clients::UdpFlowSummary;
+use crate::supervisor::{HealthStatus, Subsystem};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentEvent {
+    HealthChanged {
+        subsystem: Subsystem,
+        status: HealthStatus,
+    },
+    TcpClientConnected {
+        peer_addr: SocketAddr,
+        tunnel_addr: SocketAddr,
+        local_target: String,
+    },
+    TcpClientClosed {
+        peer_addr: SocketAddr,
+        tunnel_addr: SocketAddr,
+        local_target: String,
+        /* local address of the agent's tunnel socket, a special lan address when one was used */
+        lan_addr: Option<SocketAddr>,
+        /* unix time in milliseconds */
+        started_at: u64,
+        summary: PipeSummary,
+    },
+    UdpFlowOpened {
+        peer_addr: SocketAddr,
+        tunnel_addr: SocketAddr,
+        local_target: String,
+    },
+    UdpFlowClosed {
+        peer_addr: SocketAddr,
+        tunnel_addr: SocketAddr,
+        local_target: String,
+        /* local address of the socket sending to the local server */
+        lan_addr: Option<SocketAddr>,
+        started_at: u64,
+        summary: UdpFlowSummary,
+    },
+    LocalTargetHealthChanged {
+        tunnel: MatchAddress,
+        local_target: String,
+        healthy: bool,
+    },
+}
+
+/// Receives every event as it happens, called inline so must not block.
+pub trait EventSink: Send + Sync + 'static {
+    fn on_event(&self, event: &AgentEvent);
+}
+
+impl<F: Fn(&AgentEvent) + Send + Sync + 'static> EventSink for F {
+    fn on_event(&self, event: &AgentEvent) {
+        self(event)
+    }
+}
+
+/// Fans events out to the sinks and any number of subscribers.
+/// Slow subscribers miss events rather than holding up the agent.
+#[derive(Clone)]
+pub struct AgentEvents {
+    sender: broadcast::Sender<AgentEvent>,
+    sinks: Vec<Arc<dyn EventSink>>,
+}
+
+impl Default for AgentEvents {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl AgentEvents {
+    pub fn new(sink: Option<Arc<dyn EventSink>>) -> Self {
+        let (sender, _) = broadcast::channel(256);
+        AgentEvents { sender, sinks: sink.into_iter().collect() }
+    }
+
+    /// Sinks see every event, unlike subscribers, so anything that must not
+    /// miss events (such as the audit log) should be added as one.
+    pub fn add_sink(&mut self, sink: Arc<dyn EventSink>) {
+        self.sinks.push(sink);
+    }
+
+    pub fn emit(&self, event: AgentEvent) {
+        for sink in &self.sinks {
+            sink.on_event(&event);
+        }
+
+        /* errors only when there are no subscribers */
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentEvent> {
+        self.sender.subscribe()
+    }
+}
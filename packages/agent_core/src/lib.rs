@@ -5,6 +5,10 @@ pub mod tunnel;
 pub mod network;
 pub mod utils;
 pub mod tunnel_runner;
+pub mod supervisor;
+pub mod events;
+pub mod audit_log;
+pub mod agent;
 
 #[cfg(test)]
 mod test {
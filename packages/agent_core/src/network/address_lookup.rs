@@ -3,18 +3,48 @@ use std::sync::Arc;
 
 use playit_agent_proto::PortProto;
 
+use crate::network::local_target::LocalTarget;
+use crate::network::tls_sni::SniRoutes;
+use crate::network::tunnel_settings::TunnelSettings;
+
 pub trait AddressLookup: 'static {
     fn find_tunnel_port_range(&self, match_ip: Ipv6Addr, port: u16, proto: PortProto) -> Option<(u16, u16)>;
 
     fn local_address(&self, match_addr: MatchAddress, proto: PortProto) -> Option<SocketAddr>;
 
+    fn tunnel_settings(&self, _match_addr: MatchAddress, _proto: PortProto) -> TunnelSettings {
+        TunnelSettings::default()
+    }
+
+    /// Tunnels with a known local target, these get health checked.
+    fn mapped_tunnels(&self) -> Vec<(MatchAddress, PortProto)> {
+        Vec::new()
+    }
+
+    /// Override to send clients somewhere other than a local socket address.
+    fn local_target(&self, match_addr: MatchAddress, proto: PortProto) -> Option<LocalTarget> {
+        self.local_address(match_addr, proto).map(LocalTarget::Addr)
+    }
+
+    /// TCP clients of tunnels with routes are sent by the TLS server name
+    /// they ask for, names without a route use the tunnel's local target.
+    fn sni_routes(&self, _match_addr: MatchAddress, _proto: PortProto) -> Option<Arc<SniRoutes>> {
+        None
+    }
+
+    fn local_target_mapping(&self, tunnel_addr: SocketAddr, proto: PortProto) -> Option<LocalTarget> {
+        let match_addr = self.tunnel_match_address(tunnel_addr, proto)?;
+        let target = self.local_target(match_addr, proto)?;
+        target.with_port_offset(tunnel_addr.port() - match_addr.from_port)
+    }
+
     fn local_mapping(&self, tunnel_addr: SocketAddr, proto: PortProto) -> Option<SocketAddr> {
         let match_addr = self.tunnel_match_address(tunnel_addr, proto)?;
         let mut local_addr = self.local_address(match_addr, proto)?;
 
         let port_offset = tunnel_addr.port() - match_addr.from_port;
         let local_port = local_addr.port();
-        local_addr.set_port(local_port + port_offset);
+        local_addr.set_port(local_port.checked_add(port_offset)?);
 
         Some(local_addr)
     }
@@ -70,6 +100,22 @@ impl<T: AddressLookup> AddressLookup for Arc<T> {
         (&*self as &T).local_address(match_addr, proto)
     }
 
+    fn tunnel_settings(&self, match_addr: MatchAddress, proto: PortProto) -> TunnelSettings {
+        T::tunnel_settings(self, match_addr, proto)
+    }
+
+    fn local_target(&self, match_addr: MatchAddress, proto: PortProto) -> Option<LocalTarget> {
+        T::local_target(self, match_addr, proto)
+    }
+
+    fn mapped_tunnels(&self) -> Vec<(MatchAddress, PortProto)> {
+        T::mapped_tunnels(self)
+    }
+
+    fn sni_routes(&self, match_addr: MatchAddress, proto: PortProto) -> Option<Arc<SniRoutes>> {
+        T::sni_routes(self, match_addr, proto)
+    }
+
     fn tunnel_match_address(&self, tunnel_addr: SocketAddr, proto: PortProto) -> Option<MatchAddress> {
         (&*self as &T).tunnel_match_address(tunnel_addr, proto)
     }
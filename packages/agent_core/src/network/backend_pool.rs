@@ -0,0 +1,378 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpStream, UdpSocket};
+
+use playit_agent_proto::PortProto;
+
+use crate::network::raknet;
+use crate::utils::now_milli;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    /* keeps clients from the same ip on the same backend while it is healthy */
+    PeerIpHash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthProbe {
+    /* healthy if a connection can be opened */
+    Tcp,
+    /* healthy if the payload gets any response */
+    Udp { payload: Vec<u8> },
+    /* healthy if an unconnected ping gets a pong, for Minecraft Bedrock servers */
+    #[serde(rename = "raknet")]
+    RakNet,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HealthCheckSettings {
+    /* off unless configured, probes open extra connections to the local server */
+    pub enabled: bool,
+    pub probe: HealthProbe,
+    pub interval_secs: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for HealthCheckSettings {
+    fn default() -> Self {
+        HealthCheckSettings {
+            enabled: false,
+            probe: HealthProbe::Tcp,
+            interval_secs: 10,
+            timeout_ms: 2_000,
+        }
+    }
+}
+
+impl HealthCheckSettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.max(1))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms.max(1))
+    }
+
+    /// TCP probes can't check tunnels that only forward UDP.
+    pub fn applies_to(&self, proto: PortProto) -> bool {
+        self.enabled && !(self.probe == HealthProbe::Tcp && proto == PortProto::Udp)
+    }
+
+    pub async fn check(&self, addr: SocketAddr) -> bool {
+        match &self.probe {
+            HealthProbe::Tcp => matches!(tokio::time::timeout(self.timeout(), TcpStream::connect(addr)).await, Ok(Ok(_))),
+            HealthProbe::Udp { payload } => matches!(tokio::time::timeout(self.timeout(), udp_probe(addr, payload)).await, Ok(Ok(_))),
+            HealthProbe::RakNet => {
+                let ping = raknet::unconnected_ping(now_milli());
+                matches!(tokio::time::timeout(self.timeout(), udp_probe(addr, &ping)).await, Ok(Ok(_)))
+            }
+        }
+    }
+}
+
+async fn udp_probe(addr: SocketAddr, payload: &[u8]) -> std::io::Result<usize> {
+    let bind_ip = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?;
+    /* connected so ICMP port unreachable shows up as an error */
+    socket.connect(addr).await?;
+    socket.send(payload).await?;
+
+    let mut buffer = [0u8; 2048];
+    socket.recv(&mut buffer).await
+}
+
+pub struct Backend {
+    addr: SocketAddr,
+    healthy: AtomicBool,
+    connections: AtomicUsize,
+}
+
+impl Backend {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+}
+
+/// Set of local servers sharing one tunnel. Unhealthy backends are skipped
+/// unless every backend is unhealthy, in which case all are tried.
+pub struct BackendPool {
+    backends: Vec<Backend>,
+    strategy: BalanceStrategy,
+    next: AtomicUsize,
+    /* without health checks nothing would bring an ejected backend back */
+    health_checked: AtomicBool,
+}
+
+impl BackendPool {
+    pub fn new(addrs: Vec<SocketAddr>, strategy: BalanceStrategy) -> Arc<Self> {
+        Arc::new(BackendPool {
+            backends: addrs.into_iter().map(|addr| Backend {
+                addr,
+                healthy: AtomicBool::new(true),
+                connections: AtomicUsize::new(0),
+            }).collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+            health_checked: AtomicBool::new(false),
+        })
+    }
+
+    pub fn backends(&self) -> &[Backend] {
+        &self.backends
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    pub fn set_healthy(&self, index: usize, healthy: bool) {
+        let backend = &self.backends[index];
+        if backend.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            tracing::info!(addr = %backend.addr, healthy, "backend health changed");
+        }
+    }
+
+    /// Picks a backend for a new client, the connection is counted until the guard is dropped.
+    pub fn select(self: &Arc<Self>, peer_addr: SocketAddr) -> Option<BackendGuard> {
+        let count = self.backends.len();
+        if count == 0 {
+            return None;
+        }
+
+        let any_healthy = self.backends.iter().any(Backend::is_healthy);
+        let usable = |index: usize| !any_healthy || self.backends[index].is_healthy();
+
+        let index = match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                /* the counter wraps, reduce it first so adding the offset can't overflow */
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count).map(|i| (start % count + i) % count).find(|i| usable(*i))?
+            }
+            BalanceStrategy::LeastConnections => {
+                (0..count).filter(|i| usable(*i)).min_by_key(|i| self.backends[*i].connections())?
+            }
+            BalanceStrategy::PeerIpHash => {
+                let mut hasher = DefaultHasher::new();
+                peer_addr.ip().hash(&mut hasher);
+                let start = hasher.finish() as usize;
+                (0..count).map(|i| (start % count + i) % count).find(|i| usable(*i))?
+            }
+        };
+
+        self.backends[index].connections.fetch_add(1, Ordering::Relaxed);
+
+        Some(BackendGuard {
+            pool: self.clone(),
+            index,
+        })
+    }
+
+    /// Probes every backend on an interval until the pool is dropped or
+    /// `keep_running` is cleared. Only the first call starts the checks.
+    pub fn start_health_checks(self: &Arc<Self>, settings: HealthCheckSettings, keep_running: Arc<AtomicBool>) {
+        if self.health_checked.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let pool = Arc::downgrade(self);
+        tokio::spawn(run_health_checks(pool, settings, keep_running));
+    }
+}
+
+async fn run_health_checks(pool: Weak<BackendPool>, settings: HealthCheckSettings, keep_running: Arc<AtomicBool>) {
+    while keep_running.load(Ordering::SeqCst) {
+        let addrs = match pool.upgrade() {
+            Some(pool) => pool.backends.iter().map(Backend::addr).collect::<Vec<_>>(),
+            None => return,
+        };
+
+        for (index, addr) in addrs.into_iter().enumerate() {
+            let healthy = settings.check(addr).await;
+
+            match pool.upgrade() {
+                Some(pool) => pool.set_healthy(index, healthy),
+                None => return,
+            }
+        }
+
+        tokio::time::sleep(settings.interval()).await;
+    }
+}
+
+pub struct BackendGuard {
+    pool: Arc<BackendPool>,
+    index: usize,
+}
+
+impl BackendGuard {
+    pub fn addr(&self) -> SocketAddr {
+        self.pool.backends[self.index].addr
+    }
+
+    /// Ejects the backend until a health check finds it working again, pools
+    /// without health checks keep using it.
+    pub fn mark_unhealthy(&self) {
+        if self.pool.health_checked.load(Ordering::Relaxed) {
+            self.pool.set_healthy(self.index, false);
+        }
+    }
+
+    pub fn mark_healthy(&self) {
+        self.pool.set_healthy(self.index, true);
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.pool.backends[self.index].connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn addrs() -> Vec<SocketAddr> {
+        vec!["127.0.0.1:1001".parse().unwrap(), "127.0.0.1:1002".parse().unwrap(), "127.0.0.1:1003".parse().unwrap()]
+    }
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 5000)
+    }
+
+    #[test]
+    fn test_round_robin_skips_unhealthy() {
+        let pool = BackendPool::new(addrs(), BalanceStrategy::RoundRobin);
+        pool.set_healthy(1, false);
+
+        let picked: Vec<u16> = (0..4).map(|_| pool.select(peer("1.1.1.1")).unwrap().addr().port()).collect();
+        assert_eq!(picked, vec![1001, 1003, 1003, 1001]);
+    }
+
+    #[test]
+    fn test_round_robin_counter_wraps() {
+        let pool = BackendPool::new(addrs(), BalanceStrategy::RoundRobin);
+        pool.next.store(usize::MAX, Ordering::Relaxed);
+        pool.set_healthy((usize::MAX % 3 + 1) % 3, false);
+        pool.set_healthy(usize::MAX % 3, false);
+
+        for _ in 0..3 {
+            assert!(pool.select(peer("1.1.1.1")).is_some());
+        }
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = BackendPool::new(addrs(), BalanceStrategy::LeastConnections);
+
+        let first = pool.select(peer("1.1.1.1")).unwrap();
+        let second = pool.select(peer("1.1.1.1")).unwrap();
+        assert_eq!((first.addr().port(), second.addr().port()), (1001, 1002));
+
+        drop(first);
+        assert_eq!(pool.select(peer("1.1.1.1")).unwrap().addr().port(), 1001);
+        assert_eq!(pool.backends()[1].connections(), 1);
+    }
+
+    #[test]
+    fn test_peer_ip_hash_is_sticky() {
+        let pool = BackendPool::new(addrs(), BalanceStrategy::PeerIpHash);
+
+        let picked = pool.select(peer("1.2.3.4")).unwrap().addr();
+        for _ in 0..10 {
+            assert_eq!(pool.select(peer("1.2.3.4")).unwrap().addr(), picked);
+        }
+
+        /* fails over when the backend is ejected */
+        let index = pool.backends().iter().position(|b| b.addr() == picked).unwrap();
+        pool.set_healthy(index, false);
+        assert_ne!(pool.select(peer("1.2.3.4")).unwrap().addr(), picked);
+    }
+
+    #[test]
+    fn test_all_unhealthy_still_selects() {
+        let pool = BackendPool::new(addrs(), BalanceStrategy::RoundRobin);
+        for index in 0..pool.len() {
+            pool.set_healthy(index, false);
+        }
+        assert!(pool.select(peer("1.1.1.1")).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_ejects_dead_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive = listener.local_addr().unwrap();
+
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let pool = BackendPool::new(vec![alive, dead], BalanceStrategy::RoundRobin);
+        assert!(!HealthCheckSettings::default().applies_to(PortProto::Tcp));
+        pool.start_health_checks(HealthCheckSettings {
+            enabled: true,
+            timeout_ms: 500,
+            ..HealthCheckSettings::default()
+        }, Arc::new(AtomicBool::new(true)));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(pool.backends()[0].is_healthy());
+        assert!(!pool.backends()[1].is_healthy());
+    }
+
+    #[test]
+    fn test_mark_unhealthy_needs_health_checks() {
+        let pool = BackendPool::new(addrs(), BalanceStrategy::RoundRobin);
+
+        /* nothing would mark it healthy again */
+        pool.select(peer("1.1.1.1")).unwrap().mark_unhealthy();
+        assert!(pool.backends()[0].is_healthy());
+    }
+
+    #[tokio::test]
+    async fn test_health_checks_stop_with_keep_running() {
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let pool = BackendPool::new(vec![dead], BalanceStrategy::RoundRobin);
+
+        let keep_running = Arc::new(AtomicBool::new(false));
+        pool.start_health_checks(HealthCheckSettings { enabled: true, ..HealthCheckSettings::default() }, keep_running);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pool.backends()[0].is_healthy());
+
+        /* the pool is health checked, so a failed connect ejects the backend */
+        pool.select(peer("1.1.1.1")).unwrap().mark_unhealthy();
+        assert!(!pool.backends()[0].is_healthy());
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::network::address_lookup::MatchAddress;
+
+/* smallest burst so a full packet or read buffer always fits */
+const MIN_BURST: u64 = 64 * 1024;
+
+/// Upload is data sent to players (local to tunnel), download is data
+/// received from players (tunnel to local).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct BandwidthLimit {
+    pub upload_bytes_per_sec: Option<u64>,
+    pub download_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct BandwidthSettings {
+    /* shared by every client of the tunnel */
+    pub tunnel: BandwidthLimit,
+    /* applied to each TCP client or UDP flow on its own */
+    pub client: BandwidthLimit,
+}
+
+/// Token bucket of bytes, holding at most a second of traffic.
+pub struct ByteBucket {
+    bytes_per_sec: u64,
+    burst: u64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    /* negative while paying back bytes sent over the limit */
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ByteBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        let burst = bytes_per_sec.max(MIN_BURST);
+
+        ByteBucket {
+            bytes_per_sec,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.bytes_per_sec as f64).min(self.burst as f64);
+        state.updated = now;
+    }
+
+    /* takes the bytes and returns how long to wait before sending them */
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.tokens -= bytes as f64;
+
+        match state.tokens < 0.0 {
+            true => Duration::from_secs_f64(-state.tokens / self.bytes_per_sec as f64),
+            false => Duration::ZERO,
+        }
+    }
+
+    fn available(&self, bytes: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        bytes as f64 <= state.tokens
+    }
+}
+
+/// Set of buckets a direction of traffic has to pass, empty if unlimited.
+#[derive(Clone, Default)]
+pub struct Shaper {
+    buckets: Vec<Arc<ByteBucket>>,
+}
+
+impl Shaper {
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Waits until the bytes fit within every limit.
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self.buckets.iter()
+            .map(|bucket| bucket.take(bytes))
+            .max()
+            .unwrap_or(Duration::ZERO);
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes the bytes only if they fit now, for traffic that is dropped rather than delayed.
+    pub fn try_consume(&self, bytes: usize) -> bool {
+        if !self.buckets.iter().all(|bucket| bucket.available(bytes)) {
+            return false;
+        }
+
+        for bucket in &self.buckets {
+            bucket.take(bytes);
+        }
+        true
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ClientShapers {
+    pub upload: Shaper,
+    pub download: Shaper,
+}
+
+struct TunnelBuckets {
+    limit: BandwidthLimit,
+    upload: Option<Arc<ByteBucket>>,
+    download: Option<Arc<ByteBucket>>,
+}
+
+/// Keeps the per tunnel buckets so clients of the same tunnel share them.
+#[derive(Default)]
+pub struct TunnelBandwidth {
+    tunnels: Mutex<HashMap<MatchAddress, TunnelBuckets>>,
+}
+
+impl TunnelBandwidth {
+    pub fn client_shapers(&self, tunnel: MatchAddress, settings: &BandwidthSettings) -> ClientShapers {
+        let mut shapers = ClientShapers::default();
+
+        if settings.tunnel != BandwidthLimit::default() {
+            let mut tunnels = self.tunnels.lock().unwrap();
+
+            let buckets = tunnels.entry(tunnel).or_insert_with(|| TunnelBuckets::new(settings.tunnel));
+            /* settings changed, start over with new buckets */
+            if buckets.limit != settings.tunnel {
+                *buckets = TunnelBuckets::new(settings.tunnel);
+            }
+
+            shapers.upload.buckets.extend(buckets.upload.clone());
+            shapers.download.buckets.extend(buckets.download.clone());
+        }
+
+        shapers.upload.buckets.extend(settings.client.upload_bytes_per_sec.map(|rate| Arc::new(ByteBucket::new(rate))));
+        shapers.download.buckets.extend(settings.client.download_bytes_per_sec.map(|rate| Arc::new(ByteBucket::new(rate))));
+
+        shapers
+    }
+}
+
+impl TunnelBuckets {
+    fn new(limit: BandwidthLimit) -> Self {
+        TunnelBuckets {
+            limit,
+            upload: limit.upload_bytes_per_sec.map(|rate| Arc::new(ByteBucket::new(rate))),
+            download: limit.download_bytes_per_sec.map(|rate| Arc::new(ByteBucket::new(rate))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shaper_delays_over_limit() {
+        let bandwidth = TunnelBandwidth::default();
+        let settings = BandwidthSettings {
+            client: BandwidthLimit { upload_bytes_per_sec: Some(MIN_BURST), download_bytes_per_sec: None },
+            ..BandwidthSettings::default()
+        };
+
+        let shapers = bandwidth.client_shapers(MatchAddress { ip: Ipv6Addr::UNSPECIFIED, from_port: 1, to_port: 2 }, &settings);
+        assert!(shapers.download.is_unlimited());
+
+        let start = Instant::now();
+        /* burst passes right away, the rest has to wait for a quarter second */
+        shapers.upload.consume(MIN_BURST as usize).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        shapers.upload.consume(MIN_BURST as usize / 4).await;
+        assert!(Duration::from_millis(200) <= start.elapsed());
+    }
+
+    #[test]
+    fn test_tunnel_buckets_shared() {
+        let bandwidth = TunnelBandwidth::default();
+        let tunnel = MatchAddress { ip: Ipv6Addr::UNSPECIFIED, from_port: 1, to_port: 2 };
+        let settings = BandwidthSettings {
+            tunnel: BandwidthLimit { upload_bytes_per_sec: None, download_bytes_per_sec: Some(MIN_BURST) },
+            ..BandwidthSettings::default()
+        };
+
+        let first = bandwidth.client_shapers(tunnel, &settings);
+        let second = bandwidth.client_shapers(tunnel, &settings);
+
+        assert!(first.download.try_consume(MIN_BURST as usize - 100));
+        assert!(!second.download.try_consume(1000));
+        assert!(second.download.try_consume(50));
+        assert!(first.upload.try_consume(usize::MAX));
+    }
+}
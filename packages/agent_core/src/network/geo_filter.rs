@@ -0,0 +1,208 @@
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+#[cfg(feature = "geoip")]
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+/// Per tunnel allow and deny lists, countries are ISO 3166 codes such as `DE`.
+/// Deny lists are checked first, non empty allow lists must then match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct GeoRules {
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+    pub allow_asns: Vec<u32>,
+    pub deny_asns: Vec<u32>,
+    /* for addresses missing from the database, or when none is loaded */
+    pub allow_unknown: bool,
+}
+
+impl Default for GeoRules {
+    fn default() -> Self {
+        GeoRules {
+            allow_countries: Vec::new(),
+            deny_countries: Vec::new(),
+            allow_asns: Vec::new(),
+            deny_asns: Vec::new(),
+            allow_unknown: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+pub trait GeoLookup: Send + Sync + 'static {
+    fn lookup(&self, ip: IpAddr) -> GeoInfo;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeoDenied {
+    Country(String),
+    Asn(u32),
+    Unknown,
+}
+
+impl Display for GeoDenied {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for GeoDenied {
+}
+
+impl GeoRules {
+    pub fn is_empty(&self) -> bool {
+        self.allow_countries.is_empty() && self.deny_countries.is_empty() && self.allow_asns.is_empty() && self.deny_asns.is_empty()
+    }
+
+    pub fn check(&self, info: &GeoInfo) -> Result<(), GeoDenied> {
+        let country = info.country.as_deref();
+
+        if let Some(country) = country {
+            if self.deny_countries.iter().any(|c| c.eq_ignore_ascii_case(country)) {
+                return Err(GeoDenied::Country(country.to_string()));
+            }
+        }
+
+        if let Some(asn) = info.asn {
+            if self.deny_asns.contains(&asn) {
+                return Err(GeoDenied::Asn(asn));
+            }
+        }
+
+        if !self.allow_countries.is_empty() {
+            match country {
+                Some(country) if self.allow_countries.iter().any(|c| c.eq_ignore_ascii_case(country)) => {}
+                Some(country) => return Err(GeoDenied::Country(country.to_string())),
+                None if !self.allow_unknown => return Err(GeoDenied::Unknown),
+                None => {}
+            }
+        }
+
+        if !self.allow_asns.is_empty() {
+            match info.asn {
+                Some(asn) if self.allow_asns.contains(&asn) => {}
+                Some(asn) => return Err(GeoDenied::Asn(asn)),
+                None if !self.allow_unknown => return Err(GeoDenied::Unknown),
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Applies tunnel rules to new TCP clients and UDP flows, shared by both.
+#[derive(Default)]
+pub struct GeoFilter {
+    lookup: Option<Arc<dyn GeoLookup>>,
+    denied: AtomicU64,
+}
+
+impl GeoFilter {
+    pub fn new(lookup: Option<Arc<dyn GeoLookup>>) -> Self {
+        GeoFilter {
+            lookup,
+            denied: AtomicU64::new(0),
+        }
+    }
+
+    pub fn check(&self, ip: IpAddr, rules: &GeoRules) -> Result<(), GeoDenied> {
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        let info = match &self.lookup {
+            Some(lookup) => lookup.lookup(ip),
+            None => GeoInfo::default(),
+        };
+
+        let res = rules.check(&info);
+        if res.is_err() {
+            self.denied.fetch_add(1, Ordering::Relaxed);
+        }
+        res
+    }
+
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+}
+
+/// Reads MaxMind country and ASN databases (such as GeoLite2) from disk.
+#[cfg(feature = "geoip")]
+pub struct MmdbGeoLookup {
+    country: Option<maxminddb::Reader<Vec<u8>>>,
+    asn: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+#[cfg(feature = "geoip")]
+impl MmdbGeoLookup {
+    pub fn open<P: AsRef<Path>>(country_db: Option<P>, asn_db: Option<P>) -> Result<Self, maxminddb::MaxMindDBError> {
+        Ok(MmdbGeoLookup {
+            country: country_db.map(maxminddb::Reader::open_readfile).transpose()?,
+            asn: asn_db.map(maxminddb::Reader::open_readfile).transpose()?,
+        })
+    }
+}
+
+#[cfg(feature = "geoip")]
+impl GeoLookup for MmdbGeoLookup {
+    fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let country = self.country.as_ref()
+            .and_then(|reader| reader.lookup::<maxminddb::geoip2::Country>(ip).ok())
+            .and_then(|record| record.country)
+            .and_then(|country| country.iso_code)
+            .map(|code| code.to_string());
+
+        let asn = self.asn.as_ref()
+            .and_then(|reader| reader.lookup::<maxminddb::geoip2::Asn>(ip).ok())
+            .and_then(|record| record.autonomous_system_number);
+
+        GeoInfo { country, asn }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestLookup;
+
+    impl GeoLookup for TestLookup {
+        fn lookup(&self, ip: IpAddr) -> GeoInfo {
+            match ip.to_string().as_str() {
+                "1.1.1.1" => GeoInfo { country: Some("DE".to_string()), asn: Some(3320) },
+                "2.2.2.2" => GeoInfo { country: Some("US".to_string()), asn: Some(15169) },
+                _ => GeoInfo::default(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_geo_rules() {
+        let filter = GeoFilter::new(Some(Arc::new(TestLookup)));
+
+        let allow_de = GeoRules { allow_countries: vec!["de".to_string()], ..GeoRules::default() };
+        assert_eq!(filter.check("1.1.1.1".parse().unwrap(), &allow_de), Ok(()));
+        assert_eq!(filter.check("2.2.2.2".parse().unwrap(), &allow_de), Err(GeoDenied::Country("US".to_string())));
+        assert_eq!(filter.check("3.3.3.3".parse().unwrap(), &allow_de), Ok(()));
+
+        let strict = GeoRules { deny_asns: vec![15169], allow_unknown: false, allow_asns: vec![3320], ..GeoRules::default() };
+        assert_eq!(filter.check("2.2.2.2".parse().unwrap(), &strict), Err(GeoDenied::Asn(15169)));
+        assert_eq!(filter.check("3.3.3.3".parse().unwrap(), &strict), Err(GeoDenied::Unknown));
+        assert_eq!(filter.check("1.1.1.1".parse().unwrap(), &strict), Ok(()));
+
+        /* no rules skips the lookup entirely */
+        assert_eq!(filter.check("3.3.3.3".parse().unwrap(), &GeoRules { allow_unknown: false, ..GeoRules::default() }), Ok(()));
+        assert_eq!(filter.denied(), 3);
+    }
+}
@@ -0,0 +1,680 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::sync::oneshot;
+
+use crate::network::bandwidth::ClientShapers;
+use crate::network::tcp_pipe::{pipe_bidirectional_shaped, PipeSummary};
+
+const MAX_HEAD_LEN: usize = 16 * 1024;
+const MAX_CHUNK_LINE_LEN: usize = 1024;
+const MAX_STATUS_LINE_LEN: usize = 256;
+
+pub const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// HTTP aware mode for TCP tunnels, off by default. Requests are routed by
+/// the `Host` of the connection's first request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpSettings {
+    pub enabled: bool,
+    /* host without port to backend, other hosts use the tunnel's local target */
+    pub routes: HashMap<String, SocketAddr>,
+    /* backend for the tunnel's custom domain, added to routes by its mapping override */
+    pub custom_domain_target: Option<SocketAddr>,
+    /* replaces any X-Forwarded-For sent by the client with the peer's ip */
+    pub forwarded_for: bool,
+    pub head_timeout_secs: u64,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            enabled: false,
+            routes: HashMap::new(),
+            custom_domain_target: None,
+            forwarded_for: true,
+            head_timeout_secs: 10,
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn head_timeout(&self) -> Duration {
+        Duration::from_secs(self.head_timeout_secs.max(1))
+    }
+
+    pub fn route(&self, head: &RequestHead) -> Option<SocketAddr> {
+        let host = head.host()?;
+        self.routes.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&host))
+            .map(|(_, addr)| *addr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Body {
+    None,
+    Length(u64),
+    Chunked,
+}
+
+impl RequestHead {
+    pub fn parse(data: &[u8]) -> std::io::Result<Self> {
+        let text = std::str::from_utf8(data).map_err(|_| invalid_data("request head is not utf8"))?;
+        let mut lines = text.split('\n').map(|line| line.trim_end_matches('\r'));
+
+        let mut parts = lines.next().unwrap_or_default().split(' ');
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid_data("invalid request line"));
+        };
+
+        if !version.starts_with("HTTP/1.") {
+            return Err(invalid_data("unsupported http version"));
+        }
+
+        let mut headers = Vec::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid_data("invalid header"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        Ok(RequestHead {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Lowercase host without the port.
+    pub fn host(&self) -> Option<String> {
+        let host = self.header("host")?;
+
+        let host = match host.strip_prefix('[') {
+            Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+            None => host.split(':').next().unwrap_or_default(),
+        };
+
+        Some(host.trim_end_matches('.').to_ascii_lowercase())
+    }
+
+    fn headers_named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers.iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Everything after the head belongs to another protocol if the backend accepts.
+    fn is_upgrade(&self) -> bool {
+        self.is_connect() || self.header("upgrade").is_some()
+    }
+
+    fn is_connect(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+    }
+
+    /* framing the agent and backend could disagree on is rejected, see RFC 9112 section 6.3 */
+    fn body(&self) -> std::io::Result<Body> {
+        let mut lengths = self.headers_named("content-length");
+        let length = lengths.next();
+        if lengths.next().is_some() {
+            return Err(invalid_data("duplicate content length"));
+        }
+
+        let encodings = self.headers_named("transfer-encoding")
+            .flat_map(|value| value.split(','))
+            .map(|encoding| encoding.trim().to_ascii_lowercase())
+            .collect::<Vec<_>>();
+
+        if !encodings.is_empty() {
+            if length.is_some() {
+                return Err(invalid_data("content length with transfer encoding"));
+            }
+            if encodings.last().map(String::as_str) != Some("chunked") {
+                return Err(invalid_data("transfer encoding is not chunked"));
+            }
+            return Ok(Body::Chunked);
+        }
+
+        match length {
+            Some(len) if !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()) => {
+                len.parse().map(Body::Length).map_err(|_| invalid_data("invalid content length"))
+            }
+            Some(_) => Err(invalid_data("invalid content length")),
+            None => Ok(Body::None),
+        }
+    }
+
+    pub(crate) fn encode(&self, forwarded_for: Option<IpAddr>) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version);
+
+        for (name, value) in &self.headers {
+            if forwarded_for.is_some() && name.eq_ignore_ascii_case("x-forwarded-for") {
+                continue;
+            }
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        if let Some(ip) = forwarded_for {
+            out.push_str(&format!("X-Forwarded-For: {}\r\n", ip));
+        }
+
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+}
+
+/// First request of a connection, read before connecting locally to pick the backend.
+#[derive(Debug)]
+pub struct FirstRequest {
+    pub head: RequestHead,
+    /* read past the head, the start of the body or of a pipelined request */
+    leftover: Vec<u8>,
+}
+
+pub async fn read_first_request<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<FirstRequest> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 2048];
+
+    loop {
+        let received = stream.read(&mut chunk).await?;
+        if received == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        let searched = buffer.len().saturating_sub(3);
+        buffer.extend_from_slice(&chunk[..received]);
+
+        if let Some(pos) = buffer[searched..].windows(4).position(|window| window == b"\r\n\r\n") {
+            let head_len = searched + pos + 4;
+            let head = RequestHead::parse(&buffer[..head_len])?;
+            head.body()?;
+
+            return Ok(FirstRequest {
+                head,
+                leftover: buffer.split_off(head_len),
+            });
+        }
+
+        if MAX_HEAD_LEN < buffer.len() {
+            return Err(invalid_data("request head too large"));
+        }
+    }
+}
+
+/// Pipes the connection while rewriting each request head on the way to
+/// the local server, responses are passed through as is. Requests on a kept
+/// alive connection for a host routed elsewhere close the connection, as do
+/// requests with ambiguous framing once they get a 400 after the responses
+/// already underway. Upgrades are only passed through as is when they are
+/// the first request and the backend switches protocols.
+pub async fn pipe_requests<T, L>(
+    tunnel: T,
+    first: FirstRequest,
+    local: L,
+    peer_ip: IpAddr,
+    settings: &HttpSettings,
+    idle_timeout: Duration,
+    shapers: &ClientShapers,
+) -> PipeSummary where T: AsyncRead + AsyncWrite, L: AsyncRead + AsyncWrite + Unpin {
+    let (tunnel_read, tunnel_write) = tokio::io::split(tunnel);
+    let (rewritten, rewriter_end) = tokio::io::duplex(64 * 1024);
+
+    let forwarded_for = settings.forwarded_for.then_some(peer_ip);
+    let (status_tx, status_rx) = oneshot::channel();
+    let bad_request = Arc::new(AtomicBool::new(false));
+
+    let reader = BufReader::new(std::io::Cursor::new(first.leftover).chain(tunnel_read));
+    let rewriter = rewrite_requests(reader, rewriter_end, first.head, settings, forwarded_for, status_rx);
+
+    let local = ResponseTap {
+        inner: local,
+        status_line: Vec::new(),
+        status_tx: Some(status_tx),
+        bad_request: bad_request.clone(),
+        pending: &[],
+    };
+
+    /* the rewriter's end of the duplex closing is seen as the client's EOF */
+    let pipe = pipe_bidirectional_shaped(Joined(rewritten, tunnel_write), local, idle_timeout, shapers);
+
+    tokio::pin!(rewriter);
+    tokio::pin!(pipe);
+
+    let mut rewriting = true;
+    loop {
+        tokio::select! {
+            summary = &mut pipe => return summary,
+            res = &mut rewriter, if rewriting => {
+                if let Err(error) = res {
+                    tracing::info!(?error, "stopped forwarding http requests");
+
+                    if error.kind() == ErrorKind::InvalidData {
+                        bad_request.store(true, Ordering::Relaxed);
+                    }
+                }
+                rewriting = false;
+            }
+        }
+    }
+}
+
+async fn rewrite_requests<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    mut reader: R,
+    mut out: W,
+    mut head: RequestHead,
+    settings: &HttpSettings,
+    forwarded_for: Option<IpAddr>,
+    first_status: oneshot::Receiver<Option<u16>>,
+) -> std::io::Result<()> {
+    let route = settings.route(&head);
+    let mut first_status = Some(first_status);
+
+    loop {
+        let body = head.body()?;
+        out.write_all(&head.encode(forwarded_for)).await?;
+
+        match body {
+            Body::None => {}
+            Body::Length(len) => copy_exact(&mut reader, &mut out, len).await?,
+            Body::Chunked => copy_chunked(&mut reader, &mut out).await?,
+        }
+
+        /* the first response status only answers the first request */
+        let status = first_status.take();
+
+        if head.is_upgrade() {
+            /* nothing else is forwarded until the backend agreed to switch */
+            let status = match status {
+                Some(status) => status.await.ok().flatten(),
+                None => None,
+            };
+
+            let switched = match status {
+                Some(status) if head.is_connect() => (200..300).contains(&status),
+                Some(status) => status == 101,
+                None => false,
+            };
+
+            if !switched {
+                tracing::info!(?status, "http upgrade not accepted, closing after request");
+                return Ok(());
+            }
+
+            tokio::io::copy_buf(&mut reader, &mut out).await?;
+            return Ok(());
+        }
+
+        head = match read_head(&mut reader).await? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+
+        if settings.route(&head) != route {
+            tracing::info!(host = ?head.host(), "request for a host routed to another backend, closing");
+            return Ok(());
+        }
+    }
+}
+
+/* None on EOF before a new request */
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<Option<RequestHead>> {
+    let mut head = Vec::new();
+
+    loop {
+        let start = head.len();
+        read_line(reader, &mut head, MAX_HEAD_LEN).await?;
+
+        match &head[start..] {
+            [] if start == 0 => return Ok(None),
+            [] => return Err(ErrorKind::UnexpectedEof.into()),
+            /* blank lines between requests are allowed */
+            b"\r\n" | b"\n" if start == 0 => head.clear(),
+            b"\r\n" | b"\n" => return RequestHead::parse(&head).map(Some),
+            _ => {}
+        }
+    }
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R, out: &mut Vec<u8>, limit: usize) -> std::io::Result<()> {
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(());
+        }
+
+        let (len, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(pos) => (pos + 1, true),
+            None => (available.len(), false),
+        };
+
+        out.extend_from_slice(&available[..len]);
+        reader.consume(len);
+
+        if limit < out.len() {
+            return Err(invalid_data("line too long"));
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+async fn copy_exact<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, out: &mut W, len: u64) -> std::io::Result<()> {
+    let copied = tokio::io::copy(&mut reader.take(len), out).await?;
+    if copied != len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+async fn copy_chunked<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, out: &mut W) -> std::io::Result<()> {
+    loop {
+        let mut line = Vec::new();
+        read_line(reader, &mut line, MAX_CHUNK_LINE_LEN).await?;
+        out.write_all(&line).await?;
+
+        let size = std::str::from_utf8(&line).ok()
+            .and_then(|line| u64::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .ok_or_else(|| invalid_data("invalid chunk size"))?;
+
+        if size == 0 {
+            break;
+        }
+
+        /* chunk data and its trailing CRLF */
+        copy_exact(reader, out, size + 2).await?;
+    }
+
+    /* trailers end with a blank line */
+    loop {
+        let mut line = Vec::new();
+        read_line(reader, &mut line, MAX_CHUNK_LINE_LEN).await?;
+        out.write_all(&line).await?;
+
+        match line.as_slice() {
+            [] => return Err(ErrorKind::UnexpectedEof.into()),
+            b"\r\n" | b"\n" => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/* local stream that reports the status of the first response, and that
+ * ends with a 400 when the rewriter stopped at a bad request */
+struct ResponseTap<L> {
+    inner: L,
+    status_line: Vec<u8>,
+    status_tx: Option<oneshot::Sender<Option<u16>>>,
+    bad_request: Arc<AtomicBool>,
+    pending: &'static [u8],
+}
+
+impl<L> ResponseTap<L> {
+    fn observe(&mut self, data: &[u8]) {
+        let Some(status_tx) = self.status_tx.take() else {
+            return;
+        };
+
+        let end = data.iter().position(|b| *b == b'\n');
+        self.status_line.extend_from_slice(&data[..end.unwrap_or(data.len())]);
+
+        if end.is_none() && !data.is_empty() && self.status_line.len() < MAX_STATUS_LINE_LEN {
+            self.status_tx = Some(status_tx);
+            return;
+        }
+
+        /* "HTTP/1.1 101 Switching Protocols" */
+        let status = std::str::from_utf8(&self.status_line).ok()
+            .filter(|line| line.starts_with("HTTP/1."))
+            .and_then(|line| line.split(' ').nth(1)?.parse().ok());
+
+        let _ = status_tx.send(status);
+    }
+}
+
+impl<L: AsyncRead + Unpin> AsyncRead for ResponseTap<L> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+
+        if !this.pending.is_empty() {
+            let len = this.pending.len().min(buf.remaining());
+            buf.put_slice(&this.pending[..len]);
+            this.pending = &this.pending[len..];
+            return Poll::Ready(Ok(()));
+        }
+
+        let start = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let read = &buf.filled()[start..];
+                let eof = read.is_empty();
+                this.observe(read);
+
+                /* the 400 follows the responses to the requests before the bad one */
+                if eof && this.bad_request.swap(false, Ordering::Relaxed) {
+                    this.pending = BAD_REQUEST;
+                    return Pin::new(this).poll_read(cx, buf);
+                }
+
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<L: AsyncWrite + Unpin> AsyncWrite for ResponseTap<L> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/* read and write halves used as a single stream */
+struct Joined<R, W>(R, W);
+
+impl<R: AsyncRead + Unpin, W: Unpin> AsyncRead for Joined<R, W> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<R: Unpin, W: AsyncWrite + Unpin> AsyncWrite for Joined<R, W> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.1).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.1).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.1).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_first_request_routing() {
+        let data = b"GET / HTTP/1.1\r\nHost: Panel.Example.com:8080\r\nX-Forwarded-For: 6.6.6.6\r\n\r\nGET /next";
+        let mut stream = &data[..];
+
+        let first = read_first_request(&mut stream).await.unwrap();
+        assert_eq!(first.head.host().as_deref(), Some("panel.example.com"));
+        assert_eq!(first.leftover, b"GET /next");
+
+        let settings = HttpSettings {
+            routes: HashMap::from([("panel.example.com".to_string(), "127.0.0.1:3000".parse().unwrap())]),
+            ..HttpSettings::default()
+        };
+        assert_eq!(settings.route(&first.head), Some("127.0.0.1:3000".parse().unwrap()));
+
+        let encoded = String::from_utf8(first.head.encode(Some("1.2.3.4".parse().unwrap()))).unwrap();
+        assert_eq!(encoded, "GET / HTTP/1.1\r\nHost: Panel.Example.com:8080\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n");
+
+        let ipv6 = RequestHead::parse(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n").unwrap();
+        assert_eq!(ipv6.host().as_deref(), Some("::1"));
+        assert!(RequestHead::parse(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ambiguous_framing_rejected() {
+        for data in [
+            &b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n",
+        ] {
+            let mut stream = data;
+            assert_eq!(read_first_request(&mut stream).await.unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+
+        /* a pipelined request with two lengths is not forwarded, the client gets a 400 after the first response */
+        let (mut client, tunnel) = duplex(64 * 1024);
+        let (local, mut server) = duplex(64 * 1024);
+
+        client.write_all(concat!(
+            "GET /a HTTP/1.1\r\nHost: a\r\n\r\n",
+            "POST /b HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "0\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: a\r\n\r\n",
+        ).as_bytes()).await.unwrap();
+
+        let pipe = tokio::spawn(async move {
+            let mut tunnel = tunnel;
+            let first = read_first_request(&mut tunnel).await.unwrap();
+            pipe_requests(tunnel, first, local, "1.2.3.4".parse().unwrap(), &HttpSettings::default(), Duration::from_secs(5), &ClientShapers::default()).await
+        });
+
+        let mut forwarded = String::new();
+        server.read_to_string(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded, "GET /a HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n");
+
+        server.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        drop(server);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response.as_bytes(), [&b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"[..], BAD_REQUEST].concat());
+        pipe.await.unwrap();
+    }
+
+    async fn upgrade(response: &'static [u8]) -> String {
+        let (mut client, tunnel) = duplex(64 * 1024);
+        let (local, mut server) = duplex(64 * 1024);
+
+        client.write_all(concat!(
+            "GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n",
+            "GET /admin HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 127.0.0.1\r\n\r\n",
+        ).as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let pipe = tokio::spawn(async move {
+            let mut tunnel = tunnel;
+            let first = read_first_request(&mut tunnel).await.unwrap();
+            pipe_requests(tunnel, first, local, "1.2.3.4".parse().unwrap(), &HttpSettings::default(), Duration::from_secs(5), &ClientShapers::default()).await
+        });
+
+        let mut head = vec![0u8; 96];
+        server.read_exact(&mut head).await.unwrap();
+        server.write_all(response).await.unwrap();
+
+        let mut rest = String::new();
+        server.read_to_string(&mut rest).await.unwrap();
+        drop(server);
+        pipe.await.unwrap();
+
+        String::from_utf8(head).unwrap() + &rest
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_waits_for_switching_protocols() {
+        let head = "GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n";
+        assert_eq!(head.len(), 96);
+
+        /* refused upgrade, what follows must not reach the backend unrewritten */
+        let forwarded = upgrade(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+        assert_eq!(forwarded, head);
+
+        /* accepted upgrade, the rest of the stream belongs to the new protocol */
+        let forwarded = upgrade(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n").await;
+        assert_eq!(forwarded, format!("{}GET /admin HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 127.0.0.1\r\n\r\n", head));
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_requests_rewritten() {
+        let (mut client, tunnel) = duplex(64 * 1024);
+        let (local, mut server) = duplex(64 * 1024);
+
+        client.write_all(concat!(
+            "POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello",
+            "POST /b HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+            "GET /c HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 6.6.6.6\r\n\r\n",
+        ).as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let pipe = tokio::spawn(async move {
+            let mut tunnel = tunnel;
+            let first = read_first_request(&mut tunnel).await.unwrap();
+            pipe_requests(tunnel, first, local, "1.2.3.4".parse().unwrap(), &HttpSettings::default(), Duration::from_secs(5), &ClientShapers::default()).await
+        });
+
+        let mut forwarded = String::new();
+        server.read_to_string(&mut forwarded).await.unwrap();
+        server.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        drop(server);
+
+        assert_eq!(forwarded, concat!(
+            "POST /a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nX-Forwarded-For: 1.2.3.4\r\n\r\nhello",
+            "POST /b HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+            "GET /c HTTP/1.1\r\nHost: a\r\nX-Forwarded-For: 1.2.3.4\r\n\r\n",
+        ));
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        let summary = pipe.await.unwrap();
+        assert_eq!(summary.tunnel_to_local, forwarded.len() as u64);
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::events::{AgentEvent, AgentEvents};
+use crate::network::address_lookup::{AddressLookup, MatchAddress};
+use crate::network::backend_pool::{Backend, HealthCheckSettings};
+use crate::network::local_target::LocalTarget;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LocalTargetReport {
+    pub tunnel_ip: Ipv6Addr,
+    pub tunnel_port: u16,
+    pub local_target: String,
+    pub healthy: bool,
+}
+
+struct TargetState {
+    local_target: String,
+    healthy: bool,
+    next_check: Instant,
+}
+
+/// Last known state of each mapped local target, updated by periodic checks
+/// and by the outcome of connecting TCP clients.
+pub struct LocalHealth {
+    targets: RwLock<HashMap<MatchAddress, TargetState>>,
+    events: AgentEvents,
+}
+
+impl LocalHealth {
+    pub fn new(events: AgentEvents) -> Self {
+        LocalHealth {
+            targets: RwLock::new(HashMap::new()),
+            events,
+        }
+    }
+
+    /// Only true once a check has failed, unchecked targets are assumed up.
+    pub fn is_down(&self, tunnel: MatchAddress) -> bool {
+        let targets = self.targets.read().unwrap();
+        matches!(targets.get(&tunnel), Some(state) if !state.healthy)
+    }
+
+    pub fn set(&self, tunnel: MatchAddress, local_target: String, healthy: bool) {
+        let changed = {
+            let mut targets = self.targets.write().unwrap();
+            let state = targets.entry(tunnel).or_insert_with(|| TargetState {
+                local_target: local_target.clone(),
+                /* first result is always reported */
+                healthy: !healthy,
+                next_check: Instant::now(),
+            });
+
+            let changed = state.healthy != healthy || state.local_target != local_target;
+            state.healthy = healthy;
+            state.local_target = local_target.clone();
+            changed
+        };
+
+        if changed {
+            match healthy {
+                true => tracing::info!(?tunnel, %local_target, "local target is up"),
+                false => tracing::warn!(?tunnel, %local_target, "local target is down, is your server running?"),
+            }
+
+            self.events.emit(AgentEvent::LocalTargetHealthChanged { tunnel, local_target, healthy });
+        }
+    }
+
+    /// Updates a checked target from the outcome of a client connecting to it.
+    pub fn record(&self, tunnel: MatchAddress, healthy: bool) {
+        let local_target = match self.targets.read().unwrap().get(&tunnel) {
+            Some(state) if state.healthy != healthy => state.local_target.clone(),
+            _ => return,
+        };
+
+        self.set(tunnel, local_target, healthy);
+    }
+
+    pub fn report(&self) -> Vec<LocalTargetReport> {
+        let targets = self.targets.read().unwrap();
+
+        let mut report = targets.iter()
+            .map(|(tunnel, state)| LocalTargetReport {
+                tunnel_ip: tunnel.ip,
+                tunnel_port: tunnel.from_port,
+                local_target: state.local_target.clone(),
+                healthy: state.healthy,
+            })
+            .collect::<Vec<_>>();
+
+        report.sort_by_key(|target| (target.tunnel_ip, target.tunnel_port));
+        report
+    }
+
+    fn check_due(&self, tunnel: MatchAddress, interval: Duration) -> bool {
+        let mut targets = self.targets.write().unwrap();
+        let now = Instant::now();
+
+        match targets.get_mut(&tunnel) {
+            Some(state) if now < state.next_check => false,
+            Some(state) => {
+                state.next_check = now + interval;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn schedule(&self, tunnel: MatchAddress, interval: Duration) {
+        if let Some(state) = self.targets.write().unwrap().get_mut(&tunnel) {
+            state.next_check = Instant::now() + interval;
+        }
+    }
+
+    /// Checks the targets of every mapped tunnel, each on its own interval.
+    pub async fn run<L: AddressLookup>(&self, lookup: Arc<L>, keep_running: Arc<AtomicBool>) {
+        while keep_running.load(Ordering::SeqCst) {
+            for (tunnel, proto) in lookup.mapped_tunnels() {
+                let settings = lookup.tunnel_settings(tunnel, proto).effective_health_check();
+                if !settings.applies_to(proto) || !self.check_due(tunnel, settings.interval()) {
+                    continue;
+                }
+
+                let target = match lookup.local_target(tunnel, proto) {
+                    Some(target) => target,
+                    None => continue,
+                };
+
+                /* pools probe their own backends, started with the first check */
+                if let LocalTarget::Pool { pool, .. } = &target {
+                    pool.start_health_checks(settings.clone(), keep_running.clone());
+                }
+
+                if let Some(healthy) = check_target(&settings, &target).await {
+                    self.set(tunnel, target.to_string(), healthy);
+                    self.schedule(tunnel, settings.interval());
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+/* None for targets that can't be probed */
+async fn check_target(settings: &HealthCheckSettings, target: &LocalTarget) -> Option<bool> {
+    match target {
+        LocalTarget::Addr(addr) => Some(settings.check(*addr).await),
+        #[cfg(unix)]
+        LocalTarget::Unix(path) => Some(matches!(tokio::time::timeout(settings.timeout(), UnixStream::connect(path)).await, Ok(Ok(_)))),
+        LocalTarget::Handler(_) => None,
+        LocalTarget::Pool { pool, .. } => Some(pool.backends().iter().any(Backend::is_healthy)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use playit_agent_proto::PortProto;
+
+    use crate::network::backend_pool::{BackendPool, BalanceStrategy};
+    use crate::network::tunnel_settings::TunnelSettings;
+
+    use super::*;
+
+    struct TestLookup {
+        up: SocketAddr,
+        down: SocketAddr,
+    }
+
+    fn tunnel(port: u16) -> MatchAddress {
+        MatchAddress { ip: Ipv6Addr::UNSPECIFIED, from_port: port, to_port: port + 1 }
+    }
+
+    impl AddressLookup for TestLookup {
+        fn find_tunnel_port_range(&self, _match_ip: Ipv6Addr, port: u16, _proto: PortProto) -> Option<(u16, u16)> {
+            Some((port, port + 1))
+        }
+
+        fn local_address(&self, match_addr: MatchAddress, _proto: PortProto) -> Option<SocketAddr> {
+            match match_addr.from_port {
+                1 => Some(self.up),
+                _ => Some(self.down),
+            }
+        }
+
+        fn tunnel_settings(&self, _match_addr: MatchAddress, _proto: PortProto) -> TunnelSettings {
+            let mut settings = TunnelSettings::default();
+            settings.health_check.enabled = true;
+            settings.health_check.timeout_ms = 500;
+            settings
+        }
+
+        fn mapped_tunnels(&self) -> Vec<(MatchAddress, PortProto)> {
+            vec![(tunnel(1), PortProto::Tcp), (tunnel(2), PortProto::Both), (tunnel(3), PortProto::Udp)]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_health_checks_mapped_targets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let lookup = Arc::new(TestLookup { up: listener.local_addr().unwrap(), down });
+
+        let events = AgentEvents::default();
+        let mut rx = events.subscribe();
+
+        let health = Arc::new(LocalHealth::new(events));
+        let keep_running = Arc::new(AtomicBool::new(true));
+
+        let task = {
+            let health = health.clone();
+            let keep_running = keep_running.clone();
+            tokio::spawn(async move { health.run(lookup, keep_running).await })
+        };
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        keep_running.store(false, Ordering::SeqCst);
+        task.await.unwrap();
+
+        assert!(!health.is_down(tunnel(1)));
+        assert!(health.is_down(tunnel(2)));
+        /* tcp probe is skipped for udp only tunnels */
+        assert!(!health.is_down(tunnel(3)));
+        assert_eq!(health.report().len(), 2);
+
+        assert_eq!(rx.try_recv().unwrap(), AgentEvent::LocalTargetHealthChanged {
+            tunnel: tunnel(1),
+            local_target: listener.local_addr().unwrap().to_string(),
+            healthy: true,
+        });
+
+        assert!(matches!(rx.try_recv().unwrap(), AgentEvent::LocalTargetHealthChanged { healthy: false, .. }));
+
+        /* only changes are reported */
+        health.record(tunnel(2), false);
+        assert!(rx.try_recv().is_err());
+
+        health.record(tunnel(2), true);
+        assert!(!health.is_down(tunnel(2)));
+        assert!(rx.try_recv().is_ok());
+    }
+
+    struct PoolLookup(Arc<BackendPool>);
+
+    impl AddressLookup for PoolLookup {
+        fn find_tunnel_port_range(&self, _match_ip: Ipv6Addr, port: u16, _proto: PortProto) -> Option<(u16, u16)> {
+            Some((port, port + 1))
+        }
+
+        fn local_address(&self, _match_addr: MatchAddress, _proto: PortProto) -> Option<SocketAddr> {
+            None
+        }
+
+        fn local_target(&self, _match_addr: MatchAddress, _proto: PortProto) -> Option<LocalTarget> {
+            Some(LocalTarget::from(self.0.clone()))
+        }
+
+        fn tunnel_settings(&self, _match_addr: MatchAddress, _proto: PortProto) -> TunnelSettings {
+            let mut settings = TunnelSettings::default();
+            settings.health_check.enabled = true;
+            settings.health_check.timeout_ms = 500;
+            settings
+        }
+
+        fn mapped_tunnels(&self) -> Vec<(MatchAddress, PortProto)> {
+            vec![(tunnel(1), PortProto::Tcp)]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_health_starts_pool_checks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let pool = BackendPool::new(vec![listener.local_addr().unwrap(), down], BalanceStrategy::RoundRobin);
+        let health = Arc::new(LocalHealth::new(AgentEvents::default()));
+        let keep_running = Arc::new(AtomicBool::new(true));
+
+        let task = {
+            let health = health.clone();
+            let lookup = Arc::new(PoolLookup(pool.clone()));
+            let keep_running = keep_running.clone();
+            tokio::spawn(async move { health.run(lookup, keep_running).await })
+        };
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        keep_running.store(false, Ordering::SeqCst);
+        task.await.unwrap();
+
+        assert!(pool.backends()[0].is_healthy());
+        assert!(!pool.backends()[1].is_healthy());
+        assert!(!health.is_down(tunnel(1)));
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::network::backend_pool::BackendPool;
+use crate::network::tunnel_handler::TunnelHandler;
+
+/// Where clients of a tunnel are sent.
+#[derive(Clone)]
+pub enum LocalTarget {
+    Addr(SocketAddr),
+    /* only used for TCP tunnels */
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Handler(Arc<dyn TunnelHandler>),
+    /* a backend is picked from the pool for each client */
+    Pool {
+        pool: Arc<BackendPool>,
+        port_offset: u16,
+    },
+}
+
+impl LocalTarget {
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            LocalTarget::Addr(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    /// Shifts the local port for tunnels covering a range of ports, None
+    /// if the shifted port is out of range.
+    pub fn with_port_offset(self, port_offset: u16) -> Option<Self> {
+        match self {
+            LocalTarget::Addr(mut addr) => {
+                addr.set_port(addr.port().checked_add(port_offset)?);
+                Some(LocalTarget::Addr(addr))
+            }
+            /* checked per backend when connecting */
+            LocalTarget::Pool { pool, .. } => Some(LocalTarget::Pool { pool, port_offset }),
+            other => Some(other),
+        }
+    }
+}
+
+impl From<Arc<BackendPool>> for LocalTarget {
+    fn from(pool: Arc<BackendPool>) -> Self {
+        LocalTarget::Pool { pool, port_offset: 0 }
+    }
+}
+
+impl From<SocketAddr> for LocalTarget {
+    fn from(addr: SocketAddr) -> Self {
+        LocalTarget::Addr(addr)
+    }
+}
+
+impl Display for LocalTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocalTarget::Addr(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            LocalTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+            LocalTarget::Handler(_) => write!(f, "handler"),
+            LocalTarget::Pool { pool, .. } => write!(f, "pool({} backends)", pool.len()),
+        }
+    }
+}
+
+/// Parses `unix:/path/to.sock`, `<ip>:<port>` or a port on localhost.
+impl FromStr for LocalTarget {
+    type Err = InvalidLocalTarget;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return match path.is_empty() {
+                true => Err(InvalidLocalTarget),
+                false => Ok(LocalTarget::Unix(PathBuf::from(path))),
+            };
+
+            #[cfg(not(unix))]
+            return {
+                let _ = path;
+                Err(InvalidLocalTarget)
+            };
+        }
+
+        if let Ok(addr) = SocketAddr::from_str(s) {
+            return Ok(LocalTarget::Addr(addr));
+        }
+
+        match u16::from_str(s) {
+            Ok(port) => Ok(LocalTarget::Addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))),
+            Err(_) => Err(InvalidLocalTarget),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidLocalTarget;
+
+impl Display for InvalidLocalTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected unix:<path>, <ip>:<port> or <port>")
+    }
+}
+
+impl std::error::Error for InvalidLocalTarget {
+}
+
+impl Debug for LocalTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LocalTarget({})", self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(unix)]
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_local_target() {
+        assert_eq!(LocalTarget::from_str("25565").unwrap().socket_addr(), Some("127.0.0.1:25565".parse().unwrap()));
+        assert_eq!(LocalTarget::from_str("10.0.0.2:80").unwrap().socket_addr(), Some("10.0.0.2:80".parse().unwrap()));
+        assert!(LocalTarget::from_str("unix:").is_err());
+        assert!(LocalTarget::from_str("not a target").is_err());
+
+        #[cfg(unix)]
+        assert!(matches!(
+            LocalTarget::from_str("unix:/run/panel.sock").unwrap(),
+            LocalTarget::Unix(path) if path == Path::new("/run/panel.sock")
+        ));
+    }
+
+    #[test]
+    fn test_port_offset_out_of_range() {
+        let target = LocalTarget::Addr("127.0.0.1:65530".parse().unwrap());
+        assert_eq!(target.clone().with_port_offset(5).unwrap().socket_addr(), Some("127.0.0.1:65535".parse().unwrap()));
+        assert!(target.with_port_offset(6).is_none());
+    }
+}
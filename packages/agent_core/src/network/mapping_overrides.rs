@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use playit_agent_proto::PortProto;
+
+use crate::api::client::{ApiClient, ApiError};
+use crate::api::messages::{AccountTunnel, ListAccountTunnels};
+use crate::network::address_lookup::{AddressLookup, MatchAddress};
+use crate::network::local_target::LocalTarget;
+use crate::network::tls_sni::SniRoutes;
+use crate::network::tunnel_handler::TunnelHandler;
+use crate::network::tunnel_settings::TunnelSettings;
+
+pub struct MappingOverride {
+    tunnel: AccountTunnel,
+    match_ip: Ipv6Addr,
+    target: LocalTarget,
+    settings: TunnelSettings,
+    sni_routes: Option<Arc<SniRoutes>>,
+}
+
+impl MappingOverride {
+    pub fn new(tunnel: AccountTunnel, local_addr: SocketAddr) -> Self {
+        Self::with_target(tunnel, LocalTarget::Addr(local_addr))
+    }
+
+    /// Serve the tunnel's clients in-process.
+    pub fn with_handler(tunnel: AccountTunnel, handler: Arc<dyn TunnelHandler>) -> Self {
+        Self::with_target(tunnel, LocalTarget::Handler(handler))
+    }
+
+    pub fn with_target(tunnel: AccountTunnel, target: LocalTarget) -> Self {
+        let match_ip = LookupWithOverrides::match_ip(tunnel.ip_address);
+
+        MappingOverride {
+            tunnel,
+            match_ip,
+            target,
+            settings: TunnelSettings::default(),
+            sni_routes: None,
+        }
+    }
+
+    /// Sends TLS clients asking for `server_name` to `target` instead of the
+    /// tunnel's target, `*.example.com` matches any subdomain. Handler targets
+    /// are given the ClientHello read to route the client.
+    pub fn with_sni_route(mut self, server_name: &str, target: LocalTarget) -> Self {
+        let routes = self.sni_routes.get_or_insert_with(Default::default);
+        Arc::make_mut(routes).add(server_name, target);
+        self
+    }
+
+    /// Also routes HTTP requests for the tunnel's custom domain, if it has one,
+    /// to `HttpSettings::custom_domain_target`.
+    pub fn with_settings(mut self, mut settings: TunnelSettings) -> Self {
+        if let (Some(domain), Some(target)) = (&self.tunnel.custom_domain, settings.http.custom_domain_target) {
+            settings.http.routes.entry(domain.name.to_ascii_lowercase()).or_insert(target);
+        }
+
+        self.settings = settings;
+        self
+    }
+
+    pub fn tunnel(&self) -> &AccountTunnel {
+        &self.tunnel
+    }
+}
+
+/// Maps tunnels to the local address given by their override, tunnels
+/// without one are sent to localhost on the tunnel's own port.
+#[derive(Default)]
+pub struct LookupWithOverrides {
+    overrides: Vec<MappingOverride>,
+    /* account tunnels without an override, only used to list mapped tunnels */
+    account_tunnels: Vec<AccountTunnel>,
+    default_settings: TunnelSettings,
+}
+
+impl LookupWithOverrides {
+    pub fn new(overrides: Vec<MappingOverride>) -> Self {
+        LookupWithOverrides {
+            overrides,
+            account_tunnels: Vec::new(),
+            default_settings: TunnelSettings::default(),
+        }
+    }
+
+    /// Resolves `(tunnel id, local target)` pairs against the account's tunnels.
+    pub async fn load(api: &ApiClient, targets: Vec<(Uuid, LocalTarget)>) -> Result<Self, OverrideError> {
+        let tunnels = api.req(ListAccountTunnels).await?;
+        let mut tunnel_lookup = HashMap::new();
+        let mut tunnel_found = HashSet::new();
+
+        for tunnel in tunnels.tunnels {
+            tunnel_found.insert(tunnel.id);
+            tunnel_lookup.insert(tunnel.id, tunnel);
+        }
+
+        let mut overrides = Vec::with_capacity(targets.len());
+
+        for (tunnel_id, target) in targets {
+            match tunnel_lookup.remove(&tunnel_id) {
+                Some(tunnel) => overrides.push(MappingOverride::with_target(tunnel, target)),
+                None if tunnel_found.contains(&tunnel_id) => return Err(OverrideError::TunnelOverwrittenAlready(tunnel_id)),
+                None => return Err(OverrideError::TunnelNotFound(tunnel_id)),
+            }
+        }
+
+        Ok(Self::new(overrides).with_account_tunnels(tunnel_lookup.into_values().collect()))
+    }
+
+    /// Tunnels of the account served by the default mapping, so they are
+    /// health checked like overridden ones. Tunnels with an override are skipped.
+    pub fn with_account_tunnels(mut self, tunnels: Vec<AccountTunnel>) -> Self {
+        let overridden: HashSet<Uuid> = self.overrides.iter().map(|over| over.tunnel.id).collect();
+        self.account_tunnels = tunnels.into_iter().filter(|tunnel| !overridden.contains(&tunnel.id)).collect();
+        self
+    }
+
+    /// Settings used for tunnels that don't have an override.
+    pub fn with_default_settings(mut self, settings: TunnelSettings) -> Self {
+        self.default_settings = settings;
+        self
+    }
+
+    pub fn overrides(&self) -> &[MappingOverride] {
+        &self.overrides
+    }
+
+    /* a port can have separate tcp and udp tunnels, each with its own override */
+    fn find_override(&self, match_addr: MatchAddress, proto: PortProto) -> Option<&MappingOverride> {
+        self.overrides.iter().find(|over| {
+            over.match_ip == match_addr.ip
+                && over.tunnel.from_port == match_addr.from_port
+                && (over.tunnel.port_type == PortProto::Both || over.tunnel.port_type == proto)
+        })
+    }
+}
+
+impl AddressLookup for LookupWithOverrides {
+    fn find_tunnel_port_range(&self, match_ip: Ipv6Addr, port: u16, proto: PortProto) -> Option<(u16, u16)> {
+        for over in &self.overrides {
+            if over.match_ip == match_ip && over.tunnel.from_port <= port && port < over.tunnel.to_port && (over.tunnel.port_type == PortProto::Both || over.tunnel.port_type == proto) {
+                return Some((over.tunnel.from_port, over.tunnel.to_port));
+            }
+        }
+        Some((1, u16::MAX))
+    }
+
+    fn local_address(&self, match_addr: MatchAddress, proto: PortProto) -> Option<SocketAddr> {
+        self.local_target(match_addr, proto)?.socket_addr()
+    }
+
+    fn local_target(&self, match_addr: MatchAddress, proto: PortProto) -> Option<LocalTarget> {
+        match self.find_override(match_addr, proto) {
+            Some(over) => Some(over.target.clone()),
+            None => Some(LocalTarget::Addr(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, match_addr.from_port)))),
+        }
+    }
+
+    fn mapped_tunnels(&self) -> Vec<(MatchAddress, PortProto)> {
+        let overridden = self.overrides.iter().map(|over| (over.match_ip, &over.tunnel));
+        let account = self.account_tunnels.iter().map(|tunnel| (Self::match_ip(tunnel.ip_address), tunnel));
+
+        overridden.chain(account)
+            .map(|(match_ip, tunnel)| {
+                let match_addr = MatchAddress {
+                    ip: match_ip,
+                    from_port: tunnel.from_port,
+                    to_port: tunnel.to_port,
+                };
+                (match_addr, tunnel.port_type)
+            })
+            .collect()
+    }
+
+    fn sni_routes(&self, match_addr: MatchAddress, proto: PortProto) -> Option<Arc<SniRoutes>> {
+        self.find_override(match_addr, proto).and_then(|over| over.sni_routes.clone())
+    }
+
+    fn tunnel_settings(&self, match_addr: MatchAddress, proto: PortProto) -> TunnelSettings {
+        match self.find_override(match_addr, proto) {
+            Some(over) => over.settings.clone(),
+            None => self.default_settings.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OverrideError {
+    TunnelNotFound(Uuid),
+    TunnelOverwrittenAlready(Uuid),
+    ApiError(ApiError),
+}
+
+impl Display for OverrideError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for OverrideError {
+}
+
+impl From<ApiError> for OverrideError {
+    fn from(e: ApiError) -> Self {
+        OverrideError::ApiError(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::api::messages::TunnelProtocol;
+
+    use super::*;
+
+    fn account_tunnel(from_port: u16, port_type: PortProto) -> AccountTunnel {
+        AccountTunnel {
+            id: Uuid::from_u128(from_port as u128),
+            enabled: true,
+            name: None,
+            ip_address: "147.185.221.1".parse().unwrap(),
+            ip_hostname: "example.gl.at.ply.gg".to_string(),
+            custom_domain: None,
+            assigned_domain: "example.gl.joinmc.link".to_string(),
+            display_address: format!("example.gl.joinmc.link:{}", from_port),
+            is_dedicated_ip: false,
+            from_port,
+            to_port: from_port + 1,
+            tunnel_type: None,
+            port_type,
+            firewall_id: None,
+            protocol: TunnelProtocol::ToAgent {
+                local_ip: "127.0.0.1".parse().unwrap(),
+                local_port: from_port,
+                agent_id: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_mapped_tunnels_include_account_tunnels() {
+        let overridden = account_tunnel(1000, PortProto::Tcp);
+        let default_mapped = account_tunnel(2000, PortProto::Udp);
+
+        let lookup = LookupWithOverrides::new(vec![MappingOverride::new(overridden.clone(), "127.0.0.1:25565".parse().unwrap())])
+            .with_account_tunnels(vec![overridden, default_mapped]);
+
+        let ports: Vec<(u16, PortProto)> = lookup.mapped_tunnels().into_iter()
+            .map(|(match_addr, proto)| (match_addr.from_port, proto))
+            .collect();
+        assert_eq!(ports, vec![(1000, PortProto::Tcp), (2000, PortProto::Udp)]);
+    }
+
+    #[test]
+    fn test_overrides_match_port_proto() {
+        let mut tcp_settings = TunnelSettings::default();
+        tcp_settings.tcp.nodelay = true;
+        let mut udp_settings = TunnelSettings::default();
+        udp_settings.udp.idle_timeout_secs = 30;
+
+        let mut udp_tunnel = account_tunnel(1000, PortProto::Udp);
+        udp_tunnel.id = Uuid::from_u128(1);
+
+        let lookup = LookupWithOverrides::new(vec![
+            MappingOverride::new(account_tunnel(1000, PortProto::Tcp), "127.0.0.1:25565".parse().unwrap())
+                .with_settings(tcp_settings.clone())
+                .with_sni_route("example.com", LocalTarget::Addr("127.0.0.1:8443".parse().unwrap())),
+            MappingOverride::new(udp_tunnel, "127.0.0.1:19132".parse().unwrap()).with_settings(udp_settings.clone()),
+        ]);
+
+        let match_addr = MatchAddress { ip: LookupWithOverrides::match_ip("147.185.221.1".parse().unwrap()), from_port: 1000, to_port: 1001 };
+        assert_eq!(lookup.tunnel_settings(match_addr, PortProto::Tcp), tcp_settings);
+        assert_eq!(lookup.tunnel_settings(match_addr, PortProto::Udp), udp_settings);
+        assert!(lookup.sni_routes(match_addr, PortProto::Tcp).is_some());
+        assert!(lookup.sni_routes(match_addr, PortProto::Udp).is_none());
+        assert_eq!(lookup.local_address(match_addr, PortProto::Udp), Some("127.0.0.1:19132".parse().unwrap()));
+    }
+}
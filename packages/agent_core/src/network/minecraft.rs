@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/* largest handshake or login start we expect, login start carried key signatures in 1.19 */
+const MAX_PACKET_LEN: usize = 4096;
+const LEGACY_PING: u8 = 0xFE;
+
+const STATE_STATUS: i32 = 1;
+
+/// Handshake aware mode for Minecraft Java tunnels, off by default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct MinecraftSettings {
+    pub enabled: bool,
+    /* requested hostname to backend, other hostnames use the tunnel's local target */
+    pub routes: HashMap<String, SocketAddr>,
+    /* shown in the server list while the backend can't be reached */
+    pub offline_motd: Option<String>,
+    /* max time for the player to send the handshake and login start */
+    pub handshake_timeout_secs: u64,
+}
+
+impl Default for MinecraftSettings {
+    fn default() -> Self {
+        MinecraftSettings {
+            enabled: false,
+            routes: HashMap::new(),
+            offline_motd: None,
+            handshake_timeout_secs: 10,
+        }
+    }
+}
+
+impl MinecraftSettings {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_secs.max(1))
+    }
+
+    pub fn route(&self, handshake: &Handshake) -> Option<SocketAddr> {
+        let hostname = handshake.hostname();
+        self.routes.iter()
+            .find(|(name, _)| name.trim_end_matches('.').eq_ignore_ascii_case(&hostname))
+            .map(|(_, addr)| *addr)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol_version: i32,
+    pub server_address: String,
+    pub server_port: u16,
+    pub next_state: i32,
+}
+
+impl Handshake {
+    /// Requested hostname without the data mod loaders append after a nul
+    /// (such as Forge's `\0FML\0`) or the trailing dot of SRV lookups.
+    pub fn hostname(&self) -> String {
+        let hostname = self.server_address.split('\0').next().unwrap_or_default();
+        hostname.trim_end_matches('.').to_ascii_lowercase()
+    }
+
+    pub fn is_status(&self) -> bool {
+        self.next_state == STATE_STATUS
+    }
+
+    fn parse(packet: &[u8]) -> std::io::Result<Self> {
+        let mut reader = PacketReader(packet);
+        if reader.varint()? != 0 {
+            return Err(invalid_data("expected handshake packet"));
+        }
+
+        Ok(Handshake {
+            protocol_version: reader.varint()?,
+            server_address: reader.string()?,
+            server_port: reader.u16()?,
+            next_state: reader.varint()?,
+        })
+    }
+}
+
+/// What was read from the player before connecting locally. The raw bytes
+/// have to be sent to the local server before piping the rest.
+#[derive(Debug, Default)]
+pub struct MinecraftSession {
+    /* None for legacy server list pings, which are forwarded as is */
+    pub handshake: Option<Handshake>,
+    pub username: Option<String>,
+    pub consumed: Vec<u8>,
+}
+
+/// Reads the handshake, and for logins the login start packet with the player's name.
+pub async fn read_session<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<MinecraftSession> {
+    let mut session = MinecraftSession::default();
+
+    let first = stream.read_u8().await?;
+    session.consumed.push(first);
+
+    if first == LEGACY_PING {
+        return Ok(session);
+    }
+
+    let packet = read_packet_after(stream, &mut session.consumed, first).await?;
+    let handshake = Handshake::parse(&packet)?;
+
+    if !handshake.is_status() {
+        let packet = read_packet(stream, &mut session.consumed).await?;
+        session.username = parse_login_start(&packet);
+    }
+
+    session.handshake = Some(handshake);
+    Ok(session)
+}
+
+/// Answers a status request and ping on behalf of a backend that is down.
+pub async fn respond_offline_status<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, handshake: &Handshake, motd: &str) -> std::io::Result<()> {
+    let mut consumed = Vec::new();
+
+    let request = read_packet(stream, &mut consumed).await?;
+    if request != [0x00] {
+        return Err(invalid_data("expected status request"));
+    }
+
+    let status = serde_json::json!({
+        "version": { "name": "offline", "protocol": handshake.protocol_version },
+        "players": { "max": 0, "online": 0 },
+        "description": { "text": motd },
+    });
+
+    let mut response = Vec::new();
+    write_varint(&mut response, 0x00);
+    write_string(&mut response, &status.to_string());
+    write_packet(stream, &response).await?;
+
+    /* the client may close without pinging, that's not an error */
+    let ping = match read_packet(stream, &mut consumed).await {
+        Ok(ping) => ping,
+        Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+        Err(error) => return Err(error),
+    };
+
+    if ping.len() != 9 || ping[0] != 0x01 {
+        return Err(invalid_data("expected ping"));
+    }
+
+    /* pong echoes the ping's payload */
+    write_packet(stream, &ping).await?;
+    stream.flush().await
+}
+
+fn parse_login_start(packet: &[u8]) -> Option<String> {
+    let mut reader = PacketReader(packet);
+    if reader.varint().ok()? != 0 {
+        return None;
+    }
+    reader.string().ok()
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R, consumed: &mut Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let first = stream.read_u8().await?;
+    consumed.push(first);
+    read_packet_after(stream, consumed, first).await
+}
+
+async fn read_packet_after<R: AsyncRead + Unpin>(stream: &mut R, consumed: &mut Vec<u8>, first: u8) -> std::io::Result<Vec<u8>> {
+    let mut len = (first & 0x7F) as usize;
+    let mut byte = first;
+    let mut shift = 7;
+
+    while byte & 0x80 != 0 {
+        if 21 <= shift {
+            return Err(invalid_data("packet length too long"));
+        }
+
+        byte = stream.read_u8().await?;
+        consumed.push(byte);
+        len |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+    }
+
+    if len == 0 || MAX_PACKET_LEN < len {
+        return Err(invalid_data("unexpected packet length"));
+    }
+
+    let mut packet = vec![0u8; len];
+    stream.read_exact(&mut packet).await?;
+    consumed.extend_from_slice(&packet);
+
+    Ok(packet)
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(stream: &mut W, packet: &[u8]) -> std::io::Result<()> {
+    let mut out = Vec::with_capacity(packet.len() + 5);
+    write_varint(&mut out, packet.len() as i32);
+    out.extend_from_slice(packet);
+    stream.write_all(&out).await
+}
+
+fn write_varint(out: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            out.push(value as u8);
+            return;
+        }
+        out.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_varint(out, value.len() as i32);
+    out.extend_from_slice(value.as_bytes());
+}
+
+struct PacketReader<'a>(&'a [u8]);
+
+impl PacketReader<'_> {
+    fn take(&mut self, len: usize) -> std::io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("packet too short"));
+        }
+
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn varint(&mut self) -> std::io::Result<i32> {
+        let mut value = 0u32;
+        for i in 0..5 {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7F) as u32) << (7 * i);
+
+            if byte & 0x80 == 0 {
+                return Ok(value as i32);
+            }
+        }
+        Err(invalid_data("varint too long"))
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        let len = self.varint()?;
+        if len < 0 {
+            return Err(invalid_data("negative string length"));
+        }
+
+        let value = self.take(len as usize)?;
+        String::from_utf8(value.to_vec()).map_err(|_| invalid_data("string is not utf8"))
+    }
+
+    fn u16(&mut self) -> std::io::Result<u16> {
+        let value = self.take(2)?;
+        Ok(u16::from_be_bytes([value[0], value[1]]))
+    }
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn handshake_packet(server_address: &str, next_state: i32) -> Vec<u8> {
+        let mut packet = Vec::new();
+        write_varint(&mut packet, 0x00);
+        write_varint(&mut packet, 767);
+        write_string(&mut packet, server_address);
+        packet.extend_from_slice(&25565u16.to_be_bytes());
+        write_varint(&mut packet, next_state);
+        packet
+    }
+
+    fn framed(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for packet in packets {
+            write_varint(&mut out, packet.len() as i32);
+            out.extend_from_slice(packet);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn test_read_login_session() {
+        let mut login_start = Vec::new();
+        write_varint(&mut login_start, 0x00);
+        write_string(&mut login_start, "Notch");
+        login_start.extend_from_slice(&[0u8; 16]);
+
+        let mut data = framed(&[handshake_packet("Play.Example.com.\0FML\0", 2), login_start]);
+        data.extend_from_slice(b"rest");
+
+        let mut stream = &data[..];
+        let session = read_session(&mut stream).await.unwrap();
+
+        let handshake = session.handshake.unwrap();
+        assert_eq!(handshake.protocol_version, 767);
+        assert_eq!(handshake.hostname(), "play.example.com");
+        assert!(!handshake.is_status());
+        assert_eq!(session.username.as_deref(), Some("Notch"));
+
+        /* everything but what follows login start is kept for the local server */
+        assert_eq!(session.consumed, data[..data.len() - 4]);
+        assert_eq!(stream, b"rest");
+
+        let settings = MinecraftSettings {
+            routes: HashMap::from([("play.example.com.".to_string(), "10.0.0.1:25565".parse().unwrap())]),
+            ..MinecraftSettings::default()
+        };
+        assert_eq!(settings.route(&handshake), Some("10.0.0.1:25565".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_ping_and_bad_packets() {
+        let mut stream = &[0xFE, 0x01][..];
+        let session = read_session(&mut stream).await.unwrap();
+        assert!(session.handshake.is_none());
+        assert_eq!(session.consumed, vec![0xFE]);
+
+        let mut stream = &[0xFF, 0xFF, 0xFF, 0x7F][..];
+        assert_eq!(read_session(&mut stream).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_offline_status() {
+        let (mut client, mut agent) = tokio::io::duplex(4096);
+
+        let mut ping = vec![0x01];
+        ping.extend_from_slice(&42i64.to_be_bytes());
+        client.write_all(&framed(&[handshake_packet("localhost", 1), vec![0x00], ping.clone()])).await.unwrap();
+
+        let session = read_session(&mut agent).await.unwrap();
+        let handshake = session.handshake.unwrap();
+        assert!(handshake.is_status());
+        assert_eq!(session.username, None);
+
+        respond_offline_status(&mut agent, &handshake, "server is sleeping").await.unwrap();
+        drop(agent);
+
+        let mut consumed = Vec::new();
+        let response = read_packet(&mut client, &mut consumed).await.unwrap();
+        let mut reader = PacketReader(&response);
+        assert_eq!(reader.varint().unwrap(), 0x00);
+
+        let status: serde_json::Value = serde_json::from_str(&reader.string().unwrap()).unwrap();
+        assert_eq!(status["description"]["text"], "server is sleeping");
+        assert_eq!(status["version"]["protocol"], 767);
+
+        assert_eq!(read_packet(&mut client, &mut consumed).await.unwrap(), ping);
+    }
+}
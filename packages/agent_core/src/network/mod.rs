@@ -3,4 +3,18 @@ pub mod tcp_clients;
 pub mod address_lookup;
 pub mod lan_address;
 pub mod tcp_pipe;
-
+pub mod tunnel_settings;
+pub mod mapping_overrides;
+pub mod tunnel_handler;
+pub mod local_target;
+pub mod backend_pool;
+pub mod local_health;
+pub mod rate_limit;
+pub mod bandwidth;
+pub mod geo_filter;
+pub mod minecraft;
+pub mod http_routing;
+pub mod tls_sni;
+pub mod tls_termination;
+pub mod raknet;
+pub mod packet_filter;
@@ -0,0 +1,331 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+#[cfg(feature = "lua")]
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use playit_agent_proto::PortProto;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/* max wait for the first bytes, servers that speak first never get any */
+pub const PEEK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/* lua VM instructions a script may run per call before it is stopped */
+#[cfg(feature = "lua")]
+pub const LUA_INSTRUCTION_LIMIT: u32 = 1_000_000;
+
+/// A new TCP client or UDP flow, given to the filter before it reaches the
+/// local server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterRequest {
+    pub proto: PortProto,
+    pub peer_addr: SocketAddr,
+    pub tunnel_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterVerdict {
+    Accept,
+    /* TCP clients are closed, UDP packets dropped without opening a flow */
+    Reject,
+    /* forward to this address instead of the tunnel's local target */
+    Route(SocketAddr),
+}
+
+/// User supplied per connection filter, such as custom anti bot checks.
+/// Called once per TCP client and once for the first packet of a UDP flow,
+/// including those sent to a `TunnelHandler`.
+///
+/// Calls are made on tokio's blocking pool so a slow filter only delays the
+/// connection being checked, but implementations must not block (no network
+/// or disk I/O, no waiting on locks held elsewhere) as every new client
+/// waits on a thread from that pool.
+pub trait PacketFilter: Send + Sync + 'static {
+    /// Bytes of the TCP stream or first datagram to pass to `filter`. With
+    /// 0 TCP clients are checked before being claimed.
+    fn peek_len(&self) -> usize {
+        0
+    }
+
+    fn filter(&self, request: &FilterRequest, data: &[u8]) -> FilterVerdict;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterRejected;
+
+impl Display for FilterRejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for FilterRejected {
+}
+
+/// Runs the agent's filter for TCP clients and UDP flows, shared by both.
+#[derive(Default)]
+pub struct FilterHook {
+    filter: Option<Arc<dyn PacketFilter>>,
+    rejected: AtomicU64,
+}
+
+impl FilterHook {
+    pub fn new(filter: Option<Arc<dyn PacketFilter>>) -> Self {
+        FilterHook {
+            filter,
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    pub fn peek_len(&self) -> usize {
+        self.filter.as_ref().map(|filter| filter.peek_len()).unwrap_or(0)
+    }
+
+    /// Route to use if the filter picked one, data is cut to `peek_len`. The
+    /// filter runs on the blocking pool, a filter that panics rejects.
+    pub async fn check(&self, request: &FilterRequest, data: &[u8]) -> Result<Option<SocketAddr>, FilterRejected> {
+        let Some(filter) = &self.filter else {
+            return Ok(None);
+        };
+
+        let filter = filter.clone();
+        let request = *request;
+        let data = data[..data.len().min(filter.peek_len())].to_vec();
+
+        let verdict = tokio::task::spawn_blocking(move || filter.filter(&request, &data)).await;
+        match verdict.unwrap_or(FilterVerdict::Reject) {
+            FilterVerdict::Accept => Ok(None),
+            FilterVerdict::Route(addr) => Ok(Some(addr)),
+            FilterVerdict::Reject => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(FilterRejected)
+            }
+        }
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+/// Reads what the client sent first, up to `len` bytes. Returns what arrived
+/// within `PEEK_TIMEOUT`, which may be nothing.
+pub async fn peek_stream<S: AsyncRead + Unpin>(stream: &mut S, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    let mut read = 0;
+
+    let deadline = tokio::time::Instant::now() + PEEK_TIMEOUT;
+    while read < len {
+        match tokio::time::timeout_at(deadline, stream.read(&mut data[read..])).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(bytes)) => read += bytes,
+            Ok(Err(error)) => return Err(error),
+        }
+    }
+
+    data.truncate(read);
+    Ok(data)
+}
+
+/// Filter written in Lua. The script defines a global `filter(request, data)`
+/// function, where request has `proto`, `peer_ip`, `peer_port`, `tunnel_ip`
+/// and `tunnel_port`. Returning nil or true accepts, false rejects and an
+/// address string routes. A global `peek_len` number sets how much data is
+/// passed, scripts that fail to run or exceed `LUA_INSTRUCTION_LIMIT` reject
+/// the connection.
+#[cfg(feature = "lua")]
+pub struct LuaPacketFilter {
+    lua: std::sync::Mutex<mlua::Lua>,
+    peek_len: usize,
+}
+
+#[cfg(feature = "lua")]
+impl LuaPacketFilter {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LuaFilterError> {
+        let source = std::fs::read_to_string(path).map_err(LuaFilterError::Io)?;
+        Self::from_source(&source)
+    }
+
+    pub fn from_source(source: &str) -> Result<Self, LuaFilterError> {
+        let lua = mlua::Lua::new();
+        Self::limit_instructions(&lua);
+        lua.load(source).exec().map_err(LuaFilterError::Lua)?;
+
+        let globals = lua.globals();
+        if !matches!(globals.get::<_, mlua::Value>("filter"), Ok(mlua::Value::Function(_))) {
+            return Err(LuaFilterError::MissingFilterFunction);
+        }
+        let peek_len = globals.get::<_, Option<usize>>("peek_len").map_err(LuaFilterError::Lua)?.unwrap_or(0);
+        drop(globals);
+
+        Ok(LuaPacketFilter {
+            lua: std::sync::Mutex::new(lua),
+            peek_len,
+        })
+    }
+
+    /* setting the hook restarts its count, so each call gets the full budget */
+    fn limit_instructions(lua: &mlua::Lua) {
+        lua.set_hook(mlua::HookTriggers::new().every_nth_instruction(LUA_INSTRUCTION_LIMIT), |_, _| {
+            Err(mlua::Error::RuntimeError("instruction limit exceeded".to_string()))
+        });
+    }
+
+    fn call(&self, request: &FilterRequest, data: &[u8]) -> mlua::Result<FilterVerdict> {
+        let lua = self.lua.lock().unwrap();
+        Self::limit_instructions(&lua);
+
+        let table = lua.create_table()?;
+        table.set("proto", match request.proto {
+            PortProto::Tcp => "tcp",
+            PortProto::Udp => "udp",
+            PortProto::Both => "both",
+        })?;
+        table.set("peer_ip", request.peer_addr.ip().to_string())?;
+        table.set("peer_port", request.peer_addr.port())?;
+        table.set("tunnel_ip", request.tunnel_addr.ip().to_string())?;
+        table.set("tunnel_port", request.tunnel_addr.port())?;
+
+        let filter: mlua::Function = lua.globals().get("filter")?;
+        let result = filter.call::<_, mlua::Value>((table, lua.create_string(data)?))?;
+
+        let verdict = match result {
+            mlua::Value::Nil | mlua::Value::Boolean(true) => FilterVerdict::Accept,
+            mlua::Value::Boolean(false) => FilterVerdict::Reject,
+            mlua::Value::String(addr) => match addr.to_str()?.parse() {
+                Ok(addr) => FilterVerdict::Route(addr),
+                Err(_) => return Err(mlua::Error::RuntimeError(format!("invalid route address: {:?}", addr.to_string_lossy()))),
+            },
+            other => return Err(mlua::Error::RuntimeError(format!("unexpected filter result: {}", other.type_name()))),
+        };
+        Ok(verdict)
+    }
+}
+
+#[cfg(feature = "lua")]
+impl PacketFilter for LuaPacketFilter {
+    fn peek_len(&self) -> usize {
+        self.peek_len
+    }
+
+    fn filter(&self, request: &FilterRequest, data: &[u8]) -> FilterVerdict {
+        match self.call(request, data) {
+            Ok(verdict) => verdict,
+            Err(error) => {
+                tracing::error!(%error, ?request, "lua packet filter failed, rejecting");
+                FilterVerdict::Reject
+            }
+        }
+    }
+}
+
+#[cfg(feature = "lua")]
+#[derive(Debug)]
+pub enum LuaFilterError {
+    Io(std::io::Error),
+    Lua(mlua::Error),
+    MissingFilterFunction,
+}
+
+#[cfg(feature = "lua")]
+impl Display for LuaFilterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(feature = "lua")]
+impl std::error::Error for LuaFilterError {
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct PrefixFilter;
+
+    impl PacketFilter for PrefixFilter {
+        fn peek_len(&self) -> usize {
+            4
+        }
+
+        fn filter(&self, request: &FilterRequest, data: &[u8]) -> FilterVerdict {
+            match data {
+                b"bad!" => FilterVerdict::Reject,
+                b"alt!" => FilterVerdict::Route(SocketAddr::new(request.peer_addr.ip(), 2000)),
+                _ => FilterVerdict::Accept,
+            }
+        }
+    }
+
+    fn request() -> FilterRequest {
+        FilterRequest {
+            proto: PortProto::Tcp,
+            peer_addr: "127.0.0.1:5000".parse().unwrap(),
+            tunnel_addr: "147.185.221.1:25565".parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_hook() {
+        assert_eq!(FilterHook::default().check(&request(), b"bad!").await, Ok(None));
+
+        let hook = FilterHook::new(Some(Arc::new(PrefixFilter)));
+        assert_eq!(hook.check(&request(), b"good data").await, Ok(None));
+        /* only peek_len bytes are passed on */
+        assert_eq!(hook.check(&request(), b"alt!ernative").await, Ok(Some("127.0.0.1:2000".parse().unwrap())));
+        assert_eq!(hook.check(&request(), b"bad!").await, Err(FilterRejected));
+        assert_eq!(hook.rejected(), 1);
+
+        let (mut client, mut agent) = tokio::io::duplex(64);
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"ba").await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"d!rest").await.unwrap();
+        assert_eq!(peek_stream(&mut agent, hook.peek_len()).await.unwrap(), b"bad!");
+    }
+
+    #[cfg(feature = "lua")]
+    #[test]
+    fn test_lua_filter() {
+        let filter = LuaPacketFilter::from_source(r#"
+            peek_len = 3
+            function filter(request, data)
+                if request.proto == "udp" and data == "bot" then
+                    return false
+                end
+                if request.peer_ip == "10.0.0.1" then
+                    return "127.0.0.1:2000"
+                end
+            end
+        "#).unwrap();
+
+        assert_eq!(filter.peek_len(), 3);
+        assert_eq!(filter.filter(&request(), b"bot"), FilterVerdict::Accept);
+        assert_eq!(filter.filter(&FilterRequest { proto: PortProto::Udp, ..request() }, b"bot"), FilterVerdict::Reject);
+        assert_eq!(
+            filter.filter(&FilterRequest { peer_addr: "10.0.0.1:1".parse().unwrap(), ..request() }, b""),
+            FilterVerdict::Route("127.0.0.1:2000".parse().unwrap()),
+        );
+
+        assert!(matches!(LuaPacketFilter::from_source("peek_len = 1"), Err(LuaFilterError::MissingFilterFunction)));
+        let broken = LuaPacketFilter::from_source("function filter() error('boom') end").unwrap();
+        assert_eq!(broken.filter(&request(), b""), FilterVerdict::Reject);
+
+        /* runaway scripts are stopped, and later calls get a fresh budget */
+        let looping = LuaPacketFilter::from_source(r#"
+            function filter(request, data)
+                if data == "spin" then
+                    while true do end
+                end
+            end
+        "#).unwrap();
+        assert_eq!(looping.filter(&request(), b"spin"), FilterVerdict::Reject);
+        assert_eq!(looping.filter(&request(), b""), FilterVerdict::Accept);
+        assert!(LuaPacketFilter::from_source("while true do end function filter() end").is_err());
+    }
+}
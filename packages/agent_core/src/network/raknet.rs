@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+
+/* marks offline (unconnected) RakNet messages */
+const OFFLINE_MESSAGE_MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe,
+    0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+
+const UNCONNECTED_PING: u8 = 0x01;
+const UNCONNECTED_PING_OPEN_CONNECTIONS: u8 = 0x02;
+const UNCONNECTED_PONG: u8 = 0x1c;
+const DISCONNECTION_NOTIFICATION: u8 = 0x15;
+
+const DATAGRAM_VALID: u8 = 0x80;
+const DATAGRAM_ACK: u8 = 0x40;
+const DATAGRAM_NACK: u8 = 0x20;
+const FRAME_SPLIT: u8 = 0x10;
+
+/* shown as the server id, clients only use it to tell servers apart */
+const OFFLINE_SERVER_GUID: u64 = 0x706c_6179_6974_2121;
+
+/// RakNet aware mode for Minecraft Bedrock tunnels, off by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RakNetSettings {
+    pub enabled: bool,
+    /* answers server list pings while the local target fails health checks,
+     * a RakNet probe is used if no health check is configured */
+    pub offline_motd: Option<String>,
+}
+
+/// Time sent with an unconnected ping, echoed back in the pong.
+pub fn parse_unconnected_ping(packet: &[u8]) -> Option<u64> {
+    if packet.len() < 25 || !matches!(packet[0], UNCONNECTED_PING | UNCONNECTED_PING_OPEN_CONNECTIONS) {
+        return None;
+    }
+
+    if packet[9..25] != OFFLINE_MESSAGE_MAGIC {
+        return None;
+    }
+
+    Some(u64::from_be_bytes(packet[1..9].try_into().unwrap()))
+}
+
+pub fn unconnected_ping(ping_time: u64) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(33);
+    packet.push(UNCONNECTED_PING);
+    packet.extend_from_slice(&ping_time.to_be_bytes());
+    packet.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+    /* client guid */
+    packet.extend_from_slice(&0u64.to_be_bytes());
+    packet
+}
+
+/// Pong listing a Bedrock server with no players and an unknown version.
+pub fn offline_pong(ping_time: u64, motd: &str, port: u16) -> Vec<u8> {
+    /* ';' separates fields, the client would cut the motd short */
+    let motd = motd.replace(';', ",");
+    let status = format!(
+        "MCPE;{};0;offline;0;0;{};playit.gg;Survival;1;{};{};",
+        motd, OFFLINE_SERVER_GUID, port, port,
+    );
+    let status = &status.as_bytes()[..status.len().min(u16::MAX as usize)];
+
+    let mut packet = Vec::with_capacity(35 + status.len());
+    packet.push(UNCONNECTED_PONG);
+    packet.extend_from_slice(&ping_time.to_be_bytes());
+    packet.extend_from_slice(&OFFLINE_SERVER_GUID.to_be_bytes());
+    packet.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+    packet.extend_from_slice(&(status.len() as u16).to_be_bytes());
+    packet.extend_from_slice(status);
+    packet
+}
+
+pub fn is_unconnected_pong(packet: &[u8]) -> bool {
+    35 <= packet.len() && packet[0] == UNCONNECTED_PONG && packet[17..33] == OFFLINE_MESSAGE_MAGIC
+}
+
+/// Connected datagrams only flow once the RakNet handshake completed.
+pub fn is_connected_datagram(packet: &[u8]) -> bool {
+    matches!(packet.first(), Some(flags) if flags & DATAGRAM_VALID != 0 && flags & (DATAGRAM_ACK | DATAGRAM_NACK) == 0)
+}
+
+/// True if a datagram carries a disconnection notification from either side.
+pub fn is_disconnect(packet: &[u8]) -> bool {
+    if !is_connected_datagram(packet) {
+        return false;
+    }
+
+    /* flags and 3 byte sequence number */
+    let mut pos = 4;
+
+    while pos < packet.len() {
+        let flags = packet[pos];
+        let Some(bits) = packet.get(pos + 1..pos + 3) else {
+            return false;
+        };
+        let len = (u16::from_be_bytes([bits[0], bits[1]]) as usize).div_ceil(8);
+        pos += 3;
+
+        let reliability = flags >> 5;
+        if matches!(reliability, 2 | 3 | 4 | 6 | 7) {
+            /* reliable index */
+            pos += 3;
+        }
+        if matches!(reliability, 1 | 4) {
+            /* sequenced index */
+            pos += 3;
+        }
+        if matches!(reliability, 1 | 3 | 4 | 7) {
+            /* ordered index and channel */
+            pos += 4;
+        }
+
+        let split = flags & FRAME_SPLIT != 0;
+        if split {
+            /* split count, id and index */
+            pos += 10;
+        }
+
+        let Some(body) = packet.get(pos..pos + len) else {
+            return false;
+        };
+
+        if !split && body.first() == Some(&DISCONNECTION_NOTIFICATION) {
+            return true;
+        }
+
+        pos += len;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn datagram(frames: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut packet = vec![DATAGRAM_VALID | 0x04, 0x01, 0x00, 0x00];
+
+        for (reliability, body) in frames {
+            packet.push(reliability << 5);
+            packet.extend_from_slice(&((body.len() * 8) as u16).to_be_bytes());
+            /* reliable ordered has reliable and ordered indexes, unreliable has none */
+            if *reliability == 3 {
+                packet.extend_from_slice(&[0x02, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]);
+            }
+            packet.extend_from_slice(body);
+        }
+
+        packet
+    }
+
+    #[test]
+    fn test_offline_ping_and_pong() {
+        let ping = unconnected_ping(1234);
+        assert_eq!(ping.len(), 33);
+        assert_eq!(parse_unconnected_ping(&ping), Some(1234));
+        assert_eq!(parse_unconnected_ping(&ping[..24]), None);
+        assert_eq!(parse_unconnected_ping(&datagram(&[(0, &[0x00])])), None);
+
+        let pong = offline_pong(1234, "back; soon", 19132);
+        assert!(is_unconnected_pong(&pong));
+        assert_eq!(&pong[1..9], &1234u64.to_be_bytes());
+
+        let len = u16::from_be_bytes([pong[33], pong[34]]) as usize;
+        let status = std::str::from_utf8(&pong[35..]).unwrap();
+        assert_eq!(status.len(), len);
+        assert!(status.starts_with("MCPE;back, soon;0;offline;0;0;"));
+        assert!(status.ends_with(";19132;19132;"));
+    }
+
+    #[test]
+    fn test_detect_disconnect() {
+        let game_packet: &[u8] = &[0xfe, 0x01, 0x02, 0x03];
+
+        assert!(!is_disconnect(&datagram(&[(3, game_packet)])));
+        assert!(is_disconnect(&datagram(&[(3, game_packet), (3, &[DISCONNECTION_NOTIFICATION])])));
+        assert!(is_disconnect(&datagram(&[(0, &[DISCONNECTION_NOTIFICATION])])));
+
+        /* acks and offline messages are never disconnects */
+        assert!(!is_disconnect(&[DATAGRAM_VALID | DATAGRAM_ACK, 0x00, 0x01, 0x00, 0x00, DISCONNECTION_NOTIFICATION]));
+        assert!(!is_disconnect(&[DISCONNECTION_NOTIFICATION]));
+
+        /* truncated frames are ignored */
+        let mut truncated = datagram(&[(3, &[DISCONNECTION_NOTIFICATION, 0x00])]);
+        truncated.pop();
+        assert!(!is_disconnect(&truncated));
+    }
+}
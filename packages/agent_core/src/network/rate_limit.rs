@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::now_milli;
+
+/* buckets that have refilled are dropped once the table grows past this,
+   then the least recently used until it is back under by an eighth */
+const MAX_TRACKED_IPS: usize = 4096;
+const EVICT_TO_IPS: usize = MAX_TRACKED_IPS - MAX_TRACKED_IPS / 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /* new connections allowed per second on average */
+    pub per_second: f64,
+    /* connections allowed at once after being idle */
+    pub burst: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct RateLimits {
+    pub per_ip: Option<RateLimit>,
+    pub global: Option<RateLimit>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_ms: u64,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit, now: u64) -> Self {
+        TokenBucket {
+            tokens: limit.burst as f64,
+            updated_ms: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_ms) as f64 / 1000.0;
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated_ms = now;
+    }
+
+    fn has_token(&mut self, limit: &RateLimit, now: u64) -> bool {
+        self.refill(limit, now);
+        1.0 <= self.tokens
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimited {
+    PeerIp,
+    Global,
+}
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for RateLimited {
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimitedCounts {
+    pub peer_ip: u64,
+    pub global: u64,
+}
+
+/// Token buckets for new TCP clients and UDP flows, shared by both. Clients
+/// and flows sent to a `TunnelHandler` are limited the same way.
+pub struct ConnectionRateLimiter {
+    limits: RateLimits,
+    per_ip: Mutex<HashMap<IpAddr, TokenBucket>>,
+    global: Mutex<Option<TokenBucket>>,
+    limited_peer_ip: AtomicU64,
+    limited_global: AtomicU64,
+}
+
+impl Default for ConnectionRateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimits::default())
+    }
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        ConnectionRateLimiter {
+            limits,
+            per_ip: Mutex::new(HashMap::new()),
+            global: Mutex::new(None),
+            limited_peer_ip: AtomicU64::new(0),
+            limited_global: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits
+    }
+
+    /// Takes a token for a new connection from `peer_ip`, counting it if limited.
+    pub fn check(&self, peer_ip: IpAddr) -> Result<(), RateLimited> {
+        self.check_at(peer_ip, now_milli())
+    }
+
+    fn check_at(&self, peer_ip: IpAddr, now: u64) -> Result<(), RateLimited> {
+        let mut per_ip = self.per_ip.lock().unwrap();
+        let mut global = self.global.lock().unwrap();
+
+        /* check both before taking from either so a rejected connection costs nothing */
+        let per_ip = match &self.limits.per_ip {
+            Some(limit) => {
+                if MAX_TRACKED_IPS <= per_ip.len() && !per_ip.contains_key(&peer_ip) {
+                    Self::evict(&mut per_ip, limit, now);
+                }
+
+                let bucket = per_ip.entry(peer_ip).or_insert_with(|| TokenBucket::new(limit, now));
+                if !bucket.has_token(limit, now) {
+                    self.limited_peer_ip.fetch_add(1, Ordering::Relaxed);
+                    return Err(RateLimited::PeerIp);
+                }
+
+                Some(bucket)
+            }
+            None => None,
+        };
+
+        let global = match &self.limits.global {
+            Some(limit) => {
+                let bucket = global.get_or_insert_with(|| TokenBucket::new(limit, now));
+                if !bucket.has_token(limit, now) {
+                    self.limited_global.fetch_add(1, Ordering::Relaxed);
+                    return Err(RateLimited::Global);
+                }
+
+                Some(bucket)
+            }
+            None => None,
+        };
+
+        for bucket in per_ip.into_iter().chain(global) {
+            bucket.tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+
+    fn evict(buckets: &mut HashMap<IpAddr, TokenBucket>, limit: &RateLimit, now: u64) {
+        buckets.retain(|_, bucket| {
+            bucket.refill(limit, now);
+            bucket.tokens < limit.burst as f64
+        });
+
+        if buckets.len() < MAX_TRACKED_IPS {
+            return;
+        }
+
+        let mut oldest = buckets.iter()
+            .map(|(ip, bucket)| (bucket.updated_ms, *ip))
+            .collect::<Vec<_>>();
+
+        let evict = buckets.len() - EVICT_TO_IPS;
+        oldest.select_nth_unstable(evict - 1);
+
+        for (_, ip) in &oldest[..evict] {
+            buckets.remove(ip);
+        }
+    }
+
+    pub fn limited(&self) -> RateLimitedCounts {
+        RateLimitedCounts {
+            peer_ip: self.limited_peer_ip.load(Ordering::Relaxed),
+            global: self.limited_global.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limits() {
+        let limiter = ConnectionRateLimiter::new(RateLimits {
+            per_ip: Some(RateLimit { per_second: 1.0, burst: 2 }),
+            global: Some(RateLimit { per_second: 10.0, burst: 3 }),
+        });
+
+        let a: IpAddr = "1.1.1.1".parse().unwrap();
+        let b: IpAddr = "2.2.2.2".parse().unwrap();
+        let c: IpAddr = "3.3.3.3".parse().unwrap();
+
+        assert_eq!(limiter.check_at(a, 1000), Ok(()));
+        assert_eq!(limiter.check_at(a, 1000), Ok(()));
+        assert_eq!(limiter.check_at(a, 1000), Err(RateLimited::PeerIp));
+
+        assert_eq!(limiter.check_at(b, 1000), Ok(()));
+        assert_eq!(limiter.check_at(b, 1000), Err(RateLimited::Global));
+
+        /* global refills a token every 100ms, per ip every second */
+        assert_eq!(limiter.check_at(c, 1100), Ok(()));
+        assert_eq!(limiter.check_at(a, 1200), Err(RateLimited::PeerIp));
+        assert_eq!(limiter.check_at(a, 2000), Ok(()));
+
+        assert_eq!(limiter.limited(), RateLimitedCounts { peer_ip: 2, global: 1 });
+    }
+
+    #[test]
+    fn test_limited_connection_takes_no_tokens() {
+        let limiter = ConnectionRateLimiter::new(RateLimits {
+            per_ip: Some(RateLimit { per_second: 0.5, burst: 1 }),
+            global: Some(RateLimit { per_second: 1.0, burst: 1 }),
+        });
+
+        let a: IpAddr = "1.1.1.1".parse().unwrap();
+        let b: IpAddr = "2.2.2.2".parse().unwrap();
+
+        assert_eq!(limiter.check_at(a, 1000), Ok(()));
+        /* b is refused by the global bucket and keeps its own token, it
+           would only have half a token by 2000 if one had been taken */
+        assert_eq!(limiter.check_at(b, 1000), Err(RateLimited::Global));
+        assert_eq!(limiter.check_at(b, 2000), Ok(()));
+    }
+
+    #[test]
+    fn test_tracked_ips_are_capped() {
+        let limiter = ConnectionRateLimiter::new(RateLimits {
+            per_ip: Some(RateLimit { per_second: 0.001, burst: 1 }),
+            global: None,
+        });
+
+        /* none of these refill so all of them are still limited */
+        for i in 0..(MAX_TRACKED_IPS as u32 * 2) {
+            let ip = IpAddr::from(i.to_be_bytes());
+            assert_eq!(limiter.check_at(ip, 1000 + i as u64), Ok(()));
+            assert!(limiter.per_ip.lock().unwrap().len() <= MAX_TRACKED_IPS);
+        }
+
+        /* the most recent peers are still tracked, the oldest were evicted */
+        let last = IpAddr::from((MAX_TRACKED_IPS as u32 * 2 - 1).to_be_bytes());
+        assert_eq!(limiter.check_at(last, 100_000), Err(RateLimited::PeerIp));
+        assert_eq!(limiter.check_at(IpAddr::from(0u32.to_be_bytes()), 100_000), Ok(()));
+    }
+
+    #[test]
+    fn test_no_limits() {
+        let limiter = ConnectionRateLimiter::default();
+        for _ in 0..1000 {
+            assert!(limiter.check("1.1.1.1".parse().unwrap()).is_ok());
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::io::Error;
-use std::net::SocketAddr;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -12,16 +13,61 @@ use tokio::sync::RwLock;
 
 use playit_agent_proto::control_feed::{ClaimInstructions, NewClient};
 
-use crate::tunnel::tcp_tunnel::TcpTunnel;
+use crate::network::address_lookup::MatchAddress;
+use crate::network::tunnel_settings::TcpSettings;
+use crate::tunnel::tcp_tunnel::{ClaimError, TcpTunnel};
 
 #[derive(Clone)]
 pub struct TcpClients {
     inner: Arc<RwLock<Inner>>,
     pub use_special_lan: bool,
+    /* across all tunnels, per tunnel limits are in TcpSettings */
+    pub max_connections: Option<usize>,
 }
 
 struct Inner {
     active: HashMap<(SocketAddr, SocketAddr), NewClient>,
+    tunnels: HashMap<MatchAddress, TunnelCount>,
+    peak: usize,
+    refused: u64,
+}
+
+#[derive(Default)]
+struct TunnelCount {
+    current: usize,
+    peak: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimit {
+    Tunnel,
+    Global,
+}
+
+impl Display for ConnectionLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ConnectionLimit {
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TcpClientUsage {
+    pub current: usize,
+    pub peak: usize,
+    /* clients refused for being over a limit */
+    pub refused: u64,
+    pub tunnels: Vec<TunnelUsage>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelUsage {
+    pub tunnel_ip: Ipv6Addr,
+    pub tunnel_port: u16,
+    pub current: usize,
+    pub peak: usize,
 }
 
 impl TcpClients {
@@ -29,43 +75,107 @@ impl TcpClients {
         TcpClients {
             inner: Arc::new(RwLock::new(Inner {
                 active: HashMap::new(),
+                tunnels: HashMap::new(),
+                peak: 0,
+                refused: 0,
             })),
-            use_special_lan: true
+            use_special_lan: true,
+            max_connections: None,
         }
     }
 
-    pub async fn connect(&self, new_client: NewClient) -> std::io::Result<Option<TcpClient>> {
-        let peer_addr = new_client.peer_addr;
-        let key = (peer_addr, new_client.connect_addr);
+    pub async fn client_count(&self) -> usize {
+        self.inner.read().await.active.len()
+    }
 
-        let claim_instructions = new_client.claim_instructions.clone();
+    pub async fn usage(&self) -> TcpClientUsage {
+        let lock = self.inner.read().await;
+
+        let mut tunnels = lock.tunnels.iter()
+            .map(|(tunnel, count)| TunnelUsage {
+                tunnel_ip: tunnel.ip,
+                tunnel_port: tunnel.from_port,
+                current: count.current,
+                peak: count.peak,
+            })
+            .collect::<Vec<_>>();
+        tunnels.sort_by_key(|usage| (usage.tunnel_ip, usage.tunnel_port));
+
+        TcpClientUsage {
+            current: lock.active.len(),
+            peak: lock.peak,
+            refused: lock.refused,
+            tunnels,
+        }
+    }
+
+    /// Counts the client against the connection limits before it is claimed,
+    /// `None` if the client is already being handled.
+    pub async fn reserve(&self, new_client: NewClient, tunnel: Option<MatchAddress>, tunnel_max: Option<usize>) -> Result<Option<ReservedClient>, ConnectionLimit> {
+        let key = (new_client.peer_addr, new_client.connect_addr);
 
         {
             let mut lock = self.inner.write().await;
             if lock.active.contains_key(&key) {
                 return Ok(None);
             }
-            lock.active.insert(key, new_client);
+
+            if matches!(self.max_connections, Some(max) if max <= lock.active.len()) {
+                lock.refused += 1;
+                return Err(ConnectionLimit::Global);
+            }
+
+            if let Some(tunnel) = tunnel {
+                let current = lock.tunnels.get(&tunnel).map(|count| count.current).unwrap_or(0);
+                if matches!(tunnel_max, Some(max) if max <= current) {
+                    lock.refused += 1;
+                    return Err(ConnectionLimit::Tunnel);
+                }
+
+                let count = lock.tunnels.entry(tunnel).or_default();
+                count.current += 1;
+                count.peak = count.peak.max(count.current);
+            }
+
+            lock.active.insert(key, new_client.clone());
+            lock.peak = lock.peak.max(lock.active.len());
         }
 
-        /* create dropper before connect so on failure / timeout we clean up active map */
-        let dropper = Dropper {
-            key,
-            inner: self.inner.clone(),
-        };
+        Ok(Some(ReservedClient {
+            new_client,
+            use_special_lan: self.use_special_lan,
+            /* create dropper before connect so on failure / timeout we clean up active map */
+            dropper: Dropper {
+                key,
+                tunnel,
+                inner: self.inner.clone(),
+            },
+        }))
+    }
+}
+
+pub struct ReservedClient {
+    new_client: NewClient,
+    use_special_lan: bool,
+    dropper: Dropper,
+}
 
+impl ReservedClient {
+    pub async fn claim(self, settings: TcpSettings) -> Result<TcpClient, ClaimError> {
         let mut tunnel = TcpTunnel::new(
-            claim_instructions,
-            peer_addr
+            self.new_client.claim_instructions,
+            self.new_client.peer_addr,
         );
         tunnel.use_special_lan = self.use_special_lan;
+        tunnel.settings = settings;
 
-        let stream = tunnel.connect().await?;
+        let (stream, response) = tunnel.connect().await?;
+        tracing::debug!(?response, "claimed client");
 
-        Ok(Some(TcpClient {
+        Ok(TcpClient {
             stream,
-            dropper,
-        }))
+            dropper: self.dropper,
+        })
     }
 }
 
@@ -86,10 +196,15 @@ pub struct TcpClientRead {
 
 struct Dropper {
     key: (SocketAddr, SocketAddr),
+    tunnel: Option<MatchAddress>,
     inner: Arc<RwLock<Inner>>,
 }
 
 impl TcpClient {
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
     pub fn into_split(self) -> (TcpClientRead, TcpClientWrite) {
         let (read, write) = self.stream.into_split();
         let dropper = Arc::new(self.dropper);
@@ -151,11 +266,71 @@ impl AsyncRead for TcpClientRead {
 impl Drop for Dropper {
     fn drop(&mut self) {
         let key = self.key;
+        let tunnel = self.tunnel;
         let inner = self.inner.clone();
 
         tokio::spawn(async move {
             let mut lock = inner.write().await;
             lock.active.remove(&key);
+
+            if let Some(count) = tunnel.and_then(|tunnel| lock.tunnels.get_mut(&tunnel)) {
+                count.current -= 1;
+            }
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn new_client(peer: &str) -> NewClient {
+        NewClient {
+            connect_addr: "147.185.221.1:5000".parse().unwrap(),
+            peer_addr: peer.parse().unwrap(),
+            claim_instructions: ClaimInstructions {
+                address: "127.0.0.1:1".parse().unwrap(),
+                token: vec![],
+            },
+            tunnel_server_id: 1,
+            data_center_id: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let tunnel = MatchAddress { ip: Ipv6Addr::UNSPECIFIED, from_port: 5000, to_port: 5001 };
+        let other = MatchAddress { from_port: 6000, to_port: 6001, ..tunnel };
+
+        let mut clients = TcpClients::new();
+        clients.max_connections = Some(3);
+
+        let a = clients.reserve(new_client("1.1.1.1:1"), Some(tunnel), Some(2)).await.unwrap().unwrap();
+        let _b = clients.reserve(new_client("1.1.1.1:2"), Some(tunnel), Some(2)).await.unwrap().unwrap();
+
+        /* same client twice is not counted again */
+        assert!(clients.reserve(new_client("1.1.1.1:2"), Some(tunnel), Some(2)).await.unwrap().is_none());
+
+        assert_eq!(clients.reserve(new_client("1.1.1.1:3"), Some(tunnel), Some(2)).await.err(), Some(ConnectionLimit::Tunnel));
+        let _c = clients.reserve(new_client("1.1.1.1:4"), Some(other), None).await.unwrap().unwrap();
+        assert_eq!(clients.reserve(new_client("1.1.1.1:5"), Some(other), None).await.err(), Some(ConnectionLimit::Global));
+
+        /* the reservation is released by a spawned task */
+        drop(a);
+        let usage = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                let usage = clients.usage().await;
+                if usage.current == 2 {
+                    return usage;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.unwrap();
+
+        assert_eq!((usage.current, usage.peak, usage.refused), (2, 3, 2));
+        assert_eq!(usage.tunnels[0], TunnelUsage { tunnel_ip: Ipv6Addr::UNSPECIFIED, tunnel_port: 5000, current: 1, peak: 2 });
+        assert!(clients.reserve(new_client("1.1.1.1:3"), Some(tunnel), Some(2)).await.unwrap().is_some());
+    }
+}
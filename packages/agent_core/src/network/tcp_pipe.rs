@@ -1,32 +1,96 @@
-use std::sync::Arc;
-use std::sync::atomic::Ordering;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
-use crate::network::tcp_clients::TcpClient;
+use crate::network::bandwidth::{ClientShapers, Shaper};
 use crate::utils::now_milli;
 
-pub async fn pipe<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipeSummary {
+    pub tunnel_to_local: u64,
+    pub local_to_tunnel: u64,
+    pub duration: Duration,
+    pub close_reason: PipeCloseReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeCloseReason {
+    /* both sides sent EOF */
+    Eof,
+    IdleTimeout,
+    Error(std::io::ErrorKind),
+}
+
+/// Relays data between the tunnel and local connection until both directions
+/// have reached EOF. EOF on one side is forwarded as a write shutdown to the
+/// other so half-closed connections behave as they would without the tunnel.
+/// An error or idle timeout in either direction closes both connections.
+pub async fn pipe_bidirectional<T, L>(tunnel: T, local: L, idle_timeout: Duration) -> PipeSummary
+    where T: AsyncRead + AsyncWrite, L: AsyncRead + AsyncWrite
+{
+    pipe_bidirectional_shaped(tunnel, local, idle_timeout, &ClientShapers::default()).await
+}
+
+/// Same as [`pipe_bidirectional`] with each direction held to its bandwidth limits.
+pub async fn pipe_bidirectional_shaped<T, L>(tunnel: T, local: L, idle_timeout: Duration, shapers: &ClientShapers) -> PipeSummary
+    where T: AsyncRead + AsyncWrite, L: AsyncRead + AsyncWrite
+{
+    let start = Instant::now();
+
+    let (tunnel_read, tunnel_write) = tokio::io::split(tunnel);
+    let (local_read, local_write) = tokio::io::split(local);
+
+    let last_activity = AtomicU64::new(now_milli());
+    let tunnel_to_local = AtomicU64::new(0);
+    let local_to_tunnel = AtomicU64::new(0);
+
+    let res = tokio::try_join!(
+        pipe(tunnel_read, local_write, &tunnel_to_local, &last_activity, idle_timeout, &shapers.download),
+        pipe(local_read, tunnel_write, &local_to_tunnel, &last_activity, idle_timeout, &shapers.upload),
+    );
+
+    let close_reason = match res {
+        Ok(_) => PipeCloseReason::Eof,
+        Err(error) if error.kind() == std::io::ErrorKind::TimedOut => PipeCloseReason::IdleTimeout,
+        Err(error) => PipeCloseReason::Error(error.kind()),
+    };
+
+    PipeSummary {
+        tunnel_to_local: tunnel_to_local.load(Ordering::Relaxed),
+        local_to_tunnel: local_to_tunnel.load(Ordering::Relaxed),
+        duration: start.elapsed(),
+        close_reason,
+    }
+}
+
+async fn pipe<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
     mut from: R,
     mut to: W,
+    transferred: &AtomicU64,
+    last_activity: &AtomicU64,
+    idle_timeout: Duration,
+    shaper: &Shaper,
 ) -> std::io::Result<()> {
-    let mut buffer = Vec::new();
-    buffer.resize(2048, 0u8);
+    let mut buffer = vec![0u8; 2048];
 
     loop {
-        tokio::task::yield_now().await;
-
-        let received = match tokio::time::timeout(Duration::from_secs(200), from.read(&mut buffer[..])).await {
-            Ok(Ok(received)) => {
-                received
-            }
+        let received = match tokio::time::timeout(idle_timeout, from.read(&mut buffer[..])).await {
+            Ok(Ok(received)) => received,
             Ok(Err(error)) => {
                 tracing::error!(?error, "failed to read data");
                 return Err(error);
             }
-            Err(_) => break,
+            Err(_) => {
+                /* other direction may still be active */
+                let idle_ms = now_milli().saturating_sub(last_activity.load(Ordering::Relaxed));
+                if idle_ms < idle_timeout.as_millis() as u64 {
+                    continue;
+                }
+
+                tracing::info!("pipe ended due to idle timeout");
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "pipe idle timeout"));
+            }
         };
 
         if received == 0 {
@@ -34,11 +98,79 @@ pub async fn pipe<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
             break;
         }
 
+        last_activity.store(now_milli(), Ordering::Relaxed);
+        transferred.fetch_add(received as u64, Ordering::Relaxed);
+        shaper.consume(received).await;
+
         to.write_all(&buffer[..received]).await.map_err(|error| {
             tracing::error!(?error, "failed to write data");
             error
         })?;
     }
 
-    Ok(())
+    /* propagate FIN so the other side sees the half close */
+    to.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_half_close_propagates() {
+        let (tunnel, mut tunnel_remote) = duplex(64);
+        let (local, mut local_remote) = duplex(64);
+
+        let task = tokio::spawn(pipe_bidirectional(tunnel, local, Duration::from_secs(200)));
+
+        tunnel_remote.write_all(b"hello").await.unwrap();
+        tunnel_remote.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        local_remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+
+        /* other direction still open after half close */
+        local_remote.write_all(b"world!").await.unwrap();
+        local_remote.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        tunnel_remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"world!");
+
+        let summary = task.await.unwrap();
+        assert_eq!(summary.tunnel_to_local, 5);
+        assert_eq!(summary.local_to_tunnel, 6);
+        assert_eq!(summary.close_reason, PipeCloseReason::Eof);
+    }
+
+    #[tokio::test]
+    async fn test_error_closes_both_sides() {
+        let (tunnel, mut tunnel_remote) = duplex(64);
+        let (local, local_remote) = duplex(64);
+
+        let task = tokio::spawn(pipe_bidirectional(tunnel, local, Duration::from_secs(200)));
+        drop(local_remote);
+
+        /* write fails once local is gone, pipe must still release the tunnel side */
+        let _ = tunnel_remote.write_all(b"data").await;
+
+        let mut received = Vec::new();
+        tunnel_remote.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+
+        let summary = tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert_ne!(summary.close_reason, PipeCloseReason::IdleTimeout);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (tunnel, _tunnel_remote) = duplex(64);
+        let (local, _local_remote) = duplex(64);
+
+        let summary = pipe_bidirectional(tunnel, local, Duration::from_millis(100)).await;
+        assert_eq!(summary.close_reason, PipeCloseReason::IdleTimeout);
+    }
 }
@@ -0,0 +1,235 @@
+use std::io::{Error, ErrorKind};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::network::local_target::LocalTarget;
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST: u8 = 0x00;
+
+/* large enough for post quantum key shares, small enough to not buffer abuse */
+const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+
+/// Server names to local targets for tunnels carrying TLS. Names starting
+/// with `*.` match any subdomain, exact names are preferred over them.
+#[derive(Debug, Clone, Default)]
+pub struct SniRoutes {
+    routes: Vec<(String, LocalTarget)>,
+}
+
+impl SniRoutes {
+    pub fn add(&mut self, server_name: &str, target: LocalTarget) {
+        self.routes.push((server_name.trim_end_matches('.').to_ascii_lowercase(), target));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn target(&self, server_name: &str) -> Option<LocalTarget> {
+        let server_name = server_name.trim_end_matches('.').to_ascii_lowercase();
+
+        let exact = self.routes.iter().find(|(name, _)| *name == server_name);
+        let wildcard = || self.routes.iter().find(|(name, _)| {
+            matches!(name.strip_prefix('*'), Some(suffix) if suffix.starts_with('.') && server_name.ends_with(suffix))
+        });
+
+        exact.or_else(wildcard).map(|(_, target)| target.clone())
+    }
+}
+
+/// Start of a TLS connection read to find the server name, the consumed
+/// bytes have to be sent to the local server before piping the rest.
+#[derive(Debug)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub consumed: Vec<u8>,
+}
+
+/// Reads records until the whole ClientHello is buffered, TLS is not terminated.
+pub async fn read_client_hello<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<ClientHello> {
+    let mut consumed = Vec::new();
+    let mut handshake = Vec::new();
+
+    /* a ClientHello may be split over several records */
+    loop {
+        let mut header = [0u8; 5];
+        stream.read_exact(&mut header).await?;
+        consumed.extend_from_slice(&header);
+
+        if header[0] != RECORD_HANDSHAKE {
+            return Err(invalid_data("not a tls handshake"));
+        }
+
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if MAX_CLIENT_HELLO_LEN < handshake.len() + len {
+            return Err(invalid_data("client hello too large"));
+        }
+
+        let start = consumed.len();
+        consumed.resize(start + len, 0);
+        stream.read_exact(&mut consumed[start..]).await?;
+        handshake.extend_from_slice(&consumed[start..]);
+
+        if 4 <= handshake.len() {
+            if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(invalid_data("expected client hello"));
+            }
+
+            let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if 4 + hello_len <= handshake.len() {
+                let server_name = parse_server_name(&handshake[4..4 + hello_len])?;
+                return Ok(ClientHello { server_name, consumed });
+            }
+        }
+    }
+}
+
+fn parse_server_name(hello: &[u8]) -> std::io::Result<Option<String>> {
+    let mut reader = Reader(hello);
+
+    /* version and random */
+    reader.take(2 + 32)?;
+    let session_id_len = reader.u8()? as usize;
+    reader.take(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.take(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.take(compression_len)?;
+
+    /* hellos without extensions are allowed */
+    if reader.0.is_empty() {
+        return Ok(None);
+    }
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let data = extensions.take(extension_len)?;
+
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut list = Reader(data);
+        let list_len = list.u16()? as usize;
+        let mut names = Reader(list.take(list_len)?);
+
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name_len = names.u16()? as usize;
+            let name = names.take(name_len)?;
+
+            if name_type == NAME_TYPE_HOST {
+                let name = std::str::from_utf8(name).map_err(|_| invalid_data("server name is not utf8"))?;
+                return Ok(Some(name.to_ascii_lowercase()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> std::io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid_data("client hello too short"));
+        }
+
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> std::io::Result<u16> {
+        let value = self.take(2)?;
+        Ok(u16::from_be_bytes([value[0], value[1]]))
+    }
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let mut sni = Vec::new();
+        sni.extend_from_slice(&((server_name.len() + 3) as u16).to_be_bytes());
+        sni.push(NAME_TYPE_HOST);
+        sni.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(server_name.as_bytes());
+
+        let mut extensions = Vec::new();
+        /* an unrelated extension first, supported versions */
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7u8; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+        handshake
+    }
+
+    fn records(handshake: &[u8], record_len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for fragment in handshake.chunks(record_len) {
+            out.extend_from_slice(&[RECORD_HANDSHAKE, 0x03, 0x01]);
+            out.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            out.extend_from_slice(fragment);
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        for record_len in [16 * 1024, 20] {
+            let mut data = records(&client_hello("App.Example.com"), record_len);
+            let expected = data.clone();
+            data.extend_from_slice(b"next record");
+
+            let mut stream = &data[..];
+            let hello = read_client_hello(&mut stream).await.unwrap();
+
+            assert_eq!(hello.server_name.as_deref(), Some("app.example.com"));
+            assert_eq!(hello.consumed, expected);
+            assert_eq!(stream, b"next record");
+        }
+
+        let mut stream = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        assert_eq!(read_client_hello(&mut stream).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_sni_routes() {
+        let mut routes = SniRoutes::default();
+        routes.add("*.example.com", LocalTarget::Addr("127.0.0.1:1".parse().unwrap()));
+        routes.add("api.example.com", LocalTarget::Addr("127.0.0.1:2".parse().unwrap()));
+
+        assert_eq!(routes.target("API.example.com.").unwrap().to_string(), "127.0.0.1:2");
+        assert_eq!(routes.target("a.b.example.com").unwrap().to_string(), "127.0.0.1:1");
+        assert!(routes.target("example.com").is_none());
+        assert!(routes.target("badexample.com").is_none());
+    }
+}
@@ -0,0 +1,189 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::network::tcp_pipe::{PipeCloseReason, PipeSummary};
+use crate::tunnel::udp_proto::UdpFlow;
+use crate::tunnel::udp_tunnel::UdpTunnel;
+
+pub trait TunnelStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TunnelStream for T {}
+
+/// Serves tunneled clients in-process instead of forwarding them to a local socket.
+/// Methods are called from the agent's tasks so must not block, spawn a task for
+/// any long running work.
+pub trait TunnelHandler: Send + Sync + 'static {
+    /// Takes ownership of a claimed TCP connection.
+    fn handle_tcp(&self, stream: Box<dyn TunnelStream>, peer_addr: SocketAddr, tunnel_addr: SocketAddr) {
+        let _ = stream;
+        tracing::warn!(%peer_addr, %tunnel_addr, "tunnel handler does not accept tcp, closing connection");
+    }
+
+    /// Receives a datagram sent by `flow.src()` to the tunnel address `flow.dst()`.
+    fn handle_udp(&self, flow: UdpFlow, data: &[u8], reply: UdpReplySender) {
+        let _ = (data, reply);
+        tracing::warn!(?flow, "tunnel handler does not accept udp, dropping packet");
+    }
+}
+
+/// Stream given to [`TunnelHandler::handle_tcp`]. Replays what the agent
+/// already read from the client and reports a summary once the handler
+/// drops it.
+pub(crate) struct HandlerStream {
+    inner: Box<dyn TunnelStream>,
+    replay: Vec<u8>,
+    replayed: usize,
+    started: Instant,
+    tunnel_to_handler: u64,
+    handler_to_tunnel: u64,
+    error: Option<std::io::ErrorKind>,
+    on_close: Option<Box<dyn FnOnce(PipeSummary) + Send>>,
+}
+
+impl HandlerStream {
+    pub fn new<F: FnOnce(PipeSummary) + Send + 'static>(inner: Box<dyn TunnelStream>, replay: Vec<u8>, on_close: F) -> Self {
+        HandlerStream {
+            inner,
+            replay,
+            replayed: 0,
+            started: Instant::now(),
+            tunnel_to_handler: 0,
+            handler_to_tunnel: 0,
+            error: None,
+            on_close: Some(Box::new(on_close)),
+        }
+    }
+
+    fn record<T>(&mut self, res: &Poll<std::io::Result<T>>) {
+        if let Poll::Ready(Err(error)) = res {
+            self.error.get_or_insert(error.kind());
+        }
+    }
+}
+
+impl AsyncRead for HandlerStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        if self.replayed < self.replay.len() {
+            let len = buf.remaining().min(self.replay.len() - self.replayed);
+            buf.put_slice(&self.replay[self.replayed..self.replayed + len]);
+            self.replayed += len;
+            self.tunnel_to_handler += len as u64;
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.tunnel_to_handler += (buf.filled().len() - filled) as u64;
+        self.record(&res);
+        res
+    }
+}
+
+impl AsyncWrite for HandlerStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            self.handler_to_tunnel += written as u64;
+        }
+        self.record(&res);
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let res = Pin::new(&mut self.inner).poll_flush(cx);
+        self.record(&res);
+        res
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let res = Pin::new(&mut self.inner).poll_shutdown(cx);
+        self.record(&res);
+        res
+    }
+}
+
+impl Drop for HandlerStream {
+    fn drop(&mut self) {
+        if let Some(on_close) = self.on_close.take() {
+            on_close(PipeSummary {
+                tunnel_to_local: self.tunnel_to_handler,
+                local_to_tunnel: self.handler_to_tunnel,
+                duration: self.started.elapsed(),
+                close_reason: match self.error {
+                    Some(kind) => PipeCloseReason::Error(kind),
+                    None => PipeCloseReason::Eof,
+                },
+            });
+        }
+    }
+}
+
+/// Sends datagrams back to the client of a flow through the tunnel.
+#[derive(Clone)]
+pub struct UdpReplySender {
+    udp_tunnel: UdpTunnel,
+    reply_flow: UdpFlow,
+}
+
+impl UdpReplySender {
+    pub(crate) fn new(udp_tunnel: UdpTunnel, flow: UdpFlow) -> Self {
+        UdpReplySender {
+            udp_tunnel,
+            reply_flow: flow.flip(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.reply_flow.dst()
+    }
+
+    pub async fn send(&self, data: &[u8]) -> std::io::Result<usize> {
+        let mut buffer = Vec::with_capacity(data.len() + self.reply_flow.len());
+        buffer.extend_from_slice(data);
+        self.udp_tunnel.send(&mut buffer, self.reply_flow).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    struct EchoHandler;
+
+    impl TunnelHandler for EchoHandler {
+        fn handle_tcp(&self, stream: Box<dyn TunnelStream>, _peer_addr: SocketAddr, _tunnel_addr: SocketAddr) {
+            tokio::spawn(async move {
+                let (mut read, mut write) = tokio::io::split(stream);
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_handler_stream() {
+        let (mut client, agent) = tokio::io::duplex(64);
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+
+        /* "hel" was read by the agent before handing the client over */
+        let stream = HandlerStream::new(Box::new(agent), b"hel".to_vec(), move |summary| {
+            let _ = closed_tx.send(summary);
+        });
+        EchoHandler.handle_tcp(Box::new(stream), "1.2.3.4:5000".parse().unwrap(), "147.185.221.1:25565".parse().unwrap());
+
+        client.write_all(b"lo").await.unwrap();
+        let mut echoed = [0u8; 5];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+
+        /* the handler drops the stream once the client is done */
+        client.shutdown().await.unwrap();
+        let summary = tokio::time::timeout(std::time::Duration::from_secs(1), closed_rx).await.unwrap().unwrap();
+        assert_eq!((summary.tunnel_to_local, summary.local_to_tunnel, summary.close_reason), (5, 5, PipeCloseReason::Eof));
+    }
+}
@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+use crate::network::backend_pool::{HealthCheckSettings, HealthProbe};
+use crate::network::bandwidth::BandwidthSettings;
+use crate::network::geo_filter::GeoRules;
+use crate::network::http_routing::HttpSettings;
+use crate::network::minecraft::MinecraftSettings;
+use crate::network::raknet::RakNetSettings;
+use crate::network::tls_termination::TlsSettings;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TunnelSettings {
+    pub tcp: TcpSettings,
+    pub udp: UdpSettings,
+    pub health_check: HealthCheckSettings,
+    pub bandwidth: BandwidthSettings,
+    pub geo: GeoRules,
+    pub minecraft: MinecraftSettings,
+    pub raknet: RakNetSettings,
+    pub http: HttpSettings,
+    pub tls: TlsSettings,
+}
+
+impl TunnelSettings {
+    /// Configured health check, or a RakNet probe if an offline motd needs
+    /// one to know when the local server is down.
+    pub fn effective_health_check(&self) -> HealthCheckSettings {
+        if self.health_check.enabled || !self.raknet.enabled || self.raknet.offline_motd.is_none() {
+            return self.health_check.clone();
+        }
+
+        HealthCheckSettings { enabled: true, probe: HealthProbe::RakNet, ..self.health_check.clone() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct TcpSettings {
+    /* close connection after no data has been sent in either direction */
+    pub idle_timeout_secs: u64,
+    /* SO_KEEPALIVE idle time and probe interval, disabled if None */
+    pub keepalive_secs: Option<u64>,
+    pub nodelay: bool,
+    pub send_buffer_size: Option<u32>,
+    pub recv_buffer_size: Option<u32>,
+    /* leave new clients unclaimed while the local target fails health checks */
+    pub skip_claim_when_down: bool,
+    /* only claim clients once the local server accepted a connection */
+    pub connect_before_claim: bool,
+    pub local_connect_timeout_secs: u64,
+    /* per claim attempt, attempts are retried if the claim server could not be reached */
+    pub claim_timeout_secs: u64,
+    pub claim_retries: u32,
+    /* clients over the limit are left unclaimed */
+    pub max_connections: Option<usize>,
+    /* time a client has to send its TLS ClientHello on tunnels routed by server name */
+    pub client_hello_timeout_secs: u64,
+}
+
+impl Default for TcpSettings {
+    fn default() -> Self {
+        TcpSettings {
+            idle_timeout_secs: 200,
+            keepalive_secs: None,
+            nodelay: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            skip_claim_when_down: false,
+            connect_before_claim: false,
+            local_connect_timeout_secs: 5,
+            claim_timeout_secs: 10,
+            claim_retries: 2,
+            max_connections: None,
+            client_hello_timeout_secs: 10,
+        }
+    }
+}
+
+impl TcpSettings {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs.max(1))
+    }
+
+    pub fn local_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.local_connect_timeout_secs.max(1))
+    }
+
+    pub fn claim_timeout(&self) -> Duration {
+        Duration::from_secs(self.claim_timeout_secs.max(1))
+    }
+
+    pub fn client_hello_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_hello_timeout_secs.max(1))
+    }
+
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(stream);
+
+        if let Some(secs) = self.keepalive_secs {
+            let interval = Duration::from_secs(secs.max(1));
+            let keepalive = TcpKeepalive::new().with_time(interval);
+
+            #[cfg(any(target_os = "linux", target_vendor = "apple", windows))]
+            let keepalive = keepalive.with_interval(interval);
+
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size as usize)?;
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size as usize)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct UdpSettings {
+    /* remove flow after not receiving data from the tunnel */
+    pub idle_timeout_secs: u64,
+    /* when reached, the least recently active flow is evicted */
+    pub max_flows: Option<usize>,
+    pub max_flows_per_ip: Option<usize>,
+}
+
+impl Default for UdpSettings {
+    fn default() -> Self {
+        UdpSettings {
+            idle_timeout_secs: 120,
+            max_flows: None,
+            max_flows_per_ip: None,
+        }
+    }
+}
+
+impl UdpSettings {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs.max(1))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_tcp_settings_defaults() {
+        assert_eq!(toml::from_str::<TunnelSettings>("").unwrap(), TunnelSettings::default());
+
+        /* missing fields keep their defaults */
+        let settings: TunnelSettings = toml::from_str("[tcp]\nnodelay = true\nkeepalive_secs = 30\n").unwrap();
+        assert_eq!(settings.tcp, TcpSettings { nodelay: true, keepalive_secs: Some(30), ..TcpSettings::default() });
+        assert_eq!(settings.tcp.idle_timeout(), Duration::from_secs(200));
+        assert_eq!(settings.udp, UdpSettings::default());
+    }
+
+    #[test]
+    fn test_offline_motd_enables_raknet_probe() {
+        let mut settings = TunnelSettings::default();
+        settings.raknet.offline_motd = Some("back soon".to_string());
+        assert!(!settings.effective_health_check().enabled);
+
+        settings.raknet.enabled = true;
+        let health_check = settings.effective_health_check();
+        assert!(health_check.enabled);
+        assert_eq!(health_check.probe, HealthProbe::RakNet);
+
+        /* a configured check is kept */
+        settings.health_check.enabled = true;
+        assert_eq!(settings.effective_health_check(), settings.health_check);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_settings_apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        let settings = TcpSettings { nodelay: true, keepalive_secs: Some(30), ..TcpSettings::default() };
+        settings.apply(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(stream.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(30));
+
+        TcpSettings::default().apply(&stream).unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+}
@@ -1,26 +1,203 @@
 use std::collections::{hash_map::Entry, HashMap};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::fmt::{Display, Formatter};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use byteorder::{BigEndian, ByteOrder};
-use hyper::Client;
 use playit_agent_proto::PortProto;
 use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
 
+use crate::events::{AgentEvent, AgentEvents};
 use crate::network::address_lookup::{AddressLookup, MatchAddress};
+use crate::network::geo_filter::{GeoDenied, GeoFilter};
 use crate::network::lan_address::LanAddress;
+use crate::network::backend_pool::BackendGuard;
+use crate::network::bandwidth::{ClientShapers, TunnelBandwidth};
+use crate::network::local_health::LocalHealth;
+use crate::network::local_target::LocalTarget;
+use crate::network::packet_filter::{FilterHook, FilterRejected, FilterRequest};
+use crate::network::raknet;
+use crate::network::rate_limit::{ConnectionRateLimiter, RateLimited};
+use crate::network::tunnel_handler::UdpReplySender;
+use crate::network::tunnel_settings::UdpSettings;
+use crate::tunnel::udp_batch::{UDP_SLOT_SIZE, UdpBatch};
 use crate::tunnel::udp_proto::UdpFlow;
 use crate::tunnel::udp_tunnel::UdpTunnel;
+use crate::utils::log_throttle::LogThrottle;
 use crate::utils::now_milli;
 
 pub struct UdpClients<L: AddressLookup> {
     udp_tunnel: UdpTunnel,
     lookup: L,
     udp_clients: Arc<RwLock<HashMap<ClientKey, Arc<UdpClient>>>>,
+    forward_errors: Arc<ForwardErrorStats>,
     pub use_special_lan: bool,
+    pub events: AgentEvents,
+    pub rate_limiter: Arc<ConnectionRateLimiter>,
+    pub bandwidth: Arc<TunnelBandwidth>,
+    pub geo_filter: Arc<GeoFilter>,
+    pub local_health: Arc<LocalHealth>,
+    pub filter: Arc<FilterHook>,
+    /* packets of new flows that arrived while the packet filter decides on the first */
+    filter_pending: Mutex<HashMap<ClientKey, Vec<PendingPacket>>>,
+    /* handlers keep their own flow state, this only tells which packets open a new flow */
+    handler_flows: Mutex<HashMap<ClientKey, HandlerFlow>>,
+}
+
+type PendingPacket = (UdpFlow, Vec<u8>);
+
+struct HandlerFlow {
+    last_activity: u64,
+    idle_timeout_ms: u64,
+}
+
+/* expired handler flows are dropped once the table grows past this,
+   then the least recently active until it is back under by an eighth */
+const MAX_HANDLER_FLOWS: usize = 4096;
+const EVICT_TO_HANDLER_FLOWS: usize = MAX_HANDLER_FLOWS - MAX_HANDLER_FLOWS / 8;
+
+/* packets held per new flow while the packet filter runs */
+const FILTER_PENDING_PACKETS: usize = 16;
+
+/// Read only view of the flow table and error counters that can be kept
+/// after the clients have been moved into the running agent.
+#[derive(Clone)]
+pub struct UdpClientStats {
+    udp_clients: Arc<RwLock<HashMap<ClientKey, Arc<UdpClient>>>>,
+    forward_errors: Arc<ForwardErrorStats>,
+}
+
+impl UdpClientStats {
+    pub async fn flow_count(&self) -> usize {
+        self.udp_clients.read().await.len()
+    }
+
+    pub fn forward_errors(&self) -> UdpForwardErrorCounts {
+        let stats = &self.forward_errors;
+
+        UdpForwardErrorCounts {
+            no_mapping: stats.no_mapping.count.load(Ordering::Relaxed),
+            unsupported_target: stats.unsupported_target.count.load(Ordering::Relaxed),
+            local_bind_failed: stats.local_bind_failed.count.load(Ordering::Relaxed),
+            send_failed: stats.send_failed.count.load(Ordering::Relaxed),
+            rate_limited: stats.rate_limited.count.load(Ordering::Relaxed),
+            bandwidth_limited: stats.bandwidth_limited.count.load(Ordering::Relaxed),
+            geo_denied: stats.geo_denied.count.load(Ordering::Relaxed),
+            filtered: stats.filtered.count.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Connected RakNet sessions grouped by tunnel address and local server.
+    pub async fn raknet_sessions(&self) -> Vec<RakNetSessionReport> {
+        let mut sessions = HashMap::<(SocketAddr, SocketAddr), usize>::new();
+
+        for client in self.udp_clients.read().await.values() {
+            if client.raknet_connected.load(Ordering::Relaxed) {
+                *sessions.entry((client.client_key.tunnel_addr, client.local_start_addr)).or_default() += 1;
+            }
+        }
+
+        let mut report = sessions.into_iter()
+            .map(|((tunnel_addr, local_addr), sessions)| RakNetSessionReport {
+                tunnel_addr,
+                local_target: local_addr.to_string(),
+                sessions,
+            })
+            .collect::<Vec<_>>();
+
+        report.sort_by_key(|server| server.tunnel_addr);
+        report
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RakNetSessionReport {
+    pub tunnel_addr: SocketAddr,
+    pub local_target: String,
+    pub sessions: usize,
+}
+
+#[derive(Debug)]
+pub enum UdpForwardError {
+    /* no tunnel or local address matches the packet's destination */
+    NoMapping,
+    /* the tunnel's local target only accepts TCP, such as a unix socket */
+    UnsupportedTarget,
+    LocalBindFailed(std::io::Error),
+    SendFailed(std::io::Error),
+    /* packet would have opened a new flow */
+    RateLimited(RateLimited),
+    GeoDenied(GeoDenied),
+    /* rejected by the agent's packet filter */
+    Filtered(FilterRejected),
+    /* dropped for being over the flow or tunnel's download limit */
+    BandwidthLimited,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpFlowSummary {
+    pub tunnel_to_local: u64,
+    pub local_to_tunnel: u64,
+    pub duration: Duration,
+    pub close_reason: UdpCloseReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdpCloseReason {
+    IdleTimeout,
+    /* removed to stay within the tunnel's flow limits */
+    Evicted,
+    /* RakNet disconnection notification from the player or server */
+    Disconnected,
+    Error(std::io::ErrorKind),
+}
+
+impl Display for UdpForwardError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for UdpForwardError {
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpForwardErrorCounts {
+    pub no_mapping: u64,
+    pub unsupported_target: u64,
+    pub local_bind_failed: u64,
+    pub send_failed: u64,
+    pub rate_limited: u64,
+    pub bandwidth_limited: u64,
+    pub geo_denied: u64,
+    pub filtered: u64,
+}
+
+struct ForwardErrorStats {
+    no_mapping: ErrorCounter,
+    unsupported_target: ErrorCounter,
+    local_bind_failed: ErrorCounter,
+    send_failed: ErrorCounter,
+    rate_limited: ErrorCounter,
+    bandwidth_limited: ErrorCounter,
+    geo_denied: ErrorCounter,
+    filtered: ErrorCounter,
+}
+
+struct ErrorCounter {
+    count: AtomicU64,
+    log: LogThrottle,
+}
+
+impl ErrorCounter {
+    fn new() -> Self {
+        ErrorCounter {
+            count: AtomicU64::new(0),
+            log: LogThrottle::new(Duration::from_secs(5)),
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Debug, Clone)]
@@ -35,7 +212,55 @@ impl<L: AddressLookup> UdpClients<L> {
             udp_tunnel: tunnel,
             lookup,
             udp_clients: Default::default(),
+            forward_errors: Arc::new(ForwardErrorStats {
+                no_mapping: ErrorCounter::new(),
+                unsupported_target: ErrorCounter::new(),
+                local_bind_failed: ErrorCounter::new(),
+                send_failed: ErrorCounter::new(),
+                rate_limited: ErrorCounter::new(),
+                bandwidth_limited: ErrorCounter::new(),
+                geo_denied: ErrorCounter::new(),
+                filtered: ErrorCounter::new(),
+            }),
             use_special_lan: true,
+            events: AgentEvents::default(),
+            rate_limiter: Arc::new(ConnectionRateLimiter::default()),
+            bandwidth: Arc::new(TunnelBandwidth::default()),
+            geo_filter: Arc::new(GeoFilter::default()),
+            local_health: Arc::new(LocalHealth::new(AgentEvents::default())),
+            filter: Arc::new(FilterHook::default()),
+            filter_pending: Mutex::new(HashMap::new()),
+            handler_flows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn stats(&self) -> UdpClientStats {
+        UdpClientStats {
+            udp_clients: self.udp_clients.clone(),
+            forward_errors: self.forward_errors.clone(),
+        }
+    }
+
+    pub fn forward_errors(&self) -> UdpForwardErrorCounts {
+        self.stats().forward_errors()
+    }
+
+    fn record_error(&self, flow: &UdpFlow, error: &UdpForwardError) {
+        let counter = match error {
+            UdpForwardError::NoMapping => &self.forward_errors.no_mapping,
+            UdpForwardError::UnsupportedTarget => &self.forward_errors.unsupported_target,
+            UdpForwardError::LocalBindFailed(_) => &self.forward_errors.local_bind_failed,
+            UdpForwardError::SendFailed(_) => &self.forward_errors.send_failed,
+            UdpForwardError::RateLimited(_) => &self.forward_errors.rate_limited,
+            UdpForwardError::BandwidthLimited => &self.forward_errors.bandwidth_limited,
+            UdpForwardError::GeoDenied(_) => &self.forward_errors.geo_denied,
+            UdpForwardError::Filtered(_) => &self.forward_errors.filtered,
+        };
+
+        let total = counter.count.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if let Some(suppressed) = counter.log.allow() {
+            tracing::warn!(?error, ?flow, total, suppressed, "failed to forward udp packet from tunnel");
         }
     }
 
@@ -44,47 +269,108 @@ impl<L: AddressLookup> UdpClients<L> {
         clients_lock.len()
     }
 
-    pub async fn forward_packet(&self, flow: &UdpFlow, data: &[u8]) -> std::io::Result<usize> {
+    fn client_key(&self, flow: &UdpFlow) -> Option<(MatchAddress, ClientKey)> {
         let flow_dst = flow.dst();
-        let match_addr = match self.lookup.tunnel_match_address(flow_dst, PortProto::Udp) {
-            Some(v) => v,
-            None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::ConnectionRefused,
-                    "could not find tunnel",
-                ))
-            }
-        };
+        let match_addr = self.lookup.tunnel_match_address(flow_dst, PortProto::Udp)?;
 
         /* normalize port */
         let key = ClientKey {
             client_addr: flow.src(),
-            tunnel_addr: SocketAddr::new(flow.dst().ip(), match_addr.from_port),
+            tunnel_addr: SocketAddr::new(flow_dst.ip(), match_addr.from_port),
         };
 
+        Some((match_addr, key))
+    }
+
+    /* None unless data is an unconnected ping for a tunnel with an offline motd whose target is down */
+    async fn answer_offline_ping(&self, flow: &UdpFlow, match_addr: MatchAddress, data: &[u8]) -> Option<Result<usize, UdpForwardError>> {
+        let ping_time = raknet::parse_unconnected_ping(data)?;
+        let raknet = self.lookup.tunnel_settings(match_addr, PortProto::Udp).raknet;
+        let motd = raknet.offline_motd.filter(|_| raknet.enabled)?;
+
+        if !self.local_health.is_down(match_addr) {
+            return None;
+        }
+
+        let pong = raknet::offline_pong(ping_time, &motd, flow.dst().port());
+        let res = UdpReplySender::new(self.udp_tunnel.clone(), *flow).send(&pong).await;
+        Some(res.map(|_| data.len()).map_err(UdpForwardError::SendFailed))
+    }
+
+    pub async fn forward_packet(&self, flow: &UdpFlow, data: &[u8]) -> Result<usize, UdpForwardError> {
+        let res = self.try_forward_packet(flow, data).await;
+        if let Err(error) = &res {
+            self.record_error(flow, error);
+        }
+        res
+    }
+
+    async fn try_forward_packet(&self, flow: &UdpFlow, data: &[u8]) -> Result<usize, UdpForwardError> {
+        let flow_dst = flow.dst();
+        let (match_addr, key) = self.client_key(flow).ok_or(UdpForwardError::NoMapping)?;
+
         {
             let clients = self.udp_clients.read().await;
 
             if let Some(client) = clients.get(&key) {
+                /* flows opened before the backend went down still get the offline pong */
+                if let Some(res) = self.answer_offline_ping(flow, match_addr, data).await {
+                    return res;
+                }
                 return client.send_local(flow_dst.port(), data).await;
             }
         }
 
+        let target = self.lookup.local_target(match_addr, PortProto::Udp).ok_or(UdpForwardError::NoMapping)?;
+
+        if let LocalTarget::Handler(handler) = &target {
+            if self.touch_handler_flow(&key) {
+                handler.handle_udp(*flow, data, UdpReplySender::new(self.udp_tunnel.clone(), *flow));
+                return Ok(data.len());
+            }
+        }
+
+        /* before dispatching so handler tunnels get the new flow checks too */
+        self.geo_filter.check(flow.src().ip(), &self.lookup.tunnel_settings(match_addr, PortProto::Udp).geo).map_err(UdpForwardError::GeoDenied)?;
+        self.rate_limiter.check(flow.src().ip()).map_err(UdpForwardError::RateLimited)?;
+
+        let request = FilterRequest { proto: PortProto::Udp, peer_addr: flow.src(), tunnel_addr: flow_dst };
+        let route = self.filter.check(&request, data).await.map_err(UdpForwardError::Filtered)?;
+
+        /* the filter's route wins over the tunnel's target */
+        let (local_addr, backend) = match (route, target) {
+            (Some(route), _) => (route, None),
+            (None, LocalTarget::Addr(addr)) => (addr, None),
+            #[cfg(unix)]
+            (None, LocalTarget::Unix(_)) => return Err(UdpForwardError::UnsupportedTarget),
+            (None, LocalTarget::Handler(handler)) => {
+                self.open_handler_flow(key, self.lookup.tunnel_settings(match_addr, PortProto::Udp).udp.idle_timeout());
+                handler.handle_udp(*flow, data, UdpReplySender::new(self.udp_tunnel.clone(), *flow));
+                return Ok(data.len());
+            }
+            (None, LocalTarget::Pool { pool, .. }) => {
+                let backend = pool.select(flow.src()).ok_or(UdpForwardError::NoMapping)?;
+                (backend.addr(), Some(backend))
+            }
+        };
+
+        /* answer for the backend instead of opening a flow that gets no reply */
+        if let Some(res) = self.answer_offline_ping(flow, match_addr, data).await {
+            return res;
+        }
+
         {
             let mut clients = self.udp_clients.write().await;
+            let tunnel_settings = self.lookup.tunnel_settings(match_addr, PortProto::Udp);
+            let settings = tunnel_settings.udp;
+
+            if !clients.contains_key(&key) {
+                Self::make_room(&mut clients, &key, &settings);
+            }
+
             let client = match clients.entry(key) {
                 Entry::Occupied(o) => o.into_mut(),
                 Entry::Vacant(v) => {
-                    let local_addr = match self.lookup.local_address(match_addr, PortProto::Udp) {
-                        Some(v) => v,
-                        None => {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::ConnectionRefused,
-                                "could not find tunnel",
-                            ))
-                        }
-                    };
-
                     let (send_flow, client_addr) = match flow {
                         UdpFlow::V4 { src, dst } => (
                             UdpFlow::V4 {
@@ -109,13 +395,31 @@ impl<L: AddressLookup> UdpClients<L> {
                     let client = Arc::new(UdpClient {
                         client_key,
                         send_flow,
-                        local_udp: LanAddress::udp_socket(self.use_special_lan, client_addr, local_addr).await?,
+                        local_udp: LanAddress::udp_socket(self.use_special_lan, client_addr, local_addr).await
+                            .map_err(UdpForwardError::LocalBindFailed)?,
                         udp_tunnel: self.udp_tunnel.clone(),
                         local_start_addr: local_addr,
                         tunnel_from_port: match_addr.from_port,
                         tunnel_to_port: match_addr.to_port,
                         udp_clients: self.udp_clients.clone(),
-                        last_activity: Default::default()
+                        last_activity: AtomicU64::new(now_milli()),
+                        started_at: now_milli(),
+                        tunnel_to_local: AtomicU64::new(0),
+                        local_to_tunnel: AtomicU64::new(0),
+                        idle_timeout: settings.idle_timeout(),
+                        evicted: Notify::new(),
+                        raknet: tunnel_settings.raknet.enabled,
+                        raknet_connected: AtomicBool::new(false),
+                        disconnected: AtomicBool::new(false),
+                        events: self.events.clone(),
+                        _backend: backend,
+                        shapers: self.bandwidth.client_shapers(match_addr, &self.lookup.tunnel_settings(match_addr, PortProto::Udp).bandwidth),
+                    });
+
+                    self.events.emit(AgentEvent::UdpFlowOpened {
+                        peer_addr: client.client_key.client_addr,
+                        tunnel_addr: client.client_key.tunnel_addr,
+                        local_target: local_addr.to_string(),
                     });
 
                     tokio::spawn(HostToTunnelForwarder(client.clone()).run());
@@ -126,6 +430,156 @@ impl<L: AddressLookup> UdpClients<L> {
             client.send_local(flow_dst.port(), data).await
         }
     }
+
+    /* true if the handler flow has seen a packet within its idle timeout */
+    fn touch_handler_flow(&self, key: &ClientKey) -> bool {
+        let now = now_milli();
+
+        match self.handler_flows.lock().unwrap().get_mut(key) {
+            Some(flow) if now.saturating_sub(flow.last_activity) < flow.idle_timeout_ms => {
+                flow.last_activity = now;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn open_handler_flow(&self, key: ClientKey, idle_timeout: Duration) {
+        let now = now_milli();
+        let mut flows = self.handler_flows.lock().unwrap();
+
+        if MAX_HANDLER_FLOWS <= flows.len() && !flows.contains_key(&key) {
+            flows.retain(|_, flow| now.saturating_sub(flow.last_activity) < flow.idle_timeout_ms);
+
+            if MAX_HANDLER_FLOWS <= flows.len() {
+                let mut oldest = flows.iter()
+                    .map(|(key, flow)| (flow.last_activity, key.clone()))
+                    .collect::<Vec<_>>();
+
+                let evict = flows.len() - EVICT_TO_HANDLER_FLOWS;
+                oldest.select_nth_unstable_by_key(evict - 1, |(last_activity, _)| *last_activity);
+
+                for (_, key) in &oldest[..evict] {
+                    flows.remove(key);
+                }
+            }
+        }
+
+        flows.insert(key, HandlerFlow { last_activity: now, idle_timeout_ms: idle_timeout.as_millis() as u64 });
+    }
+
+    /* evict least recently active flows so the new flow fits within the tunnel's limits */
+    fn make_room(clients: &mut HashMap<ClientKey, Arc<UdpClient>>, key: &ClientKey, settings: &UdpSettings) {
+        if let Some(max_flows) = settings.max_flows_per_ip {
+            Self::evict_lru(clients, max_flows, |other| {
+                other.tunnel_addr == key.tunnel_addr && other.client_addr.ip() == key.client_addr.ip()
+            });
+        }
+
+        if let Some(max_flows) = settings.max_flows {
+            Self::evict_lru(clients, max_flows, |other| other.tunnel_addr == key.tunnel_addr);
+        }
+    }
+
+    /* evicts down to an eighth below the limit, so a full tunnel isn't scanned for every new flow */
+    fn evict_lru<F: Fn(&ClientKey) -> bool>(clients: &mut HashMap<ClientKey, Arc<UdpClient>>, max_flows: usize, matches: F) {
+        let max_flows = max_flows.max(1);
+        if clients.len() < max_flows {
+            return;
+        }
+
+        let mut oldest = clients.iter()
+            .filter(|(key, _)| matches(key))
+            .map(|(key, client)| (client.last_activity.load(Ordering::Relaxed), key.clone()))
+            .collect::<Vec<_>>();
+
+        /* leave space for the new flow */
+        if oldest.len() < max_flows {
+            return;
+        }
+
+        let keep = (max_flows - max_flows / 8).min(max_flows - 1);
+        let evict = oldest.len() - keep;
+        oldest.select_nth_unstable_by_key(evict - 1, |(last_activity, _)| *last_activity);
+
+        for (_, key) in &oldest[..evict] {
+            if let Some(client) = clients.remove(key) {
+                tracing::info!(client_key = ?key, max_flows, "evicting udp client to stay within flow limit");
+                client.evicted.notify_one();
+            }
+        }
+    }
+}
+
+impl<L: AddressLookup + Send + Sync> UdpClients<L> {
+    /// Forwards packets received together from the tunnel, existing clients
+    /// are found under a single lock for the whole batch. Packets that fail
+    /// are counted and dropped without affecting the rest of the batch. New
+    /// flows are handed to a task when a packet filter is set.
+    pub async fn forward_packets(self: &Arc<Self>, packets: &[(UdpFlow, &[u8])]) {
+        let mut existing = Vec::with_capacity(packets.len());
+        {
+            let clients = self.udp_clients.read().await;
+            for (flow, _) in packets {
+                existing.push(self.client_key(flow).and_then(|(_, key)| clients.get(&key).cloned()));
+            }
+        }
+
+        for ((flow, data), client) in packets.iter().zip(existing) {
+            match client {
+                /* may be answered with the offline pong */
+                Some(_) if raknet::parse_unconnected_ping(data).is_some() => {
+                    let _ = self.forward_packet(flow, data).await;
+                }
+                Some(client) => {
+                    if let Err(error) = client.send_local(flow.dst().port(), data).await {
+                        self.record_error(flow, &error);
+                    }
+                }
+                None => self.forward_new_flow(flow, data).await,
+            }
+        }
+    }
+
+    /* the filter runs off the receive loop, later packets of the flow wait for its verdict */
+    async fn forward_new_flow(self: &Arc<Self>, flow: &UdpFlow, data: &[u8]) {
+        let key = match self.client_key(flow) {
+            /* handler flows the filter already accepted are sent on inline */
+            Some((_, key)) if self.filter.is_enabled() && !self.touch_handler_flow(&key) => key,
+            _ => {
+                let _ = self.forward_packet(flow, data).await;
+                return;
+            }
+        };
+
+        match self.filter_pending.lock().unwrap().entry(key.clone()) {
+            Entry::Occupied(mut o) => {
+                if o.get().len() < FILTER_PENDING_PACKETS {
+                    o.get_mut().push((*flow, data.to_vec()));
+                }
+                return;
+            }
+            Entry::Vacant(v) => {
+                v.insert(Vec::new());
+            }
+        }
+
+        let clients = self.clone();
+        let flow = *flow;
+        let data = data.to_vec();
+
+        tokio::spawn(async move {
+            let res = clients.forward_packet(&flow, &data).await;
+            let pending = clients.filter_pending.lock().unwrap().remove(&key).unwrap_or_default();
+
+            /* dropped along with the first packet if the flow was not opened */
+            if res.is_ok() {
+                for (flow, data) in pending {
+                    let _ = clients.forward_packet(&flow, &data).await;
+                }
+            }
+        });
+    }
 }
 
 struct UdpClient {
@@ -138,10 +592,23 @@ struct UdpClient {
     tunnel_to_port: u16,
     udp_clients: Arc<RwLock<HashMap<ClientKey, Arc<UdpClient>>>>,
     last_activity: AtomicU64,
+    started_at: u64,
+    tunnel_to_local: AtomicU64,
+    local_to_tunnel: AtomicU64,
+    idle_timeout: Duration,
+    evicted: Notify,
+    raknet: bool,
+    /* set once the RakNet handshake completed */
+    raknet_connected: AtomicBool,
+    disconnected: AtomicBool,
+    events: AgentEvents,
+    /* counts the flow against its pool backend until closed */
+    _backend: Option<BackendGuard>,
+    shapers: ClientShapers,
 }
 
 impl UdpClient {
-    pub async fn send_local(&self, dst_port: u16, data: &[u8]) -> std::io::Result<usize> {
+    pub async fn send_local(&self, dst_port: u16, data: &[u8]) -> Result<usize, UdpForwardError> {
         let port_offset = dst_port - self.tunnel_from_port;
 
         let target_addr = if port_offset == 0 {
@@ -154,91 +621,608 @@ impl UdpClient {
         };
 
         self.last_activity.store(now_milli(), Ordering::Relaxed);
-        self.local_udp.send_to(data, target_addr).await
+
+        /* delaying would hold up every flow behind this one */
+        if !self.shapers.download.try_consume(data.len()) {
+            return Err(UdpForwardError::BandwidthLimited);
+        }
+
+        let sent = self.local_udp.send_to(data, target_addr).await.map_err(UdpForwardError::SendFailed)?;
+        self.tunnel_to_local.fetch_add(sent as u64, Ordering::Relaxed);
+
+        if self.raknet && raknet::is_connected_datagram(data) {
+            self.raknet_connected.store(true, Ordering::Relaxed);
+
+            if raknet::is_disconnect(data) {
+                tracing::info!(client_key = ?self.client_key, "player disconnected, closing udp client");
+                self.disconnected.store(true, Ordering::Relaxed);
+                self.evicted.notify_one();
+            }
+        }
+
+        Ok(sent)
     }
 }
 
+/* per flow batches only grow for bursty flows, keep the cap low to bound memory */
+const HOST_BATCH_SLOTS: usize = 8;
+
 struct HostToTunnelForwarder(Arc<UdpClient>);
 
 impl HostToTunnelForwarder {
     pub async fn run(self) {
-        let mut buffer = vec![0u8; 2048];
-
-        loop {
-            tokio::task::yield_now().await;
+        let mut batch = UdpBatch::new(UDP_SLOT_SIZE, HOST_BATCH_SLOTS);
+        let mut packets = Vec::with_capacity(HOST_BATCH_SLOTS);
+        let recv_timeout = self.0.idle_timeout.min(Duration::from_secs(30));
 
-            buffer.resize(2048, 0);
-            let recv_res = tokio::time::timeout(
-                Duration::from_secs(30),
-                self.0.local_udp.recv_from(&mut buffer),
-            )
-            .await;
+        let close_reason = loop {
+            let recv_res = tokio::select! {
+                res = tokio::time::timeout(recv_timeout, batch.recv_from(&self.0.local_udp)) => res,
+                _ = self.0.evicted.notified() => match self.0.disconnected.load(Ordering::Relaxed) {
+                    true => break UdpCloseReason::Disconnected,
+                    false => break UdpCloseReason::Evicted,
+                },
+            };
 
-            let (bytes, source) = match recv_res {
+            let count = match recv_res {
                 Ok(Ok(v)) => v,
                 Ok(Err(error)) => {
                     tracing::error!(?error, "failed to receive data from host socket");
-                    break;
+                    break UdpCloseReason::Error(error.kind());
                 }
                 Err(_) => {
-                    if now_milli() - self.0.last_activity.load(Ordering::Relaxed) > 120_000 {
-                        tracing::info!("2 min timeout for not receiving data from host");
-                        break;
+                    let idle_ms = now_milli().saturating_sub(self.0.last_activity.load(Ordering::Relaxed));
+                    if idle_ms > self.0.idle_timeout.as_millis() as u64 {
+                        tracing::info!(idle_timeout = ?self.0.idle_timeout, "udp client timeout for not receiving data from tunnel");
+                        break UdpCloseReason::IdleTimeout;
                     }
                     continue;
                 }
             };
 
-            // tracing::info!(bytes, %source, "got client packet");
+            packets.clear();
 
-            if source.ip() != self.0.local_start_addr.ip() {
-                tracing::warn!(
-                    source = %source.ip(),
-                    expected = %self.0.local_start_addr.ip(),
-                    "dropping packet from different unexpected source"
-                );
-                continue;
-            }
+            for index in 0..count {
+                let source = batch.source(index);
+
+                if batch.is_truncated(index) {
+                    self.0.udp_tunnel.record_truncated();
+                    tracing::error!(bytes = batch.packet(index).len(), "packet from host truncated, dropping");
+                    continue;
+                }
+
+                if source.ip() != self.0.local_start_addr.ip() {
+                    tracing::warn!(
+                        source = %source.ip(),
+                        expected = %self.0.local_start_addr.ip(),
+                        "dropping packet from different unexpected source"
+                    );
+                    continue;
+                }
 
-            let port_count = self.0.tunnel_to_port - self.0.tunnel_from_port;
-            let local_from = self.0.local_start_addr.port();
-            let local_to = self.0.local_start_addr.port() + port_count;
+                let port_count = self.0.tunnel_to_port - self.0.tunnel_from_port;
+                let local_from = self.0.local_start_addr.port();
+                let local_to = self.0.local_start_addr.port() + port_count;
 
-            if source.port() < local_from || local_to <= source.port() {
-                tracing::warn!(
-                    source = source.port(),
-                    from = local_from,
-                    to = local_to,
-                    "dropping packet outside of expected port range"
-                );
+                if source.port() < local_from || local_to <= source.port() {
+                    tracing::warn!(
+                        source = source.port(),
+                        from = local_from,
+                        to = local_to,
+                        "dropping packet outside of expected port range"
+                    );
 
+                    continue;
+                }
+
+                let port_offset = source.port() - local_from;
+                packets.push((index, self.0.send_flow.with_src_port(self.0.tunnel_from_port + port_offset)));
+            }
+
+            if packets.is_empty() {
                 continue;
             }
 
-            buffer.truncate(bytes);
-            let port_offset = source.port() - local_from;
+            let bytes = packets.iter().map(|(index, _)| batch.packet(*index).len()).sum::<usize>();
+            self.0.shapers.upload.consume(bytes).await;
 
-            let flow = self.0.send_flow.with_src_port(self.0.tunnel_from_port + port_offset);
-            if let Err(error) = self.0.udp_tunnel.send(&mut buffer, flow).await {
-                tracing::error!(?error, "failed to send packet to through tunnel");
+            match self.0.udp_tunnel.send_batch(&mut batch, &packets).await {
+                Ok(_) => {
+                    self.0.local_to_tunnel.fetch_add(bytes as u64, Ordering::Relaxed);
+                }
+                Err(error) => {
+                    tracing::error!(?error, "failed to send packet to through tunnel");
+                }
             }
-        }
 
+            if self.0.raknet && packets.iter().any(|(index, _)| raknet::is_disconnect(batch.packet(*index))) {
+                tracing::info!(client_key = ?self.0.client_key, "server disconnected player, closing udp client");
+                break UdpCloseReason::Disconnected;
+            }
+        };
+
+        self.0.events.emit(AgentEvent::UdpFlowClosed {
+            peer_addr: self.0.client_key.client_addr,
+            tunnel_addr: self.0.client_key.tunnel_addr,
+            local_target: self.0.local_start_addr.to_string(),
+            lan_addr: self.0.local_udp.local_addr().ok(),
+            started_at: self.0.started_at,
+            summary: UdpFlowSummary {
+                tunnel_to_local: self.0.tunnel_to_local.load(Ordering::Relaxed),
+                local_to_tunnel: self.0.local_to_tunnel.load(Ordering::Relaxed),
+                duration: Duration::from_millis(now_milli().saturating_sub(self.0.started_at)),
+                close_reason,
+            },
+        });
+
+        /* client may have been evicted and replaced by a new flow with the same key */
         let removed = {
             let mut clients = self.0.udp_clients.write().await;
-            clients.remove(&self.0.client_key)
+            match clients.get(&self.0.client_key) {
+                Some(current) if Arc::ptr_eq(current, &self.0) => clients.remove(&self.0.client_key),
+                _ => None,
+            }
         };
 
         match removed {
             None => {
-                tracing::error!("could not find UDP client when removing");
+                tracing::info!(flow = ?self.0.send_flow, "evicted udp client closed");
             }
-            Some(v) if !Arc::ptr_eq(&v, &self.0) => {
-                tracing::error!("removing different UDP client when closing");
-            }
-            _ => {
+            Some(_) => {
                 tracing::info!(flow = ?self.0.send_flow, "udp client removed");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::AtomicUsize;
+
+    use playit_agent_proto::control_messages::UdpChannelDetails;
+
+    use crate::network::address_lookup::MatchAddress;
+    use crate::network::geo_filter::GeoRules;
+    use crate::network::packet_filter::{FilterVerdict, PacketFilter};
+    use crate::network::rate_limit::{RateLimit, RateLimits};
+    use crate::network::tunnel_handler::TunnelHandler;
+    use crate::network::tunnel_settings::TunnelSettings;
+
+    use super::*;
+
+    const UNMAPPED_PORT: u16 = 6000;
+
+    struct TestLookup {
+        local_addr: SocketAddr,
+        settings: TunnelSettings,
+        target: Option<LocalTarget>,
+    }
+
+    impl AddressLookup for TestLookup {
+        fn find_tunnel_port_range(&self, _match_ip: Ipv6Addr, port: u16, _proto: PortProto) -> Option<(u16, u16)> {
+            if port == UNMAPPED_PORT {
+                return None;
+            }
+            Some((port, port + 1))
+        }
+
+        fn local_address(&self, _match_addr: MatchAddress, _proto: PortProto) -> Option<SocketAddr> {
+            Some(self.local_addr)
+        }
+
+        fn tunnel_settings(&self, _match_addr: MatchAddress, _proto: PortProto) -> TunnelSettings {
+            self.settings.clone()
+        }
+
+        fn local_target(&self, match_addr: MatchAddress, proto: PortProto) -> Option<LocalTarget> {
+            match &self.target {
+                Some(target) => Some(target.clone()),
+                None => self.local_address(match_addr, proto).map(LocalTarget::Addr),
+            }
+        }
+    }
+
+    fn flow(src: &str) -> UdpFlow {
+        UdpFlow::V4 {
+            src: src.parse().unwrap(),
+            dst: "147.185.221.1:5000".parse().unwrap(),
+        }
+    }
+
+    async fn test_clients(udp: UdpSettings) -> (UdpClients<TestLookup>, UdpSocket) {
+        let local = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let mut clients = UdpClients::new(UdpTunnel::new().await.unwrap(), TestLookup {
+            local_addr: local.local_addr().unwrap(),
+            settings: TunnelSettings { udp, ..TunnelSettings::default() },
+            target: None,
+        });
+        clients.use_special_lan = false;
+
+        (clients, local)
+    }
+
+    #[tokio::test]
+    async fn test_max_flows_evicts_least_recent() {
+        let (clients, _local) = test_clients(UdpSettings { max_flows: Some(2), ..UdpSettings::default() }).await;
+
+        clients.forward_packet(&flow("10.0.0.1:100"), b"a").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        clients.forward_packet(&flow("10.0.0.2:100"), b"b").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        clients.forward_packet(&flow("10.0.0.1:100"), b"a").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        clients.forward_packet(&flow("10.0.0.3:100"), b"c").await.unwrap();
+
+        let lock = clients.udp_clients.read().await;
+        assert_eq!(lock.len(), 2);
+        assert!(lock.keys().any(|key| key.client_addr == "10.0.0.1:100".parse().unwrap()));
+        assert!(lock.keys().any(|key| key.client_addr == "10.0.0.3:100".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_max_flows_evicts_in_batches() {
+        let (clients, _local) = test_clients(UdpSettings { max_flows: Some(16), ..UdpSettings::default() }).await;
+
+        for port in 100..117 {
+            clients.forward_packet(&flow(&format!("10.0.0.1:{}", port)), b"a").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        /* the flow over the limit evicts an eighth of it, the next one fits without evicting */
+        assert_eq!(clients.client_count().await, 15);
+        clients.forward_packet(&flow("10.0.0.2:100"), b"b").await.unwrap();
+        assert_eq!(clients.client_count().await, 16);
+
+        let lock = clients.udp_clients.read().await;
+        assert!(!lock.keys().any(|key| key.client_addr.port() < 102 && key.client_addr.ip() == Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(lock.keys().any(|key| key.client_addr == "10.0.0.1:102".parse().unwrap()));
+        assert!(lock.keys().any(|key| key.client_addr == "10.0.0.1:116".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_max_flows_per_ip() {
+        let (clients, _local) = test_clients(UdpSettings { max_flows_per_ip: Some(2), ..UdpSettings::default() }).await;
+
+        for port in 100..110 {
+            clients.forward_packet(&flow(&format!("10.0.0.1:{}", port)), b"a").await.unwrap();
+        }
+        clients.forward_packet(&flow("10.0.0.2:100"), b"b").await.unwrap();
+
+        let lock = clients.udp_clients.read().await;
+        assert_eq!(lock.len(), 3);
+        assert!(lock.keys().any(|key| key.client_addr == "10.0.0.1:109".parse().unwrap()));
+        assert!(lock.keys().any(|key| key.client_addr == "10.0.0.2:100".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_idle_flow_removed() {
+        let (clients, _local) = test_clients(UdpSettings { idle_timeout_secs: 1, ..UdpSettings::default() }).await;
+
+        clients.forward_packet(&flow("10.0.0.1:100"), b"a").await.unwrap();
+        assert_eq!(clients.client_count().await, 1);
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(clients.client_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_flow_does_not_stop_forwarding() {
+        let (clients, local) = test_clients(UdpSettings::default()).await;
+
+        let unmapped = UdpFlow::V4 {
+            src: "10.0.0.1:100".parse().unwrap(),
+            dst: SocketAddrV4::new("147.185.221.1".parse().unwrap(), UNMAPPED_PORT),
+        };
+        let packets = [(unmapped, &b"lost"[..]), (flow("10.0.0.2:100"), &b"hello"[..])];
+
+        let clients = Arc::new(clients);
+        clients.forward_packets(&packets).await;
+        clients.forward_packets(&packets).await;
+
+        let mut buffer = [0u8; 16];
+        for _ in 0..2 {
+            let (bytes, _) = tokio::time::timeout(Duration::from_secs(1), local.recv_from(&mut buffer)).await.unwrap().unwrap();
+            assert_eq!(&buffer[..bytes], b"hello");
+        }
+
+        assert_eq!(clients.forward_errors(), UdpForwardErrorCounts { no_mapping: 2, ..UdpForwardErrorCounts::default() });
+        assert!(matches!(clients.forward_packet(&unmapped, b"lost").await, Err(UdpForwardError::NoMapping)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_target_is_unsupported() {
+        let (mut clients, _local) = test_clients(UdpSettings::default()).await;
+        clients.lookup.target = Some(LocalTarget::Unix("/run/panel.sock".into()));
+
+        let res = clients.forward_packet(&flow("10.0.0.1:100"), b"lost").await;
+        assert!(matches!(res, Err(UdpForwardError::UnsupportedTarget)));
+        assert_eq!(clients.forward_errors(), UdpForwardErrorCounts { unsupported_target: 1, ..UdpForwardErrorCounts::default() });
+    }
+
+    #[tokio::test]
+    async fn test_new_flows_rate_limited() {
+        let (mut clients, _local) = test_clients(UdpSettings::default()).await;
+        clients.rate_limiter = Arc::new(ConnectionRateLimiter::new(RateLimits {
+            per_ip: Some(RateLimit { per_second: 0.01, burst: 1 }),
+            global: None,
+        }));
+
+        clients.forward_packet(&flow("10.0.0.1:100"), b"a").await.unwrap();
+        /* packets on an existing flow are not limited */
+        clients.forward_packet(&flow("10.0.0.1:100"), b"a").await.unwrap();
+
+        assert!(matches!(
+            clients.forward_packet(&flow("10.0.0.1:101"), b"a").await,
+            Err(UdpForwardError::RateLimited(RateLimited::PeerIp))
+        ));
+        clients.forward_packet(&flow("10.0.0.2:100"), b"b").await.unwrap();
+
+        assert_eq!(clients.client_count().await, 2);
+        assert_eq!(clients.forward_errors().rate_limited, 1);
+    }
+
+    #[tokio::test]
+    async fn test_offline_pong_on_existing_flow() {
+        let server = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let tunnel = UdpTunnel::new().await.unwrap();
+        tunnel.set_udp_tunnel(UdpChannelDetails {
+            tunnel_addr: server.local_addr().unwrap(),
+            token: Arc::new(vec![1, 2, 3, 4]),
+        }).await.unwrap();
+
+        let mut buffer = [0u8; 256];
+        server.recv_from(&mut buffer).await.unwrap();
+
+        let local = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut settings = TunnelSettings::default();
+        settings.raknet = raknet::RakNetSettings { enabled: true, offline_motd: Some("back soon".to_string()) };
+
+        let mut clients = UdpClients::new(tunnel, TestLookup { local_addr: local.local_addr().unwrap(), settings, target: None });
+        clients.use_special_lan = false;
+        let clients = Arc::new(clients);
+
+        let flow = flow("10.0.0.1:100");
+        clients.forward_packet(&flow, b"a").await.unwrap();
+        let (bytes, _) = tokio::time::timeout(Duration::from_secs(1), local.recv_from(&mut buffer)).await.unwrap().unwrap();
+        assert_eq!(&buffer[..bytes], b"a");
+
+        let (match_addr, _) = clients.client_key(&flow).unwrap();
+        clients.local_health.set(match_addr, local.local_addr().unwrap().to_string(), false);
+
+        /* answered through the batch path even though the flow is open */
+        let ping = raknet::unconnected_ping(7);
+        clients.forward_packets(&[(flow, &ping[..])]).await;
+
+        let (bytes, _) = tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer)).await.unwrap().unwrap();
+        let reply_flow = UdpFlow::from_tail(&buffer[..bytes]).unwrap();
+        assert_eq!(reply_flow, flow.flip());
+        assert!(raknet::is_unconnected_pong(&buffer[..bytes - reply_flow.len()]));
+
+        assert!(tokio::time::timeout(Duration::from_millis(100), local.recv_from(&mut buffer)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_raknet_disconnect_closes_flow() {
+        let (clients, _local) = test_clients(UdpSettings::default()).await;
+        let mut settings = clients.lookup.settings.clone();
+        settings.raknet.enabled = true;
+        let clients = UdpClients { lookup: TestLookup { settings, ..clients.lookup }, ..clients };
+
+        /* offline messages alone are not a session */
+        clients.forward_packet(&flow("10.0.0.1:100"), &raknet::unconnected_ping(1)).await.unwrap();
+        assert!(clients.stats().raknet_sessions().await.is_empty());
+
+        /* reliable ordered frame with a game packet, then a disconnection notification */
+        let game = [0x84, 0x00, 0x00, 0x00, 0x60, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xfe];
+        clients.forward_packet(&flow("10.0.0.1:100"), &game).await.unwrap();
+
+        let sessions = clients.stats().raknet_sessions().await;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].sessions, 1);
+        assert_eq!(sessions[0].tunnel_addr, "147.185.221.1:5000".parse().unwrap());
+
+        let disconnect = [0x84, 0x01, 0x00, 0x00, 0x00, 0x00, 0x08, 0x15];
+        clients.forward_packet(&flow("10.0.0.1:100"), &disconnect).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(clients.client_count().await, 0);
+    }
+
+    struct PortFilter(SocketAddr);
+
+    impl PacketFilter for PortFilter {
+        fn peek_len(&self) -> usize {
+            3
+        }
+
+        fn filter(&self, request: &FilterRequest, data: &[u8]) -> FilterVerdict {
+            match (request.peer_addr.port(), data) {
+                (_, b"bot") => FilterVerdict::Reject,
+                (200, _) => FilterVerdict::Route(self.0),
+                _ => FilterVerdict::Accept,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_rejects_and_routes_flows() {
+        let (mut clients, local) = test_clients(UdpSettings::default()).await;
+        let routed = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        clients.filter = Arc::new(FilterHook::new(Some(Arc::new(PortFilter(routed.local_addr().unwrap())))));
+
+        assert!(matches!(clients.forward_packet(&flow("10.0.0.1:100"), b"bot!").await, Err(UdpForwardError::Filtered(_))));
+        clients.forward_packet(&flow("10.0.0.1:100"), b"player").await.unwrap();
+        clients.forward_packet(&flow("10.0.0.1:200"), b"player").await.unwrap();
+        /* only new flows are filtered */
+        clients.forward_packet(&flow("10.0.0.1:100"), b"bot").await.unwrap();
+
+        let mut buffer = [0u8; 16];
+        for (socket, expected) in [(&local, &b"player"[..]), (&routed, b"player"), (&local, b"bot")] {
+            let (bytes, _) = tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer)).await.unwrap().unwrap();
+            assert_eq!(&buffer[..bytes], expected);
+        }
+
+        assert_eq!(clients.forward_errors().filtered, 1);
+        assert_eq!(clients.client_count().await, 2);
+    }
+
+    struct SlowFilter;
+
+    impl PacketFilter for SlowFilter {
+        fn peek_len(&self) -> usize {
+            3
+        }
+
+        fn filter(&self, _: &FilterRequest, data: &[u8]) -> FilterVerdict {
+            std::thread::sleep(Duration::from_millis(200));
+            match data {
+                b"bot" => FilterVerdict::Reject,
+                _ => FilterVerdict::Accept,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_runs_off_receive_loop() {
+        let (mut clients, local) = test_clients(UdpSettings::default()).await;
+        clients.filter = Arc::new(FilterHook::new(Some(Arc::new(SlowFilter))));
+        let clients = Arc::new(clients);
+
+        let started = std::time::Instant::now();
+        clients.forward_packets(&[(flow("10.0.0.1:100"), &b"one"[..]), (flow("10.0.0.1:100"), &b"two"[..])]).await;
+        clients.forward_packets(&[(flow("10.0.0.2:100"), &b"bot"[..]), (flow("10.0.0.2:100"), &b"three"[..])]).await;
+        assert!(started.elapsed() < Duration::from_millis(200));
+
+        /* packets queued behind the verdict follow the first, a rejected flow drops them */
+        let mut buffer = [0u8; 16];
+        for expected in [&b"one"[..], b"two"] {
+            let (bytes, _) = tokio::time::timeout(Duration::from_secs(1), local.recv_from(&mut buffer)).await.unwrap().unwrap();
+            assert_eq!(&buffer[..bytes], expected);
+        }
+        assert!(tokio::time::timeout(Duration::from_millis(300), local.recv_from(&mut buffer)).await.is_err());
+
+        assert_eq!(clients.forward_errors().filtered, 1);
+        assert_eq!(clients.client_count().await, 1);
+    }
+
+    struct EchoHandler;
+
+    impl TunnelHandler for EchoHandler {
+        fn handle_udp(&self, _flow: UdpFlow, data: &[u8], reply: UdpReplySender) {
+            let data = data.to_vec();
+            tokio::spawn(async move {
+                reply.send(&data).await.unwrap();
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_receives_datagrams_and_replies() {
+        let server = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let tunnel = UdpTunnel::new().await.unwrap();
+        tunnel.set_udp_tunnel(UdpChannelDetails {
+            tunnel_addr: server.local_addr().unwrap(),
+            token: Arc::new(vec![1, 2, 3, 4]),
+        }).await.unwrap();
+
+        let mut buffer = [0u8; 128];
+        server.recv_from(&mut buffer).await.unwrap();
+
+        let clients = UdpClients::new(tunnel, TestLookup {
+            local_addr: "127.0.0.1:1".parse().unwrap(),
+            settings: TunnelSettings::default(),
+            target: Some(LocalTarget::Handler(Arc::new(EchoHandler))),
+        });
+
+        let flow = flow("10.0.0.1:100");
+        clients.forward_packet(&flow, b"ping").await.unwrap();
+        assert_eq!(clients.client_count().await, 0);
+
+        let (bytes, _) = tokio::time::timeout(Duration::from_secs(1), server.recv_from(&mut buffer)).await.unwrap().unwrap();
+        let reply_flow = UdpFlow::from_tail(&buffer[..bytes]).unwrap();
+        assert_eq!(reply_flow, flow.flip());
+        assert_eq!(&buffer[..bytes - reply_flow.len()], b"ping");
+    }
+
+    struct CountingHandler(AtomicUsize);
+
+    impl TunnelHandler for CountingHandler {
+        fn handle_udp(&self, _flow: UdpFlow, _data: &[u8], _reply: UdpReplySender) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_geo_denied() {
+        let handler = Arc::new(CountingHandler(AtomicUsize::new(0)));
+
+        /* without a geo database every peer is unknown */
+        let geo = GeoRules { allow_countries: vec!["DE".to_string()], allow_unknown: false, ..GeoRules::default() };
+        let clients = UdpClients::new(UdpTunnel::new().await.unwrap(), TestLookup {
+            local_addr: "127.0.0.1:1".parse().unwrap(),
+            settings: TunnelSettings { geo, ..TunnelSettings::default() },
+            target: Some(LocalTarget::Handler(handler.clone())),
+        });
+
+        let res = clients.forward_packet(&flow("10.0.0.1:100"), b"denied").await;
+        assert!(matches!(res, Err(UdpForwardError::GeoDenied(GeoDenied::Unknown))));
+        assert_eq!(handler.0.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_handler_flows_rate_limited() {
+        let handler = Arc::new(CountingHandler(AtomicUsize::new(0)));
+
+        let mut clients = UdpClients::new(UdpTunnel::new().await.unwrap(), TestLookup {
+            local_addr: "127.0.0.1:1".parse().unwrap(),
+            settings: TunnelSettings::default(),
+            target: Some(LocalTarget::Handler(handler.clone())),
+        });
+        clients.rate_limiter = Arc::new(ConnectionRateLimiter::new(RateLimits {
+            per_ip: Some(RateLimit { per_second: 0.01, burst: 1 }),
+            global: None,
+        }));
+
+        clients.forward_packet(&flow("10.0.0.1:100"), b"a").await.unwrap();
+        /* packets on a flow the handler already has are not limited */
+        clients.forward_packet(&flow("10.0.0.1:100"), b"a").await.unwrap();
+
+        assert!(matches!(
+            clients.forward_packet(&flow("10.0.0.1:101"), b"a").await,
+            Err(UdpForwardError::RateLimited(RateLimited::PeerIp))
+        ));
+        assert_eq!(handler.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_filter_runs_for_handler_flows() {
+        let handler = Arc::new(CountingHandler(AtomicUsize::new(0)));
+        let routed = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let mut clients = UdpClients::new(UdpTunnel::new().await.unwrap(), TestLookup {
+            local_addr: "127.0.0.1:1".parse().unwrap(),
+            settings: TunnelSettings::default(),
+            target: Some(LocalTarget::Handler(handler.clone())),
+        });
+        clients.use_special_lan = false;
+        clients.filter = Arc::new(FilterHook::new(Some(Arc::new(PortFilter(routed.local_addr().unwrap())))));
+
+        assert!(matches!(clients.forward_packet(&flow("10.0.0.1:100"), b"bot!").await, Err(UdpForwardError::Filtered(_))));
+        clients.forward_packet(&flow("10.0.0.1:100"), b"player").await.unwrap();
+        /* only new flows are filtered */
+        clients.forward_packet(&flow("10.0.0.1:100"), b"bot").await.unwrap();
+        assert_eq!(handler.0.load(Ordering::Relaxed), 2);
+
+        /* a routed flow goes to the route instead of the handler */
+        clients.forward_packet(&flow("10.0.0.1:200"), b"player").await.unwrap();
+        let mut buffer = [0u8; 16];
+        let (bytes, _) = tokio::time::timeout(Duration::from_secs(1), routed.recv_from(&mut buffer)).await.unwrap().unwrap();
+        assert_eq!(&buffer[..bytes], b"player");
+        assert_eq!(handler.0.load(Ordering::Relaxed), 2);
+    }
+}
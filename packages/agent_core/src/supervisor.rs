@@ -0,0 +1,267 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::events::{AgentEvent, AgentEvents};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+    Down,
+}
+
+impl HealthStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => HealthStatus::Healthy,
+            1 => HealthStatus::Degraded,
+            _ => HealthStatus::Down,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            HealthStatus::Healthy => 0,
+            HealthStatus::Degraded => 1,
+            HealthStatus::Down => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Subsystem {
+    Control,
+    UdpChannel,
+    TcpAccept,
+}
+
+pub struct SubsystemHealth {
+    subsystem: Subsystem,
+    status: AtomicU8,
+    restarts: AtomicU64,
+    events: AgentEvents,
+}
+
+impl SubsystemHealth {
+    fn new(subsystem: Subsystem, events: AgentEvents) -> Self {
+        SubsystemHealth {
+            subsystem,
+            status: AtomicU8::new(HealthStatus::Down.as_u8()),
+            restarts: AtomicU64::new(0),
+            events,
+        }
+    }
+
+    pub fn set(&self, status: HealthStatus) {
+        let previous = self.status.swap(status.as_u8(), Ordering::Relaxed);
+        if previous != status.as_u8() {
+            tracing::info!(subsystem = ?self.subsystem, from = ?HealthStatus::from_u8(previous), to = ?status, "subsystem health changed");
+            self.events.emit(AgentEvent::HealthChanged { subsystem: self.subsystem, status });
+        }
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        HealthStatus::from_u8(self.status.load(Ordering::Relaxed))
+    }
+
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    fn report(&self) -> SubsystemReport {
+        SubsystemReport {
+            status: self.status(),
+            restarts: self.restarts(),
+        }
+    }
+}
+
+/// Health of the agent's subsystems, updated by the running tasks.
+pub struct AgentHealth {
+    pub control: SubsystemHealth,
+    pub udp_channel: SubsystemHealth,
+    pub tcp_accept: SubsystemHealth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SubsystemReport {
+    pub status: HealthStatus,
+    pub restarts: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub control: SubsystemReport,
+    pub udp_channel: SubsystemReport,
+    pub tcp_accept: SubsystemReport,
+}
+
+impl Default for AgentHealth {
+    fn default() -> Self {
+        Self::new(AgentEvents::default())
+    }
+}
+
+impl AgentHealth {
+    pub fn new(events: AgentEvents) -> Self {
+        AgentHealth {
+            control: SubsystemHealth::new(Subsystem::Control, events.clone()),
+            udp_channel: SubsystemHealth::new(Subsystem::UdpChannel, events.clone()),
+            tcp_accept: SubsystemHealth::new(Subsystem::TcpAccept, events),
+        }
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        self.report().status
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let control = self.control.report();
+        let udp_channel = self.udp_channel.report();
+        let mut tcp_accept = self.tcp_accept.report();
+
+        /* new clients are delivered over the control channel */
+        if control.status == HealthStatus::Down {
+            tcp_accept.status = HealthStatus::Down;
+        }
+
+        let status = if control.status == HealthStatus::Down {
+            HealthStatus::Down
+        } else if [control, udp_channel, tcp_accept].iter().all(|s| s.status == HealthStatus::Healthy) {
+            HealthStatus::Healthy
+        } else {
+            HealthStatus::Degraded
+        };
+
+        HealthReport {
+            status,
+            control,
+            udp_channel,
+            tcp_accept,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /* a task that ran this long before failing restarts without delay growth */
+    pub reset_after: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Runs the task produced by `start` until `keep_running` is cleared,
+/// restarting it with exponential backoff whenever it panics or exits early.
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    health: &SubsystemHealth,
+    keep_running: Arc<AtomicBool>,
+    backoff: Backoff,
+    mut start: F,
+) where F: FnMut() -> Fut, Fut: Future<Output = ()> + Send + 'static {
+    let mut delay = backoff.initial;
+
+    loop {
+        let started = Instant::now();
+        let res = tokio::spawn(start()).await;
+
+        if !keep_running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        health.set(HealthStatus::Down);
+        health.restarts.fetch_add(1, Ordering::Relaxed);
+
+        if backoff.reset_after <= started.elapsed() {
+            delay = backoff.initial;
+        }
+
+        match res {
+            Ok(()) => tracing::error!(name, ?delay, "task exited unexpectedly, restarting"),
+            Err(error) => tracing::error!(name, ?error, ?delay, "task failed, restarting"),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(backoff.max);
+
+        if !keep_running.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_supervise_restarts_failed_task() {
+        let health = AgentHealth::default();
+        let keep_running = Arc::new(AtomicBool::new(true));
+        let runs = Arc::new(AtomicU64::new(0));
+
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(20),
+            reset_after: Duration::from_secs(60),
+        };
+
+        let task_runs = runs.clone();
+        let task_keep_running = keep_running.clone();
+
+        supervise("test", &health.control, keep_running, backoff, move || {
+            let runs = task_runs.clone();
+            let keep_running = task_keep_running.clone();
+
+            async move {
+                if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("task failure");
+                }
+                keep_running.store(false, Ordering::SeqCst);
+            }
+        }).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(health.control.restarts(), 2);
+    }
+
+    #[test]
+    fn test_health_report_status() {
+        let events = AgentEvents::default();
+        let mut rx = events.subscribe();
+
+        let health = AgentHealth::new(events);
+        assert_eq!(health.status(), HealthStatus::Down);
+
+        health.control.set(HealthStatus::Healthy);
+        assert_eq!(health.status(), HealthStatus::Degraded);
+        assert_eq!(rx.try_recv().unwrap(), AgentEvent::HealthChanged {
+            subsystem: Subsystem::Control,
+            status: HealthStatus::Healthy,
+        });
+
+        health.udp_channel.set(HealthStatus::Healthy);
+        health.tcp_accept.set(HealthStatus::Healthy);
+        assert_eq!(health.status(), HealthStatus::Healthy);
+
+        health.control.set(HealthStatus::Down);
+        let report = health.report();
+        assert_eq!(report.status, HealthStatus::Down);
+        assert_eq!(report.tcp_accept.status, HealthStatus::Down);
+    }
+}
@@ -68,7 +68,7 @@ impl AuthenticatedControl {
             pong: self.last_pong.clone(),
         };
 
-        let res = conn.authenticate(self.secret_key.clone()).await?;
+        let res = conn.authenticate_with_api_base(self.api_client.api_base().to_string(), self.secret_key.clone()).await?;
         *self = res;
         Ok(())
     }
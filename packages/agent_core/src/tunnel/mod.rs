@@ -1,6 +1,7 @@
 pub mod setup;
 pub mod control;
 pub mod udp_tunnel;
+pub mod udp_batch;
 pub mod udp_proto;
 pub mod tcp_tunnel;
 pub mod simple_tunnel;
\ No newline at end of file
@@ -15,6 +15,7 @@ use playit_agent_proto::rpc::ControlRpcMessage;
 use crate::api::client::{ApiClient, ApiError};
 use crate::api::messages::*;
 
+use crate::agent::DEFAULT_API_BASE;
 use crate::utils::now_milli;
 use crate::tunnel::control::AuthenticatedControl;
 use crate::utils::error_helper::ErrorHelper;
@@ -128,7 +129,11 @@ pub struct ConnectedControl {
 
 impl ConnectedControl {
     pub async fn authenticate(self, secret_key: String) -> Result<AuthenticatedControl, SetupError> {
-        let api = ApiClient::new("https://api.playit.cloud".to_string(), Some(secret_key.clone()));
+        self.authenticate_with_api_base(DEFAULT_API_BASE.to_string(), secret_key).await
+    }
+
+    pub async fn authenticate_with_api_base(self, api_base: String, secret_key: String) -> Result<AuthenticatedControl, SetupError> {
+        let api = ApiClient::new(api_base, Some(secret_key.clone()));
 
         let res = api.sign_and_register(SignAgentRegister {
             agent_version: 1,
@@ -231,6 +236,7 @@ pub enum SetupError {
     NoResponseFromAuthenticate,
     RegisterInvalidSignature,
     RegisterUnauthorized,
+    Timeout,
 }
 
 impl Display for SetupError {
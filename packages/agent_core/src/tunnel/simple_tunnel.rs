@@ -3,6 +3,7 @@ use std::time::Duration;
 use playit_agent_proto::control_feed::{ControlFeed, NewClient};
 use playit_agent_proto::control_messages::ControlResponse;
 
+use crate::agent::AgentEndpoints;
 use crate::tunnel::control::AuthenticatedControl;
 use crate::tunnel::setup::{SetupError, SetupFindSuitableChannel};
 use crate::tunnel::udp_tunnel::UdpTunnel;
@@ -21,11 +22,15 @@ pub struct SimpleTunnel {
 
 impl SimpleTunnel {
     pub async fn setup(secret_key: String) -> Result<Self, SetupError> {
-        let udp_tunnel = UdpTunnel::new().await?;
+        Self::setup_with_udp(&AgentEndpoints::default(), secret_key, UdpTunnel::new().await?).await
+    }
 
-        let addresses = address_lookup("control.playit.gg", 5525).await;
+    /// Sets up a new control channel reusing an existing udp tunnel so
+    /// flows using it survive the control channel being replaced.
+    pub async fn setup_with_udp(endpoints: &AgentEndpoints, secret_key: String, udp_tunnel: UdpTunnel) -> Result<Self, SetupError> {
+        let addresses = address_lookup(&endpoints.control_host, endpoints.control_port).await;
         let setup = SetupFindSuitableChannel::new(addresses).setup().await?;
-        let mut control_channel = setup.authenticate(secret_key.clone()).await?;
+        let control_channel = setup.authenticate_with_api_base(endpoints.api_base.clone(), secret_key.clone()).await?;
 
         Ok(SimpleTunnel {
             secret_key,
@@ -41,6 +46,10 @@ impl SimpleTunnel {
         self.udp_tunnel.clone()
     }
 
+    pub fn is_connected(&self) -> bool {
+        !self.control_channel.is_expired()
+    }
+
     pub async fn update(&mut self) -> Option<NewClient> {
         if self.control_channel.is_expired() {
             if let Err(error) = self.control_channel.authenticate().await {
@@ -1,35 +1,216 @@
+use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
 
 use playit_agent_proto::control_feed::{ClaimInstructions, NewClient};
 
 use crate::network::lan_address::LanAddress;
+use crate::network::tunnel_settings::TcpSettings;
 
 pub struct TcpTunnel {
     claim_instruction: ClaimInstructions,
     peer_addr: SocketAddr,
     pub use_special_lan: bool,
+    pub settings: TcpSettings,
+}
+
+/// Value the tunnel server replies with once it accepts the claim.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClaimResponse {
+    pub value: u64,
+}
+
+#[derive(Debug)]
+pub enum ClaimError {
+    /* the token was not sent, also used when connecting times out */
+    ConnectFailed(std::io::Error),
+    Timeout,
+    /* connection closed before a full response, token is invalid or already used */
+    Rejected,
+    IoError(std::io::Error),
+}
+
+impl ClaimError {
+    /* once sent the token may have been used, so only failed connects are retried */
+    fn can_retry(&self) -> bool {
+        matches!(self, ClaimError::ConnectFailed(_))
+    }
+}
+
+impl Display for ClaimError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ClaimError {
 }
 
 impl TcpTunnel {
     pub fn new(claim_instruction: ClaimInstructions, peer_addr: SocketAddr) -> Self {
-        TcpTunnel { claim_instruction, use_special_lan: true, peer_addr }
+        TcpTunnel { claim_instruction, use_special_lan: true, peer_addr, settings: TcpSettings::default() }
     }
 
-    pub async fn connect(self) -> std::io::Result<TcpStream> {
-        let mut stream = LanAddress::tcp_socket(
+    /// Claims the client, each attempt is limited by the claim timeout. The
+    /// claim is accepted once the server replies with a full 8 byte response.
+    pub async fn connect(self) -> Result<(TcpStream, ClaimResponse), ClaimError> {
+        let mut attempt = 0;
+
+        loop {
+            match self.claim(Instant::now() + self.settings.claim_timeout()).await {
+                Ok(claimed) => return Ok(claimed),
+                Err(error) if error.can_retry() && attempt < self.settings.claim_retries => {
+                    attempt += 1;
+                    tracing::warn!(?error, attempt, address = %self.claim_instruction.address, "claim failed, retrying");
+                    tokio::time::sleep(Duration::from_millis(250) * attempt).await;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    async fn claim(&self, deadline: Instant) -> Result<(TcpStream, ClaimResponse), ClaimError> {
+        let connect = LanAddress::tcp_socket(
             self.use_special_lan,
             self.peer_addr,
             self.claim_instruction.address,
-        ).await?;
+        );
+
+        let mut stream = match tokio::time::timeout_at(deadline, connect).await {
+            Ok(res) => res.map_err(ClaimError::ConnectFailed)?,
+            Err(_) => return Err(ClaimError::ConnectFailed(std::io::ErrorKind::TimedOut.into())),
+        };
+
+        if let Err(error) = self.settings.apply(&stream) {
+            tracing::warn!(?error, "failed to apply settings to tunnel socket");
+        }
+
+        let res = tokio::time::timeout_at(deadline, async {
+            stream.write_all(&self.claim_instruction.token).await?;
+            let mut response = [0u8; 8];
+            stream.read_exact(&mut response).await?;
+            Ok::<_, std::io::Error>(ClaimResponse { value: u64::from_be_bytes(response) })
+        }).await;
+
+        match res {
+            Ok(Ok(response)) => Ok((stream, response)),
+            Ok(Err(error)) if error.kind() == std::io::ErrorKind::UnexpectedEof => Err(ClaimError::Rejected),
+            Ok(Err(error)) => Err(ClaimError::IoError(error)),
+            Err(_) => Err(ClaimError::Timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
 
-        stream.write_all(&self.claim_instruction.token).await?;
+    use super::*;
 
-        let mut response = [0u8; 8];
-        stream.read_exact(&mut response).await?;
+    const TOKEN: &[u8] = b"claim-token";
 
-        Ok(stream)
+    enum Reply {
+        Accept(u64),
+        Short,
+        Close,
+        Stall,
     }
-}
\ No newline at end of file
+
+    async fn fake_claim_server(replies: Vec<Reply>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve_claims(listener, replies);
+        addr
+    }
+
+    /* answers each connection with the next reply, after checking the token */
+    fn serve_claims(listener: TcpListener, replies: Vec<Reply>) {
+        tokio::spawn(async move {
+            let mut stalled = Vec::new();
+
+            for reply in replies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut token = vec![0u8; TOKEN.len()];
+                stream.read_exact(&mut token).await.unwrap();
+                assert_eq!(token, TOKEN);
+
+                match reply {
+                    Reply::Accept(value) => stream.write_all(&value.to_be_bytes()).await.unwrap(),
+                    Reply::Short => stream.write_all(&[0u8; 3]).await.unwrap(),
+                    Reply::Close => drop(stream),
+                    Reply::Stall => stalled.push(stream),
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+    }
+
+    fn tunnel(address: SocketAddr, claim_retries: u32) -> TcpTunnel {
+        let mut tunnel = TcpTunnel::new(
+            ClaimInstructions { address, token: TOKEN.to_vec() },
+            "1.2.3.4:5000".parse().unwrap(),
+        );
+        tunnel.use_special_lan = false;
+        tunnel.settings.claim_timeout_secs = 1;
+        tunnel.settings.claim_retries = claim_retries;
+        tunnel
+    }
+
+    #[tokio::test]
+    async fn test_claim_accepted() {
+        let addr = fake_claim_server(vec![Reply::Accept(42)]).await;
+        let (_, response) = tunnel(addr, 0).connect().await.unwrap();
+        assert_eq!(response, ClaimResponse { value: 42 });
+    }
+
+    #[tokio::test]
+    async fn test_claim_rejected_is_not_retried() {
+        let addr = fake_claim_server(vec![Reply::Close, Reply::Accept(1)]).await;
+        assert!(matches!(tunnel(addr, 2).connect().await, Err(ClaimError::Rejected)));
+    }
+
+    #[tokio::test]
+    async fn test_claim_short_reply_rejected() {
+        let addr = fake_claim_server(vec![Reply::Short]).await;
+        assert!(matches!(tunnel(addr, 0).connect().await, Err(ClaimError::Rejected)));
+    }
+
+    #[tokio::test]
+    async fn test_claim_timeout() {
+        let addr = fake_claim_server(vec![Reply::Stall]).await;
+        assert!(matches!(tunnel(addr, 0).connect().await, Err(ClaimError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_claim_timeout_is_not_retried() {
+        /* the stalled server may have used the token already */
+        let addr = fake_claim_server(vec![Reply::Stall, Reply::Accept(1)]).await;
+        assert!(matches!(tunnel(addr, 1).connect().await, Err(ClaimError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_claim_retries_after_connect_failed() {
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        /* the claim server comes up before the first retry */
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            serve_claims(TcpListener::bind(addr).await.unwrap(), vec![Reply::Accept(7)]);
+        });
+
+        let (_, response) = tunnel(addr, 1).connect().await.unwrap();
+        assert_eq!(response.value, 7);
+    }
+
+    #[tokio::test]
+    async fn test_claim_connect_failed() {
+        let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert!(matches!(tunnel(addr, 0).connect().await, Err(ClaimError::ConnectFailed(_))));
+    }
+}
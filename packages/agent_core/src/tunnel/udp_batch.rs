@@ -0,0 +1,383 @@
+use std::cell::RefCell;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+
+/* max packets moved per syscall */
+pub const UDP_BATCH_SIZE: usize = 32;
+
+/* an ethernet MTU datagram plus the tunnel's flow footer */
+pub const UDP_SLOT_SIZE: usize = 2048;
+
+/* larger than any UDP payload, so with a slot in front nothing is truncated */
+const OVERFLOW_SIZE: usize = 65_536;
+
+thread_local! {
+    /* only borrowed during a non blocking receive, so sockets waiting for
+     * data hold MTU sized slots rather than 64 KiB each. One region per
+     * message of a batch, datagrams read together can't be re-read one at
+     * a time. Zeroed allocations this large are mapped lazily, so pages are
+     * only backed once a large datagram lands in them. */
+    static OVERFLOW: RefCell<Vec<u8>> = RefCell::new(vec![0u8; OVERFLOW_SIZE * UDP_BATCH_SIZE]);
+}
+
+/// Reusable set of receive buffers for reading several datagrams at once.
+/// Starts with a single buffer and grows (up to `max_slots`) each time a
+/// receive fills every buffer, so quiet sockets don't hold on to memory.
+///
+/// Slots are sized for typical datagrams. Larger ones spill into a per
+/// thread overflow buffer and are copied into their slot, which shrinks
+/// back on the next receive.
+pub struct UdpBatch {
+    slots: Vec<Vec<u8>>,
+    received: Vec<Received>,
+    slot_size: usize,
+    max_slots: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Received {
+    bytes: usize,
+    source: SocketAddr,
+    truncated: bool,
+}
+
+impl UdpBatch {
+    pub fn new(slot_size: usize, max_slots: usize) -> Self {
+        UdpBatch {
+            slots: vec![vec![0u8; slot_size]],
+            received: Vec::with_capacity(max_slots),
+            slot_size,
+            max_slots: max_slots.clamp(1, UDP_BATCH_SIZE),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.received.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.received.is_empty()
+    }
+
+    pub fn packet(&self, index: usize) -> &[u8] {
+        &self.slots[index][..self.received[index].bytes]
+    }
+
+    pub fn source(&self, index: usize) -> SocketAddr {
+        self.received[index].source
+    }
+
+    pub fn is_truncated(&self, index: usize) -> bool {
+        self.received[index].truncated
+    }
+
+    /// Buffer of a received packet trimmed to its length so data can be appended.
+    pub fn packet_buffer(&mut self, index: usize) -> &mut Vec<u8> {
+        let slot = &mut self.slots[index];
+        slot.truncate(self.received[index].bytes);
+        slot
+    }
+
+    pub(crate) fn slot(&self, index: usize) -> &[u8] {
+        &self.slots[index]
+    }
+
+    /// Waits for at least one datagram then reads as many as are immediately available.
+    pub async fn recv_from(&mut self, socket: &UdpSocket) -> std::io::Result<usize> {
+        self.received.clear();
+        for slot in &mut self.slots {
+            if self.slot_size < slot.capacity() {
+                slot.truncate(self.slot_size);
+                slot.shrink_to(self.slot_size);
+            }
+            slot.resize(self.slot_size, 0);
+        }
+
+        let count = sys::recv_batch(socket, &mut self.slots, &mut self.received).await?;
+
+        if count == self.slots.len() && self.slots.len() < self.max_slots {
+            self.slots.push(vec![0u8; self.slot_size]);
+        }
+
+        Ok(count)
+    }
+}
+
+/// Sends every packet to `target`, using as few syscalls as the platform allows.
+pub async fn send_batch(socket: &UdpSocket, packets: &[&[u8]], target: SocketAddr) -> std::io::Result<()> {
+    let mut sent = 0;
+    while sent < packets.len() {
+        let end = packets.len().min(sent + UDP_BATCH_SIZE);
+        sent += sys::send_batch(socket, &packets[sent..end], target).await?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::net::SocketAddr;
+    use std::os::unix::io::{AsRawFd, RawFd};
+
+    use socket2::SockAddr;
+    use tokio::io::Interest;
+    use tokio::net::UdpSocket;
+
+    use super::{Received, OVERFLOW, OVERFLOW_SIZE, UDP_BATCH_SIZE};
+
+    pub async fn recv_batch(socket: &UdpSocket, slots: &mut [Vec<u8>], received: &mut Vec<Received>) -> std::io::Result<usize> {
+        let fd = socket.as_raw_fd();
+
+        loop {
+            socket.readable().await?;
+
+            let res = socket.try_io(Interest::READABLE, || {
+                OVERFLOW.with(|overflow| recvmmsg(fd, slots, &mut overflow.borrow_mut(), received))
+            });
+
+            match res {
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    pub async fn send_batch(socket: &UdpSocket, packets: &[&[u8]], target: SocketAddr) -> std::io::Result<usize> {
+        let fd = socket.as_raw_fd();
+        let target = SockAddr::from(target);
+
+        loop {
+            socket.writable().await?;
+
+            match socket.try_io(Interest::WRITABLE, || sendmmsg(fd, packets, &target)) {
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    fn recvmmsg(fd: RawFd, slots: &mut [Vec<u8>], overflow: &mut [u8], received: &mut Vec<Received>) -> std::io::Result<usize> {
+        let count = slots.len().min(UDP_BATCH_SIZE);
+
+        // SAFETY: sockaddr_storage, iovec and mmsghdr are plain C structs for which all zero bytes is a valid value.
+        let mut addrs: [libc::sockaddr_storage; UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let mut iovecs: [[libc::iovec; 2]; UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+
+        /* every message continues into its own region of the overflow buffer */
+        let regions = overflow.chunks_mut(OVERFLOW_SIZE);
+        for ((((slot, region), iovec), addr), msg) in slots.iter_mut().zip(regions).zip(&mut iovecs).zip(&mut addrs).zip(&mut msgs) {
+            *iovec = [
+                libc::iovec {
+                    iov_base: slot.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: slot.len(),
+                },
+                libc::iovec {
+                    iov_base: region.as_mut_ptr() as *mut libc::c_void,
+                    iov_len: region.len(),
+                },
+            ];
+
+            msg.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec.as_mut_ptr();
+            msg.msg_hdr.msg_iovlen = 2;
+        }
+
+        // SAFETY: the first `count` headers point at address storage and iovecs that live on this
+        // stack frame, and each iovec covers memory of a slot or of an overflow region that is
+        // exclusively borrowed for the call. The kernel writes at most iov_len bytes to each.
+        let res = unsafe {
+            libc::recvmmsg(fd, msgs.as_mut_ptr(), count as _, libc::MSG_DONTWAIT, std::ptr::null_mut())
+        };
+
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let count = res as usize;
+
+        for (index, (msg, addr)) in msgs.iter().zip(addrs).take(count).enumerate() {
+            // SAFETY: the kernel filled `addr` with a socket address of msg_namelen bytes,
+            // which is within the size of sockaddr_storage given as msg_namelen.
+            let source = unsafe { SockAddr::new(addr, msg.msg_hdr.msg_namelen) }
+                .as_socket()
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "unsupported source address"))?;
+
+            let slot = &mut slots[index];
+            let slot_len = slot.len();
+            let bytes = msg.msg_len as usize;
+            let truncated = msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+
+            if slot_len < bytes {
+                let region = &overflow[index * OVERFLOW_SIZE..];
+                slot.extend_from_slice(&region[..bytes - slot_len]);
+            }
+
+            received.push(Received { bytes, source, truncated });
+        }
+
+        Ok(count)
+    }
+
+    fn sendmmsg(fd: RawFd, packets: &[&[u8]], target: &SockAddr) -> std::io::Result<usize> {
+        let count = packets.len().min(UDP_BATCH_SIZE);
+
+        // SAFETY: iovec and mmsghdr are plain C structs for which all zero bytes is a valid value.
+        let mut iovecs: [libc::iovec; UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; UDP_BATCH_SIZE] = unsafe { std::mem::zeroed() };
+
+        for ((packet, iovec), msg) in packets.iter().zip(&mut iovecs).zip(&mut msgs) {
+            *iovec = libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            };
+
+            msg.msg_hdr.msg_name = target.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = target.len();
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+
+        // SAFETY: the first `count` headers point at iovecs on this stack frame covering the
+        // borrowed packets, and at `target` which outlives the call. The kernel only reads them.
+        let res = unsafe {
+            libc::sendmmsg(fd, msgs.as_mut_ptr(), count as _, libc::MSG_DONTWAIT)
+        };
+
+        if res < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(res as usize)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::net::SocketAddr;
+
+    use tokio::net::UdpSocket;
+
+    use super::{Received, OVERFLOW, OVERFLOW_SIZE};
+
+    pub async fn recv_batch(socket: &UdpSocket, slots: &mut [Vec<u8>], received: &mut Vec<Received>) -> std::io::Result<usize> {
+        let mut index = 0;
+
+        while index < slots.len() {
+            if index == 0 {
+                socket.readable().await?;
+            }
+
+            /* received whole into the overflow buffer, then copied to the slot */
+            let slot = &mut slots[index];
+            let res = OVERFLOW.with(|overflow| {
+                let mut overflow = overflow.borrow_mut();
+                let (bytes, source) = socket.try_recv_from(&mut overflow[..OVERFLOW_SIZE])?;
+                slot.clear();
+                slot.extend_from_slice(&overflow[..bytes]);
+                Ok::<_, std::io::Error>((bytes, source))
+            });
+
+            match res {
+                Ok((bytes, source)) => received.push(Received { bytes, source, truncated: false }),
+                /* spurious wakeup, wait for readiness again */
+                Err(error) if index == 0 && error.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+
+            index += 1;
+        }
+
+        Ok(received.len())
+    }
+
+    pub async fn send_batch(socket: &UdpSocket, packets: &[&[u8]], target: SocketAddr) -> std::io::Result<usize> {
+        for packet in packets {
+            socket.send_to(packet, target).await?;
+        }
+        Ok(packets.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+
+    async fn socket_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let b = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn test_batch_round_trip() {
+        let (sender, receiver) = socket_pair().await;
+        let target = receiver.local_addr().unwrap();
+
+        let packets: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let slices: Vec<&[u8]> = packets.iter().map(|v| &v[..]).collect();
+        send_batch(&sender, &slices, target).await.unwrap();
+
+        let mut batch = UdpBatch::new(2048, UDP_BATCH_SIZE);
+        let mut received = Vec::new();
+
+        while received.len() < packets.len() {
+            let count = batch.recv_from(&receiver).await.unwrap();
+            assert!(0 < count);
+
+            for i in 0..count {
+                assert_eq!(batch.source(i), sender.local_addr().unwrap());
+                assert!(!batch.is_truncated(i));
+                received.push(batch.packet(i).to_vec());
+            }
+        }
+
+        assert_eq!(received, packets);
+    }
+
+    #[tokio::test]
+    async fn test_batch_overflow() {
+        let (sender, receiver) = socket_pair().await;
+        let target = receiver.local_addr().unwrap();
+
+        let large = vec![7u8; 30_000];
+        sender.send_to(&large, target).await.unwrap();
+
+        let mut batch = UdpBatch::new(256, 4);
+        assert_eq!(batch.recv_from(&receiver).await.unwrap(), 1);
+        assert!(!batch.is_truncated(0));
+        assert_eq!(batch.packet(0), &large[..]);
+
+        /* slot shrinks back once the large datagram was handled */
+        sender.send_to(&[1u8; 10], target).await.unwrap();
+        assert_eq!(batch.recv_from(&receiver).await.unwrap(), 1);
+        assert_eq!(batch.packet(0), &[1u8; 10]);
+        assert!(batch.slots.iter().all(|slot| slot.capacity() < large.len()));
+    }
+
+    #[tokio::test]
+    async fn test_batch_overflow_keeps_every_datagram() {
+        let (sender, receiver) = socket_pair().await;
+        let target = receiver.local_addr().unwrap();
+
+        let mut batch = UdpBatch::new(256, 4);
+
+        /* fill the only slot so the batch grows */
+        sender.send_to(&[0u8; 10], target).await.unwrap();
+        assert_eq!(batch.recv_from(&receiver).await.unwrap(), 1);
+
+        /* both spill past their slot in the same receive */
+        sender.send_to(&[1u8; 300], target).await.unwrap();
+        sender.send_to(&[2u8; 400], target).await.unwrap();
+        assert_eq!(batch.recv_from(&receiver).await.unwrap(), 2);
+
+        assert!(!batch.is_truncated(0));
+        assert_eq!(batch.packet(0), &[1u8; 300]);
+        assert!(!batch.is_truncated(1));
+        assert_eq!(batch.packet(1), &[2u8; 400]);
+    }
+}
@@ -2,15 +2,21 @@ use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use arc_swap::ArcSwapOption;
 use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
 
 use playit_agent_proto::control_messages::UdpChannelDetails;
 
-use crate::network::udp_clients::UdpClients;
+use crate::tunnel::udp_batch::{send_batch, UdpBatch};
 use crate::tunnel::udp_proto::UdpFlow;
 use crate::utils::now_milli;
 
+/* one byte larger than any UDP datagram so a full buffer means the packet was truncated */
+pub const UDP_RECV_BUFFER_SIZE: usize = 65_536;
+
+const UDP_MAX_PAYLOAD_V4: usize = 65_507;
+const UDP_MAX_PAYLOAD_V6: usize = 65_527;
+
 #[derive(Clone)]
 pub struct UdpTunnel {
     inner: Arc<Inner>,
@@ -19,9 +25,11 @@ pub struct UdpTunnel {
 struct Inner {
     udp4: UdpSocket,
     udp6: Option<UdpSocket>,
-    details: RwLock<Option<UdpChannelDetails>>,
+    /* read for every packet, kept lock free */
+    details: ArcSwapOption<UdpChannelDetails>,
     last_confirm: AtomicU64,
     last_send: AtomicU64,
+    truncated: AtomicU64,
 }
 
 impl UdpTunnel {
@@ -30,15 +38,32 @@ impl UdpTunnel {
             inner: Arc::new(Inner {
                 udp4: UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await?,
                 udp6: UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await.ok(),
-                details: RwLock::new(None),
+                details: ArcSwapOption::empty(),
                 last_confirm: AtomicU64::new(0),
                 last_send: AtomicU64::new(0),
+                truncated: AtomicU64::new(0),
             })
         })
     }
 
     pub async fn is_setup(&self) -> bool {
-        self.inner.details.read().await.is_some()
+        self.inner.details.load().is_some()
+    }
+
+    /// Number of packets dropped because they were larger than the receive
+    /// buffer or could not fit in a tunnel datagram along with the flow footer.
+    pub fn truncated_packets(&self) -> u64 {
+        self.inner.truncated.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_truncated(&self) {
+        self.inner.truncated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Tunnel server has confirmed our token recently.
+    pub fn is_confirmed(&self) -> bool {
+        let last_confirm = self.inner.last_confirm.load(Ordering::SeqCst);
+        now_milli().saturating_sub(last_confirm) < 30_000
     }
 
     pub fn requires_resend(&self) -> bool {
@@ -61,29 +86,21 @@ impl UdpTunnel {
     }
 
     pub async fn set_udp_tunnel(&self, details: UdpChannelDetails) -> std::io::Result<()> {
-        {
-            let mut details_lock = self.inner.details.write().await;
-
-            /* if details haven't changed, exit */
-            if let Some(current) = &*details_lock {
-                if details.eq(current) {
-                    return Ok(());
-                }
+        /* if details haven't changed, exit */
+        if let Some(current) = &*self.inner.details.load() {
+            if details.eq(current) {
+                return Ok(());
             }
-
-            details_lock.replace(details.clone());
         }
 
+        self.inner.details.store(Some(Arc::new(details.clone())));
         self.send_token(&details).await
     }
 
     pub async fn resend_token(&self) -> std::io::Result<bool> {
-        let token = {
-            let lock = self.inner.details.read();
-            match &*lock.await {
-                Some(v) => v.clone(),
-                None => return Ok(false),
-            }
+        let token = match self.inner.details.load_full() {
+            Some(v) => v,
+            None => return Ok(false),
         };
 
         self.send_token(&token).await?;
@@ -110,54 +127,99 @@ impl UdpTunnel {
     }
 
     pub async fn send(&self, data: &mut Vec<u8>, flow: UdpFlow) -> std::io::Result<usize> {
+        let (socket, details) = self.get_sock()?;
+        self.append_flow(data, flow, details.tunnel_addr)?;
+        socket.send_to(data, details.tunnel_addr).await
+    }
+
+    /// Sends the listed packets of `batch` through the tunnel. Each packet gets its
+    /// flow footer appended; packets too large for the tunnel are dropped and counted.
+    pub async fn send_batch(&self, batch: &mut UdpBatch, packets: &[(usize, UdpFlow)]) -> std::io::Result<()> {
+        let (socket, details) = self.get_sock()?;
+
+        let mut ready = Vec::with_capacity(packets.len());
+        for (index, flow) in packets {
+            if self.append_flow(batch.packet_buffer(*index), *flow, details.tunnel_addr).is_ok() {
+                ready.push(*index);
+            }
+        }
+
+        let slices: Vec<&[u8]> = ready.iter().map(|index| batch.slot(*index)).collect();
+        send_batch(socket, &slices, details.tunnel_addr).await
+    }
+
+    fn append_flow(&self, data: &mut Vec<u8>, flow: UdpFlow, tunnel_addr: SocketAddr) -> std::io::Result<()> {
+        let max_payload = if tunnel_addr.is_ipv4() { UDP_MAX_PAYLOAD_V4 } else { UDP_MAX_PAYLOAD_V6 };
+        if max_payload < data.len() + flow.len() {
+            self.record_truncated();
+            tracing::error!(bytes = data.len(), max_payload, "packet too large to send through tunnel, dropping");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "packet too large for tunnel"));
+        }
+
         /* append flow to udp packet */
         let og_packet_len = data.len();
         data.resize(flow.len() + og_packet_len, 0);
         flow.write_to(&mut data[og_packet_len..]);
 
-        let (socket, tunnel_addr, _) = self.get_sock().await?;
-        socket.send_to(&data, tunnel_addr).await
+        Ok(())
     }
 
-    async fn get_sock(&self) -> std::io::Result<(&UdpSocket, SocketAddr, Arc<Vec<u8>>)> {
-        let lock = self.inner.details.read().await;
-
-        let details = match &*lock {
+    fn get_sock(&self) -> std::io::Result<(&UdpSocket, Arc<UdpChannelDetails>)> {
+        let details = match self.inner.details.load_full() {
             Some(v) => v,
             None => return Err(std::io::Error::new(std::io::ErrorKind::NotConnected, "udp tunnel not connected")),
         };
 
         Ok(if details.tunnel_addr.is_ipv4() {
-            (&self.inner.udp4, details.tunnel_addr, details.token.clone())
+            (&self.inner.udp4, details)
         } else {
-            (self.inner.udp6.as_ref().unwrap(), details.tunnel_addr, details.token.clone())
+            (self.inner.udp6.as_ref().unwrap(), details)
         })
     }
 
     pub async fn receive_from(&self, buffer: &mut [u8]) -> std::io::Result<UdpTunnelRx> {
-        let (udp, tunnel_addr, token) = self.get_sock().await?;
+        let (udp, details) = self.get_sock()?;
         let (bytes, remote) = udp.recv_from(buffer).await?;
 
-        if tunnel_addr != remote {
+        /* recv_from silently truncates, a full buffer means data was lost */
+        self.parse_packet(&buffer[..bytes], remote, buffer.len() <= bytes, &details)
+    }
+
+    /// Receives one or more datagrams from the tunnel, use [`UdpTunnel::read_batch_packet`]
+    /// to validate and extract the flow of each.
+    pub async fn receive_batch(&self, batch: &mut UdpBatch) -> std::io::Result<usize> {
+        let (udp, _) = self.get_sock()?;
+        batch.recv_from(udp).await
+    }
+
+    pub fn read_batch_packet(&self, batch: &UdpBatch, index: usize) -> std::io::Result<UdpTunnelRx> {
+        let (_, details) = self.get_sock()?;
+        self.parse_packet(batch.packet(index), batch.source(index), batch.is_truncated(index), &details)
+    }
+
+    fn parse_packet(&self, data: &[u8], remote: SocketAddr, truncated: bool, details: &UdpChannelDetails) -> std::io::Result<UdpTunnelRx> {
+        if details.tunnel_addr != remote {
             return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "got data from other source"));
         }
 
-        if buffer[..bytes].eq(&token[..]) {
-            self.inner.last_confirm.store(now_milli(), Ordering::SeqCst);
-            return Ok(UdpTunnelRx::ConfirmedConnection);
+        if truncated {
+            self.record_truncated();
+            tracing::error!(bytes = data.len(), "packet from tunnel truncated, dropping");
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "receive buffer too small, packet truncated"));
         }
 
-        if buffer.len() + UdpFlow::len_v4().max(UdpFlow::len_v6()) < bytes {
-            return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "receive buffer too small"));
+        if data.eq(&details.token[..]) {
+            self.inner.last_confirm.store(now_milli(), Ordering::SeqCst);
+            return Ok(UdpTunnelRx::ConfirmedConnection);
         }
 
-        let footer = match UdpFlow::from_tail(&buffer[..bytes]) {
+        let footer = match UdpFlow::from_tail(data) {
             Some(v) => v,
             None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "failed to extract udp footer")),
         };
 
         Ok(UdpTunnelRx::ReceivedPacket {
-            bytes: bytes - footer.len(),
+            bytes: data.len() - footer.len(),
             flow: footer,
         })
     }
@@ -167,3 +229,86 @@ pub enum UdpTunnelRx {
     ReceivedPacket { bytes: usize, flow: UdpFlow },
     ConfirmedConnection,
 }
+
+#[cfg(test)]
+mod test {
+    use playit_agent_proto::control_messages::UdpChannelDetails;
+
+    use super::*;
+
+    async fn connected_tunnel() -> (UdpTunnel, UdpSocket, SocketAddr) {
+        let server = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+
+        let tunnel = UdpTunnel::new().await.unwrap();
+        tunnel.set_udp_tunnel(UdpChannelDetails {
+            tunnel_addr: server.local_addr().unwrap(),
+            token: Arc::new(vec![1, 2, 3, 4]),
+        }).await.unwrap();
+
+        let mut buffer = [0u8; 16];
+        let (_, agent_addr) = server.recv_from(&mut buffer).await.unwrap();
+
+        (tunnel, server, agent_addr)
+    }
+
+    fn test_flow() -> UdpFlow {
+        UdpFlow::V4 {
+            src: "10.0.0.1:1234".parse().unwrap(),
+            dst: "147.185.221.1:5000".parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_jumbo_packet() {
+        let (tunnel, server, agent_addr) = connected_tunnel().await;
+
+        let mut packet = vec![7u8; 9000];
+        packet.resize(9000 + UdpFlow::len_v4(), 0);
+        test_flow().write_to(&mut packet[9000..]);
+        server.send_to(&packet, agent_addr).await.unwrap();
+
+        let mut buffer = vec![0u8; UDP_RECV_BUFFER_SIZE];
+        match tunnel.receive_from(&mut buffer).await.unwrap() {
+            UdpTunnelRx::ReceivedPacket { bytes, flow } => {
+                assert_eq!(bytes, 9000);
+                assert_eq!(flow, test_flow());
+                assert!(buffer[..bytes].iter().all(|v| *v == 7));
+            }
+            UdpTunnelRx::ConfirmedConnection => panic!("expected packet"),
+        }
+
+        assert_eq!(tunnel.truncated_packets(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_packet_counted() {
+        let (tunnel, server, agent_addr) = connected_tunnel().await;
+
+        let mut packet = vec![7u8; 3000];
+        packet.resize(3000 + UdpFlow::len_v4(), 0);
+        test_flow().write_to(&mut packet[3000..]);
+        server.send_to(&packet, agent_addr).await.unwrap();
+
+        let mut buffer = vec![0u8; 2048];
+        assert!(tunnel.receive_from(&mut buffer).await.is_err());
+        assert_eq!(tunnel.truncated_packets(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_oversized_packet() {
+        let (tunnel, server, agent_addr) = connected_tunnel().await;
+
+        let mut data = vec![0u8; UDP_MAX_PAYLOAD_V4];
+        assert!(tunnel.send(&mut data, test_flow()).await.is_err());
+        assert_eq!(tunnel.truncated_packets(), 1);
+
+        let max_data = UDP_MAX_PAYLOAD_V4 - UdpFlow::len_v4();
+        let mut data = vec![0u8; max_data];
+        tunnel.send(&mut data, test_flow()).await.unwrap();
+
+        let mut buffer = vec![0u8; UDP_RECV_BUFFER_SIZE];
+        let (bytes, source) = server.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(source.port(), agent_addr.port());
+        assert_eq!(bytes, UDP_MAX_PAYLOAD_V4);
+    }
+}
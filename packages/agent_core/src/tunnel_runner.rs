@@ -4,38 +4,142 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::TlsAcceptor;
 use tracing::Instrument;
 
+use playit_agent_proto::control_feed::NewClient;
 use playit_agent_proto::PortProto;
 
+use crate::agent::AgentEndpoints;
+use crate::events::{AgentEvent, AgentEvents};
 use crate::network::address_lookup::{AddressLookup, MatchAddress};
-use crate::network::tcp_clients::TcpClients;
-use crate::network::tcp_pipe::pipe;
-use crate::network::udp_clients::UdpClients;
+use crate::network::backend_pool::{BackendGuard, BackendPool};
+use crate::network::bandwidth::{ClientShapers, TunnelBandwidth};
+use crate::network::geo_filter::{GeoFilter, GeoLookup};
+use crate::network::local_health::{LocalHealth, LocalTargetReport};
+use crate::network::local_target::LocalTarget;
+use crate::network::minecraft::{self, MinecraftSettings};
+use crate::network::packet_filter::{self, FilterHook, FilterRequest, PacketFilter};
+use crate::network::http_routing::{self, HttpSettings};
+use crate::network::tls_sni::{self, SniRoutes};
+use crate::network::tls_termination::{TlsTermination, TLS_HANDSHAKE_TIMEOUT};
+use crate::network::rate_limit::{ConnectionRateLimiter, RateLimitedCounts, RateLimits};
+use crate::network::tunnel_handler::{HandlerStream, TunnelHandler, TunnelStream};
+use crate::network::tcp_clients::{ReservedClient, TcpClient, TcpClients, TcpClientUsage};
+use crate::network::tcp_pipe::pipe_bidirectional_shaped;
+use crate::network::tunnel_settings::TcpSettings;
+use crate::network::udp_clients::{RakNetSessionReport, UdpClients, UdpClientStats, UdpForwardErrorCounts};
+use crate::supervisor::{AgentHealth, Backoff, HealthReport, HealthStatus, supervise};
 use crate::tunnel::setup::SetupError;
 use crate::tunnel::simple_tunnel::SimpleTunnel;
-use crate::tunnel::udp_tunnel::UdpTunnelRx;
+use crate::tunnel::tcp_tunnel::ClaimError;
+use crate::tunnel::udp_batch::{UDP_BATCH_SIZE, UDP_SLOT_SIZE, UdpBatch};
+use crate::tunnel::udp_tunnel::{UdpTunnel, UdpTunnelRx};
+use crate::utils::log_throttle::LogThrottle;
+use crate::utils::now_milli;
 
 pub struct TunnelRunner<L: AddressLookup> {
+    endpoints: AgentEndpoints,
+    secret_key: String,
     lookup: Arc<L>,
     tunnel: SimpleTunnel,
     udp_clients: UdpClients<Arc<L>>,
     tcp_clients: TcpClients,
     keep_running: Arc<AtomicBool>,
+    health: Arc<AgentHealth>,
+    local_health: Arc<LocalHealth>,
+    rate_limiter: Arc<ConnectionRateLimiter>,
+    bandwidth: Arc<TunnelBandwidth>,
+    geo_filter: Arc<GeoFilter>,
+    tls_termination: Arc<TlsTermination>,
+    filter: Arc<FilterHook>,
+    events: AgentEvents,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentStats {
+    pub health: HealthReport,
+    pub local_targets: Vec<LocalTargetReport>,
+    pub tcp_clients: usize,
+    pub tcp_usage: TcpClientUsage,
+    pub udp_flows: usize,
+    pub udp_forward_errors: UdpForwardErrorCounts,
+    pub udp_truncated_packets: u64,
+    pub raknet_sessions: Vec<RakNetSessionReport>,
+    pub rate_limited: RateLimitedCounts,
+    pub geo_denied: u64,
+    /* TCP clients and UDP flows rejected by the packet filter */
+    pub filter_rejected: u64,
+}
+
+/// Handle for reading stats of a runner after it has been started.
+#[derive(Clone)]
+pub struct RunnerStats {
+    health: Arc<AgentHealth>,
+    local_health: Arc<LocalHealth>,
+    rate_limiter: Arc<ConnectionRateLimiter>,
+    geo_filter: Arc<GeoFilter>,
+    filter: Arc<FilterHook>,
+    tcp_clients: TcpClients,
+    udp_clients: UdpClientStats,
+    udp_tunnel: UdpTunnel,
+}
+
+impl RunnerStats {
+    pub async fn snapshot(&self) -> AgentStats {
+        AgentStats {
+            health: self.health.report(),
+            local_targets: self.local_health.report(),
+            tcp_clients: self.tcp_clients.client_count().await,
+            tcp_usage: self.tcp_clients.usage().await,
+            udp_flows: self.udp_clients.flow_count().await,
+            udp_forward_errors: self.udp_clients.forward_errors(),
+            udp_truncated_packets: self.udp_tunnel.truncated_packets(),
+            raknet_sessions: self.udp_clients.raknet_sessions().await,
+            rate_limited: self.rate_limiter.limited(),
+            geo_denied: self.geo_filter.denied(),
+            filter_rejected: self.filter.rejected(),
+        }
+    }
 }
 
 impl<L: AddressLookup + Sync + Send> TunnelRunner<L> {
     pub async fn new(secret_key: String, lookup: Arc<L>) -> Result<Self, SetupError> {
-        let tunnel = SimpleTunnel::setup(secret_key).await?;
-        let udp_clients = UdpClients::new(tunnel.udp_tunnel(), lookup.clone());
+        Self::setup(AgentEndpoints::default(), secret_key, lookup, AgentEvents::default()).await
+    }
+
+    pub async fn setup(endpoints: AgentEndpoints, secret_key: String, lookup: Arc<L>, events: AgentEvents) -> Result<Self, SetupError> {
+        let tunnel = SimpleTunnel::setup_with_udp(&endpoints, secret_key.clone(), UdpTunnel::new().await?).await?;
+
+        let mut udp_clients = UdpClients::new(tunnel.udp_tunnel(), lookup.clone());
+        udp_clients.events = events.clone();
+
+        let bandwidth = Arc::new(TunnelBandwidth::default());
+        udp_clients.bandwidth = bandwidth.clone();
+
+        let local_health = Arc::new(LocalHealth::new(events.clone()));
+        udp_clients.local_health = local_health.clone();
 
         Ok(TunnelRunner {
+            endpoints,
+            secret_key,
             lookup,
             tunnel,
             udp_clients,
             tcp_clients: TcpClients::new(),
             keep_running: Arc::new(AtomicBool::new(true)),
+            health: Arc::new(AgentHealth::new(events.clone())),
+            local_health,
+            rate_limiter: Arc::new(ConnectionRateLimiter::default()),
+            bandwidth,
+            geo_filter: Arc::new(GeoFilter::default()),
+            tls_termination: Arc::new(TlsTermination::default()),
+            filter: Arc::new(FilterHook::default()),
+            events,
         })
     }
 
@@ -44,92 +148,892 @@ impl<L: AddressLookup + Sync + Send> TunnelRunner<L> {
         self.udp_clients.use_special_lan = set_use;
     }
 
+    /// Caps concurrent TCP clients across all tunnels.
+    pub fn set_max_tcp_clients(&mut self, max: Option<usize>) {
+        self.tcp_clients.max_connections = max;
+    }
+
+    /// Limits new TCP clients and UDP flows, replaces any previous limits.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limiter = Arc::new(ConnectionRateLimiter::new(limits));
+        self.udp_clients.rate_limiter = self.rate_limiter.clone();
+    }
+
+    /// Country and ASN source for the tunnels' `GeoRules`.
+    pub fn set_geo_lookup(&mut self, lookup: Option<Arc<dyn GeoLookup>>) {
+        self.geo_filter = Arc::new(GeoFilter::new(lookup));
+        self.udp_clients.geo_filter = self.geo_filter.clone();
+    }
+
+    /// Filter for new TCP clients and UDP flows, replaces any previous filter.
+    pub fn set_packet_filter(&mut self, filter: Option<Arc<dyn PacketFilter>>) {
+        self.filter = Arc::new(FilterHook::new(filter));
+        self.udp_clients.filter = self.filter.clone();
+    }
+
     pub fn keep_running(&self) -> Arc<AtomicBool> {
         self.keep_running.clone()
     }
 
-    pub async fn run(mut self) {
-        let mut tunnel = self.tunnel;
-        let mut udp = tunnel.udp_tunnel();
+    pub fn health(&self) -> Arc<AgentHealth> {
+        self.health.clone()
+    }
 
-        let tunnel_run = self.keep_running.clone();
-        let tunnel_task = tokio::spawn(async move {
-            while tunnel_run.load(Ordering::SeqCst) {
-                if let Some(new_client) = tunnel.update().await {
-                    let clients = self.tcp_clients.clone();
-                    let span = tracing::info_span!("tcp client", ?new_client);
+    pub fn local_health(&self) -> Arc<LocalHealth> {
+        self.local_health.clone()
+    }
 
-                    let local_addr = match self.lookup.local_mapping(new_client.connect_addr, PortProto::Tcp) {
-                        Some(addr) => addr,
-                        None => {
-                            tracing::info!("could not find local address for connection");
-                            continue;
-                        }
-                    };
+    pub fn events(&self) -> AgentEvents {
+        self.events.clone()
+    }
 
-                    tokio::spawn(async move {
-                        let tunnel_conn = match clients.connect(new_client.clone()).await {
-                            Ok(Some(client)) => client,
-                            Ok(None) => return,
-                            Err(error) => {
-                                tracing::error!(?error, "failed to accept new client");
-                                return;
-                            }
-                        };
+    pub fn stats(&self) -> RunnerStats {
+        RunnerStats {
+            health: self.health.clone(),
+            local_health: self.local_health.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            geo_filter: self.geo_filter.clone(),
+            filter: self.filter.clone(),
+            tcp_clients: self.tcp_clients.clone(),
+            udp_clients: self.udp_clients.stats(),
+            udp_tunnel: self.tunnel.udp_tunnel(),
+        }
+    }
 
-                        tracing::info!("connected to TCP tunnel");
+    pub async fn run(self) {
+        let udp = self.tunnel.udp_tunnel();
+        let udp_clients = Arc::new(self.udp_clients);
+        let health = self.health;
+        let local_health = self.local_health;
 
-                        let local_conn = match TcpStream::connect(local_addr).await {
-                            Ok(v) => v,
-                            Err(error) => {
-                                tracing::error!(?error, "failed to connect to local server");
-                                return;
-                            }
-                        };
+        let mut initial_tunnel = Some(self.tunnel);
+        let endpoints = self.endpoints;
+        let secret_key = self.secret_key;
+        let lookup = self.lookup;
 
-                        let (tunnel_read, tunnel_write) = tunnel_conn.into_split();
-                        let (local_read, local_write) = local_conn.into_split();
+        let local_checks = {
+            let local_health = local_health.clone();
+            let lookup = lookup.clone();
+            let keep_running = self.keep_running.clone();
+            async move { local_health.run(lookup, keep_running).await }
+        };
 
-                        tokio::spawn(pipe(tunnel_read, local_write));
-                        tokio::spawn(pipe(local_read, tunnel_write));
-                    }.instrument(span));
-                }
+        let control_ctx = Arc::new(ControlContext {
+            lookup,
+            tcp_clients: self.tcp_clients,
+            health: health.clone(),
+            local_health,
+            rate_limiter: self.rate_limiter,
+            bandwidth: self.bandwidth,
+            geo_filter: self.geo_filter,
+            tls_termination: self.tls_termination,
+            filter: self.filter,
+            events: self.events,
+            keep_running: self.keep_running.clone(),
+        });
+        let control_udp = udp.clone();
+
+        let control = supervise("control", &health.control, self.keep_running.clone(), Backoff::default(), move || {
+            /* first run uses the tunnel from new, restarts need a new control channel */
+            let tunnel = initial_tunnel.take();
+            let endpoints = endpoints.clone();
+            let secret_key = secret_key.clone();
+            let udp = control_udp.clone();
+            let ctx = control_ctx.clone();
+
+            async move {
+                let tunnel = match tunnel {
+                    Some(tunnel) => tunnel,
+                    None => match SimpleTunnel::setup_with_udp(&endpoints, secret_key, udp).await {
+                        Ok(tunnel) => tunnel,
+                        Err(error) => {
+                            tracing::error!(?error, "failed to setup control channel");
+                            return;
+                        }
+                    },
+                };
+
+                control_task(tunnel, ctx).await
             }
         });
 
-        let mut udp_clients = self.udp_clients;
+        let udp_health = health.clone();
         let udp_run = self.keep_running.clone();
 
-        let udp_task = tokio::spawn(async move {
-            let mut buffer = vec![0u8; 2048];
-            let mut had_success = false;
+        let udp_channel = supervise("udp", &health.udp_channel, self.keep_running.clone(), Backoff::default(), move || {
+            udp_task(udp.clone(), udp_clients.clone(), udp_health.clone(), udp_run.clone())
+        });
 
-            while udp_run.load(Ordering::SeqCst) {
-                let rx = match tokio::time::timeout(Duration::from_secs(1), udp.receive_from(&mut buffer)).await {
-                    Ok(Ok(v)) => v,
-                    Ok(Err(error)) => {
-                        if had_success {
-                            tracing::error!(?error, "got error");
-                        }
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        continue;
+        tokio::join!(control, udp_channel, local_checks);
+    }
+}
+
+/* shared by the control channel and the TCP clients it accepts */
+struct ControlContext<L: AddressLookup> {
+    lookup: Arc<L>,
+    tcp_clients: TcpClients,
+    health: Arc<AgentHealth>,
+    local_health: Arc<LocalHealth>,
+    rate_limiter: Arc<ConnectionRateLimiter>,
+    bandwidth: Arc<TunnelBandwidth>,
+    geo_filter: Arc<GeoFilter>,
+    tls_termination: Arc<TlsTermination>,
+    filter: Arc<FilterHook>,
+    events: AgentEvents,
+    keep_running: Arc<AtomicBool>,
+}
+
+/* decided on the control channel before a client's task is spawned */
+struct TcpClientRoute {
+    match_addr: Option<MatchAddress>,
+    local_target: LocalTarget,
+    settings: TcpSettings,
+    shapers: ClientShapers,
+    tls: Option<TlsAcceptor>,
+    minecraft: Option<MinecraftSettings>,
+    http: Option<HttpSettings>,
+    sni_routes: Option<Arc<SniRoutes>>,
+}
+
+async fn control_task<L: AddressLookup + Sync + Send>(mut tunnel: SimpleTunnel, ctx: Arc<ControlContext<L>>) {
+    ctx.health.tcp_accept.set(HealthStatus::Healthy);
+    let rate_limit_log = LogThrottle::new(Duration::from_secs(5));
+
+    while ctx.keep_running.load(Ordering::SeqCst) {
+        let new_client = tunnel.update().await;
+
+        ctx.health.control.set(match tunnel.is_connected() {
+            true => HealthStatus::Healthy,
+            false => HealthStatus::Degraded,
+        });
+
+        if let Some(new_client) = new_client {
+            /* limited clients are left unclaimed before any task or local connection is made */
+            if let Err(limited) = ctx.rate_limiter.check(new_client.peer_addr.ip()) {
+                if let Some(suppressed) = rate_limit_log.allow() {
+                    tracing::warn!(?limited, peer_addr = %new_client.peer_addr, suppressed, "new TCP client rate limited");
+                }
+                continue;
+            }
+
+            let span = tracing::info_span!("tcp client", ?new_client);
+            let Some(route) = span.in_scope(|| ctx.route_tcp_client(&new_client)) else {
+                continue;
+            };
+
+            tokio::spawn(ctx.clone().serve_tcp_client(new_client, route).instrument(span));
+        }
+    }
+}
+
+impl<L: AddressLookup + Sync + Send> ControlContext<L> {
+    /* None if the client should be left unclaimed */
+    fn route_tcp_client(&self, new_client: &NewClient) -> Option<TcpClientRoute> {
+        let local_target = match self.lookup.local_target_mapping(new_client.connect_addr, PortProto::Tcp) {
+            Some(target) => target,
+            None => {
+                tracing::info!("could not find local address for connection");
+                return None;
+            }
+        };
+
+        let match_addr = self.lookup.tunnel_match_address(new_client.connect_addr, PortProto::Tcp);
+        let tunnel_settings = match_addr
+            .map(|match_addr| self.lookup.tunnel_settings(match_addr, PortProto::Tcp))
+            .unwrap_or_default();
+        let settings = tunnel_settings.tcp;
+
+        if let Err(denied) = self.geo_filter.check(new_client.peer_addr.ip(), &tunnel_settings.geo) {
+            tracing::info!(?denied, "client denied by geo rules, not claiming");
+            return None;
+        }
+
+        let shapers = match match_addr {
+            Some(match_addr) => self.bandwidth.client_shapers(match_addr, &tunnel_settings.bandwidth),
+            None => ClientShapers::default(),
+        };
+
+        let tls = match match_addr.filter(|_| tunnel_settings.tls.enabled) {
+            Some(match_addr) => match self.tls_termination.acceptor(match_addr, &tunnel_settings.tls) {
+                Ok(acceptor) => Some(acceptor),
+                Err(error) => {
+                    tracing::error!(?error, "failed to load tls certificates, not claiming client");
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        /* handlers are given the stream as is, there is nowhere to replay the handshake */
+        let minecraft = Some(tunnel_settings.minecraft)
+            .filter(|minecraft| minecraft.enabled && !matches!(local_target, LocalTarget::Handler(_)));
+        let http = Some(tunnel_settings.http)
+            .filter(|http| http.enabled && minecraft.is_none() && !matches!(local_target, LocalTarget::Handler(_)));
+        let sni_routes = match_addr
+            .and_then(|match_addr| self.lookup.sni_routes(match_addr, PortProto::Tcp))
+            .filter(|routes| !routes.is_empty() && !matches!(local_target, LocalTarget::Handler(_)));
+
+        /* unclaimed clients get the tunnel server's offline response */
+        if settings.skip_claim_when_down && matches!(match_addr, Some(match_addr) if self.local_health.is_down(match_addr)) {
+            tracing::info!("local target is down, not claiming client");
+            return None;
+        }
+
+        Some(TcpClientRoute { match_addr, local_target, settings, shapers, tls, minecraft, http, sni_routes })
+    }
+
+    async fn serve_tcp_client(self: Arc<Self>, new_client: NewClient, route: TcpClientRoute) {
+        let TcpClientRoute { match_addr, local_target, settings, shapers, tls, minecraft, http, sni_routes } = route;
+
+        let peer_addr = new_client.peer_addr;
+        let tunnel_addr = new_client.connect_addr;
+        let started_at = now_milli();
+
+        let filter_request = FilterRequest { proto: PortProto::Tcp, peer_addr, tunnel_addr };
+        let peek_len = self.filter.peek_len();
+
+        /* filters that look at data can only decide once the client is claimed */
+        let mut filter_route = None;
+        if self.filter.is_enabled() && peek_len == 0 {
+            match self.filter.check(&filter_request, &[]).await {
+                Ok(route) => filter_route = route,
+                Err(_) => {
+                    tracing::info!("client rejected by packet filter, not claiming");
+                    return;
+                }
+            }
+        }
+
+        let reserved = match self.tcp_clients.reserve(new_client, match_addr, settings.max_connections).await {
+            Ok(Some(reserved)) => reserved,
+            Ok(None) => return,
+            Err(limit) => {
+                tracing::warn!(?limit, "connection limit reached, not claiming client");
+                return;
+            }
+        };
+
+        /* when routing, the first packet decides which backend to connect to */
+        let routed = minecraft.is_some() || http.is_some() || sni_routes.is_some() || filter_route.is_some() || 0 < peek_len;
+        let connect_first = Some(local_target.clone()).filter(|_| settings.connect_before_claim && !routed);
+
+        let (tunnel_conn, local) = match claim_client(reserved, connect_first, peer_addr, settings).await {
+            Ok(claimed) => claimed,
+            Err(ClaimFailed::LocalDown) => {
+                record_local_health(&self.local_health, match_addr, false);
+                return;
+            }
+            /* the client went away or was claimed elsewhere, not a problem with the agent */
+            Err(ClaimFailed::Claim(ClaimError::Rejected)) => {
+                tracing::warn!("claim rejected by tunnel server");
+                return;
+            }
+            Err(ClaimFailed::Claim(error)) => {
+                self.health.tcp_accept.set(HealthStatus::Degraded);
+                tracing::error!(?error, "failed to accept new client");
+                return;
+            }
+        };
+
+        self.health.tcp_accept.set(HealthStatus::Healthy);
+        tracing::info!("connected to TCP tunnel");
+
+        let mut route = None;
+        let lan_addr = tunnel_conn.local_addr().ok();
+        let peek_sni = tls.is_none() && minecraft.is_none() && http.is_none();
+
+        let mut tunnel_conn: Box<dyn TunnelStream> = match tls {
+            Some(acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(tunnel_conn)).await {
+                Ok(Ok(stream)) => {
+                    /* server name is known from the handshake, no need to peek */
+                    if let Some(sni_routes) = &sni_routes {
+                        route = stream.get_ref().1.sni_hostname().and_then(|server_name| sni_routes.target(server_name));
                     }
-                    Err(_) => continue,
-                };
+                    Box::new(stream)
+                }
+                Ok(Err(error)) => {
+                    tracing::info!(?error, "tls handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    tracing::info!("timeout during tls handshake");
+                    return;
+                }
+            },
+            None => Box::new(tunnel_conn),
+        };
+
+        let mut offline_status = None;
+        /* read from the player, replayed to the local server before piping */
+        let mut consumed = Vec::new();
+
+        if let Some(minecraft) = &minecraft {
+            let session = match tokio::time::timeout(minecraft.handshake_timeout(), minecraft::read_session(&mut tunnel_conn)).await {
+                Ok(Ok(session)) => session,
+                Ok(Err(error)) => {
+                    tracing::info!(?error, "failed to read minecraft handshake");
+                    return;
+                }
+                Err(_) => {
+                    tracing::info!("timeout reading minecraft handshake");
+                    return;
+                }
+            };
+
+            if let Some(handshake) = session.handshake {
+                route = minecraft.route(&handshake).map(LocalTarget::Addr).or(route);
+
+                if let Some(username) = &session.username {
+                    tracing::info!(%username, hostname = %handshake.hostname(), protocol_version = handshake.protocol_version, "minecraft player logging in");
+                }
+
+                if handshake.is_status() {
+                    offline_status = minecraft.offline_motd.clone().map(|motd| (handshake, motd));
+                }
+            }
+
+            consumed = session.consumed;
+        }
+
+        let mut http_request = None;
+        if let Some(http) = &http {
+            match tokio::time::timeout(http.head_timeout(), http_routing::read_first_request(&mut tunnel_conn)).await {
+                Ok(Ok(request)) => {
+                    route = http.route(&request.head).map(LocalTarget::Addr).or(route);
+                    http_request = Some(request);
+                }
+                Ok(Err(error)) => {
+                    tracing::info!(?error, "failed to read http request");
+                    let _ = tunnel_conn.write_all(http_routing::BAD_REQUEST).await;
+                    return;
+                }
+                Err(_) => {
+                    tracing::info!("timeout reading http request");
+                    return;
+                }
+            }
+        }
+
+        if let Some(sni_routes) = sni_routes.as_ref().filter(|_| peek_sni) {
+            match tokio::time::timeout(settings.client_hello_timeout(), tls_sni::read_client_hello(&mut tunnel_conn)).await {
+                Ok(Ok(hello)) => {
+                    route = hello.server_name.as_deref().and_then(|server_name| sni_routes.target(server_name));
+                    tracing::info!(server_name = ?hello.server_name, routed = route.is_some(), "read tls client hello");
+                    consumed = hello.consumed;
+                }
+                Ok(Err(error)) => {
+                    tracing::info!(?error, "failed to read tls client hello");
+                    return;
+                }
+                Err(_) => {
+                    tracing::info!("timeout reading tls client hello");
+                    return;
+                }
+            }
+        }
+
+        if self.filter.is_enabled() && 0 < peek_len {
+            /* routing modes have already read the start of the stream */
+            if consumed.is_empty() && http_request.is_none() {
+                match packet_filter::peek_stream(&mut tunnel_conn, peek_len).await {
+                    Ok(data) => consumed = data,
+                    Err(error) => {
+                        tracing::info!(?error, "failed to read data for packet filter");
+                        return;
+                    }
+                }
+            }
 
-                had_success = true;
+            let data = match &http_request {
+                Some(request) => request.head.encode(None),
+                None => consumed.clone(),
+            };
 
-                match rx {
-                    UdpTunnelRx::ReceivedPacket { bytes, flow } => {
-                        // tracing::info!(bytes, ?flow, "got packet");
-                        udp_clients.forward_packet(&flow, &buffer[..bytes]).await.unwrap();
+            match self.filter.check(&filter_request, &data).await {
+                Ok(route) => filter_route = route,
+                Err(_) => {
+                    tracing::info!("client rejected by packet filter");
+                    return;
+                }
+            }
+        }
+
+        /* the filter's route wins over protocol routes */
+        let route = filter_route.map(LocalTarget::Addr).or(route);
+
+        let local = match local {
+            Some(local) => local,
+            None => match connect_local(route.unwrap_or(local_target), peer_addr, &settings).await {
+                Some(local) => local,
+                None => {
+                    record_local_health(&self.local_health, match_addr, false);
+
+                    if let (Some(minecraft), Some((handshake, motd))) = (&minecraft, offline_status) {
+                        let reply = minecraft::respond_offline_status(&mut tunnel_conn, &handshake, &motd);
+                        if let Ok(Err(error)) = tokio::time::timeout(minecraft.handshake_timeout(), reply).await {
+                            tracing::info!(?error, "failed to send offline minecraft status");
+                        }
                     }
-                    UdpTunnelRx::ConfirmedConnection => {}
+                    return;
+                }
+            },
+        };
+
+        let (mut local_conn, target_name, _backend) = match local {
+            LocalConn::Stream { conn, target_name, backend } => (conn, target_name, backend),
+            LocalConn::Handler(handler) => {
+                self.events.emit(AgentEvent::TcpClientConnected { peer_addr, tunnel_addr, local_target: "handler".to_string() });
+
+                /* the handler owns the stream, the client is closed once it is dropped */
+                let events = self.events.clone();
+                let stream = HandlerStream::new(tunnel_conn, consumed, move |summary| {
+                    tracing::info!(?summary, "TCP client closed");
+                    events.emit(AgentEvent::TcpClientClosed {
+                        peer_addr,
+                        tunnel_addr,
+                        local_target: "handler".to_string(),
+                        lan_addr,
+                        started_at,
+                        summary,
+                    });
+                });
+
+                handler.handle_tcp(Box::new(stream), peer_addr, tunnel_addr);
+                return;
+            }
+        };
+
+        record_local_health(&self.local_health, match_addr, true);
+
+        self.events.emit(AgentEvent::TcpClientConnected { peer_addr, tunnel_addr, local_target: target_name.clone() });
+
+        if let Err(error) = local_conn.write_all(&consumed).await {
+            tracing::error!(?error, "failed to forward first packet to local server");
+            return;
+        }
+
+        let mut summary = match (&http, http_request) {
+            (Some(http), Some(request)) => http_routing::pipe_requests(
+                tunnel_conn,
+                request,
+                local_conn,
+                peer_addr.ip(),
+                http,
+                settings.idle_timeout(),
+                &shapers,
+            ).await,
+            _ => pipe_bidirectional_shaped(tunnel_conn, local_conn, settings.idle_timeout(), &shapers).await,
+        };
+        summary.tunnel_to_local += consumed.len() as u64;
+        tracing::info!(?summary, "TCP client closed");
+
+        self.events.emit(AgentEvent::TcpClientClosed {
+            peer_addr,
+            tunnel_addr,
+            local_target: target_name,
+            lan_addr,
+            started_at,
+            summary,
+        });
+    }
+}
+
+fn record_local_health(local_health: &LocalHealth, match_addr: Option<MatchAddress>, healthy: bool) {
+    if let Some(match_addr) = match_addr {
+        local_health.record(match_addr, healthy);
+    }
+}
+
+enum ClaimFailed {
+    /* the local connection made before claiming failed */
+    LocalDown,
+    Claim(ClaimError),
+}
+
+/* leaving the client unclaimed lets the tunnel server refuse it cleanly */
+async fn claim_client(reserved: ReservedClient, connect_first: Option<LocalTarget>, peer_addr: SocketAddr, settings: TcpSettings) -> Result<(TcpClient, Option<LocalConn>), ClaimFailed> {
+    let local = match connect_first {
+        Some(local_target) => Some(connect_local(local_target, peer_addr, &settings).await.ok_or(ClaimFailed::LocalDown)?),
+        None => None,
+    };
+
+    let client = reserved.claim(settings).await.map_err(ClaimFailed::Claim)?;
+    Ok((client, local))
+}
+
+enum LocalConn {
+    Stream {
+        conn: Box<dyn TunnelStream>,
+        target_name: String,
+        /* keeps the pool's connection count until the client closes */
+        backend: Option<BackendGuard>,
+    },
+    Handler(Arc<dyn TunnelHandler>),
+}
+
+async fn connect_local(local_target: LocalTarget, peer_addr: SocketAddr, settings: &TcpSettings) -> Option<LocalConn> {
+    let target_name = local_target.to_string();
+
+    let conn: Box<dyn TunnelStream> = match local_target {
+        LocalTarget::Addr(local_addr) => match connect_local_tcp(local_addr, settings).await {
+            Ok(v) => Box::new(v),
+            Err(error) => {
+                tracing::error!(?error, %local_addr, "failed to connect to local server");
+                return None;
+            }
+        },
+        #[cfg(unix)]
+        LocalTarget::Unix(path) => match tokio::time::timeout(settings.local_connect_timeout(), UnixStream::connect(&path)).await {
+            Ok(Ok(v)) => Box::new(v),
+            Ok(Err(error)) => {
+                tracing::error!(?error, ?path, "failed to connect to local unix socket");
+                return None;
+            }
+            Err(_) => {
+                tracing::error!(?path, "timeout connecting to local unix socket");
+                return None;
+            }
+        },
+        LocalTarget::Handler(handler) => return Some(LocalConn::Handler(handler)),
+        LocalTarget::Pool { pool, port_offset } => return match connect_pool_tcp(&pool, port_offset, peer_addr, settings).await {
+            Some((conn, local_addr, backend)) => Some(LocalConn::Stream {
+                target_name: local_addr.to_string(),
+                conn: Box::new(conn),
+                backend: Some(backend),
+            }),
+            None => {
+                tracing::error!(backends = pool.len(), "failed to connect to any backend in pool");
+                None
+            }
+        },
+    };
+
+    Some(LocalConn::Stream { conn, target_name, backend: None })
+}
+
+async fn connect_local_tcp(local_addr: SocketAddr, settings: &TcpSettings) -> std::io::Result<TcpStream> {
+    let local_conn = match tokio::time::timeout(settings.local_connect_timeout(), TcpStream::connect(local_addr)).await {
+        Ok(res) => res?,
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "local connect timeout")),
+    };
+
+    if let Err(error) = settings.apply(&local_conn) {
+        tracing::warn!(?error, "failed to apply settings to local socket");
+    }
+
+    Ok(local_conn)
+}
+
+/* tries backends until one accepts, ejecting the ones that refuse */
+async fn connect_pool_tcp(pool: &Arc<BackendPool>, port_offset: u16, peer_addr: SocketAddr, settings: &TcpSettings) -> Option<(TcpStream, SocketAddr, BackendGuard)> {
+    for _ in 0..pool.len() {
+        let backend = pool.select(peer_addr)?;
+
+        let local_addr = match backend.addr().port().checked_add(port_offset) {
+            Some(port) => SocketAddr::new(backend.addr().ip(), port),
+            None => {
+                tracing::warn!(backend = %backend.addr(), port_offset, "port offset is past the last port for backend");
+                continue;
+            }
+        };
+
+        match connect_local_tcp(local_addr, settings).await {
+            Ok(local_conn) => {
+                backend.mark_healthy();
+                return Some((local_conn, local_addr, backend));
+            }
+            Err(error) => {
+                tracing::warn!(?error, %local_addr, "failed to connect to backend");
+                backend.mark_unhealthy();
+            }
+        }
+    }
+
+    None
+}
+
+async fn udp_task<L: AddressLookup + Sync + Send>(
+    udp: UdpTunnel,
+    udp_clients: Arc<UdpClients<Arc<L>>>,
+    health: Arc<AgentHealth>,
+    keep_running: Arc<AtomicBool>,
+) {
+    let mut batch = UdpBatch::new(UDP_SLOT_SIZE, UDP_BATCH_SIZE);
+    let mut had_success = false;
+
+    while keep_running.load(Ordering::SeqCst) {
+        health.udp_channel.set(match udp.is_confirmed() {
+            true => HealthStatus::Healthy,
+            false => HealthStatus::Degraded,
+        });
+
+        let count = match tokio::time::timeout(Duration::from_secs(1), udp.receive_batch(&mut batch)).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(error)) => {
+                if had_success {
+                    tracing::error!(?error, "got error");
+                }
+
+                /* bad packets are dropped, only back off if the socket itself has issues */
+                if error.kind() != std::io::ErrorKind::InvalidData {
+                    health.udp_channel.set(HealthStatus::Degraded);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
                 }
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        had_success = true;
+
+        let mut packets = Vec::with_capacity(count);
+        for index in 0..count {
+            match udp.read_batch_packet(&batch, index) {
+                Ok(UdpTunnelRx::ReceivedPacket { bytes, flow }) => {
+                    // tracing::info!(bytes, ?flow, "got packet");
+                    packets.push((flow, &batch.packet(index)[..bytes]));
+                }
+                Ok(UdpTunnelRx::ConfirmedConnection) => {}
+                Err(error) => {
+                    tracing::warn!(?error, "dropping packet from tunnel");
+                }
+            }
+        }
+
+        udp_clients.forward_packets(&packets).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpSocket};
+
+    use playit_agent_proto::control_feed::ClaimInstructions;
+
+    use crate::network::backend_pool::BalanceStrategy;
+
+    use super::*;
+
+    const TOKEN: &[u8] = b"claim-token";
+
+    fn peer_addr() -> SocketAddr {
+        "1.2.3.4:5000".parse().unwrap()
+    }
+
+    fn new_client(claim_addr: SocketAddr) -> NewClient {
+        NewClient {
+            connect_addr: "147.185.221.1:5000".parse().unwrap(),
+            peer_addr: peer_addr(),
+            claim_instructions: ClaimInstructions { address: claim_addr, token: TOKEN.to_vec() },
+            tunnel_server_id: 1,
+            data_center_id: 1,
+        }
+    }
+
+    async fn reserve(claim_addr: SocketAddr) -> ReservedClient {
+        let mut clients = TcpClients::new();
+        clients.use_special_lan = false;
+
+        clients.reserve(new_client(claim_addr), None, None).await.unwrap().unwrap()
+    }
+
+    /* reads the claim token and accepts the claim */
+    async fn accept_claim(claim_server: &TcpListener) -> TcpStream {
+        let (mut claim, _) = claim_server.accept().await.unwrap();
+        let mut token = vec![0u8; TOKEN.len()];
+        claim.read_exact(&mut token).await.unwrap();
+        claim.write_all(&1u64.to_be_bytes()).await.unwrap();
+        claim
+    }
+
+    struct SniLookup {
+        routes: Arc<SniRoutes>,
+    }
+
+    impl AddressLookup for SniLookup {
+        fn find_tunnel_port_range(&self, _match_ip: Ipv6Addr, _port: u16, _proto: PortProto) -> Option<(u16, u16)> {
+            Some((5000, 5001))
+        }
+
+        fn local_address(&self, _match_addr: MatchAddress, _proto: PortProto) -> Option<SocketAddr> {
+            Some("127.0.0.1:1".parse().unwrap())
+        }
+
+        fn sni_routes(&self, _match_addr: MatchAddress, _proto: PortProto) -> Option<Arc<SniRoutes>> {
+            Some(self.routes.clone())
+        }
+    }
+
+    struct ForwardHandler(tokio::sync::mpsc::UnboundedSender<Box<dyn TunnelStream>>);
+
+    impl TunnelHandler for ForwardHandler {
+        fn handle_tcp(&self, stream: Box<dyn TunnelStream>, _peer_addr: SocketAddr, _tunnel_addr: SocketAddr) {
+            let _ = self.0.send(stream);
+        }
+    }
+
+    async fn closed_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_before_claim() {
+        let claim_server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let claim_addr = claim_server.local_addr().unwrap();
+        let settings = TcpSettings { connect_before_claim: true, ..TcpSettings::default() };
+
+        /* a local target that is down never reaches the claim server */
+        let down = LocalTarget::Addr(closed_addr().await);
+        let res = claim_client(reserve(claim_addr).await, Some(down), peer_addr(), settings.clone()).await;
+        assert!(matches!(res, Err(ClaimFailed::LocalDown)));
+        assert!(tokio::time::timeout(Duration::from_millis(200), claim_server.accept()).await.is_err());
+
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = LocalTarget::Addr(local.local_addr().unwrap());
+        let claiming = tokio::spawn(claim_client(reserve(claim_addr).await, Some(up), peer_addr(), settings));
+
+        /* local side is connected first, then the client is claimed */
+        let _local_conn = local.accept().await.unwrap();
+        let _claim = accept_claim(&claim_server).await;
+
+        assert!(matches!(claiming.await.unwrap(), Ok((_, Some(LocalConn::Stream { .. })))));
+    }
+
+    #[tokio::test]
+    async fn test_local_connect_timeout() {
+        /* once the accept backlog is full further connects get no answer */
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut queued = Vec::new();
+        while let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await {
+            queued.push(stream);
+        }
+
+        let settings = TcpSettings { local_connect_timeout_secs: 1, ..TcpSettings::default() };
+        let started = Instant::now();
+        let error = connect_local_tcp(addr, &settings).await.unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(Duration::from_secs(1) <= started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_pool_target_name_includes_port_offset() {
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut base = local.local_addr().unwrap();
+        base.set_port(base.port() - 1);
+
+        let pool = BackendPool::new(vec![base], BalanceStrategy::RoundRobin);
+        let target = LocalTarget::Pool { pool, port_offset: 1 };
+
+        match connect_local(target, peer_addr(), &TcpSettings::default()).await {
+            Some(LocalConn::Stream { target_name, .. }) => assert_eq!(target_name, local.local_addr().unwrap().to_string()),
+            _ => panic!("expected a connection to the offset port"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sni_route_to_handler_gets_client_hello() {
+        let (handled_tx, mut handled_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut routes = SniRoutes::default();
+        routes.add("app.example.com", LocalTarget::Handler(Arc::new(ForwardHandler(handled_tx))));
+
+        let events = AgentEvents::default();
+        let mut tcp_clients = TcpClients::new();
+        tcp_clients.use_special_lan = false;
+
+        let ctx = Arc::new(ControlContext {
+            lookup: Arc::new(SniLookup { routes: Arc::new(routes) }),
+            tcp_clients,
+            health: Arc::new(AgentHealth::new(events.clone())),
+            local_health: Arc::new(LocalHealth::new(events.clone())),
+            rate_limiter: Arc::new(ConnectionRateLimiter::default()),
+            bandwidth: Arc::new(TunnelBandwidth::default()),
+            geo_filter: Arc::new(GeoFilter::default()),
+            tls_termination: Arc::new(TlsTermination::default()),
+            filter: Arc::new(FilterHook::default()),
+            events,
+            keep_running: Arc::new(AtomicBool::new(true)),
+        });
+
+        let claim_server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let new_client = new_client(claim_server.local_addr().unwrap());
+        let route = ctx.route_tcp_client(&new_client).unwrap();
+        tokio::spawn(ctx.clone().serve_tcp_client(new_client, route));
+
+        /* the player's side of the tunnel, the handshake never completes */
+        let claim = accept_claim(&claim_server).await;
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        tokio::spawn(async move { connector.connect("app.example.com".try_into().unwrap(), claim).await });
+
+        let (header, record) = tokio::time::timeout(Duration::from_secs(1), async {
+            let mut stream = handled_rx.recv().await.unwrap();
+            let mut header = [0u8; 5];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut record = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+            stream.read_exact(&mut record).await.unwrap();
+            (header, record)
+        }).await.unwrap();
+
+        /* the ClientHello read for routing is replayed to the handler */
+        assert_eq!(header[0], 0x16);
+        assert!(record.windows(15).any(|name| name == b"app.example.com"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_target_relays_data() {
+        let path = std::env::temp_dir().join(format!("playit-unix-target-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let local_conn = match connect_local(LocalTarget::Unix(path.clone()), peer_addr(), &TcpSettings::default()).await {
+            Some(LocalConn::Stream { conn, target_name, .. }) => {
+                assert_eq!(target_name, format!("unix:{}", path.display()));
+                conn
             }
+            _ => panic!("expected a unix socket connection"),
+        };
+        let (mut local, _) = listener.accept().await.unwrap();
+
+        /* the player's side of the tunnel */
+        let (mut player, tunnel_conn) = tokio::io::duplex(64);
+        let piping = tokio::spawn(async move {
+            pipe_bidirectional_shaped(tunnel_conn, local_conn, Duration::from_secs(5), &ClientShapers::default()).await
         });
 
-        tunnel_task.await.unwrap();
-        udp_task.await.unwrap();
+        player.write_all(b"hello panel").await.unwrap();
+        let mut received = [0u8; 11];
+        local.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello panel");
+
+        local.write_all(b"hello player").await.unwrap();
+        let mut reply = [0u8; 12];
+        player.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"hello player");
+
+        drop((player, local));
+        let summary = tokio::time::timeout(Duration::from_secs(1), piping).await.unwrap().unwrap();
+        assert_eq!((summary.tunnel_to_local, summary.local_to_tunnel), (11, 12));
+
+        std::fs::remove_file(&path).unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_pool_port_offset_out_of_range() {
+        let pool = BackendPool::new(vec!["127.0.0.1:65535".parse().unwrap()], BalanceStrategy::RoundRobin);
+        let target = LocalTarget::Pool { pool, port_offset: 1 };
+
+        assert!(connect_local(target, peer_addr(), &TcpSettings::default()).await.is_none());
+    }
+}
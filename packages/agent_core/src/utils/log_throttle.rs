@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::utils::now_milli;
+
+/// Lets a log line through at most once per interval and counts the
+/// lines skipped in between so they can be reported with the next one.
+pub struct LogThrottle {
+    interval_ms: u64,
+    last_logged: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> Self {
+        LogThrottle {
+            interval_ms: interval.as_millis() as u64,
+            last_logged: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of suppressed lines if a line may be logged now.
+    pub fn allow(&self) -> Option<u64> {
+        let now = now_milli();
+        let last = self.last_logged.load(Ordering::Relaxed);
+
+        if (last != 0 && now.saturating_sub(last) < self.interval_ms)
+            || self.last_logged.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_throttle() {
+        let throttle = LogThrottle::new(Duration::from_millis(50));
+
+        assert_eq!(throttle.allow(), Some(0));
+        assert_eq!(throttle.allow(), None);
+        assert_eq!(throttle.allow(), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(throttle.allow(), Some(2));
+    }
+}
@@ -1,6 +1,7 @@
 pub mod name_lookup;
 pub mod error_helper;
 pub mod shuffle;
+pub mod log_throttle;
 
 pub fn now_milli() -> u64 {
     std::time::SystemTime::now()
@@ -526,7 +526,7 @@ mod test {
                     let mut len = ((rng.next_u64() % 30) + 32) as usize;
                     let mut buffer = vec![0u8; len];
                     rng.fill_bytes(&mut buffer);
-                    buffer
+                    buffer.into()
                 },
             }),
             _ => unreachable!()